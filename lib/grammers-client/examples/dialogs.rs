@@ -15,6 +15,7 @@ use grammers_client::{Client, Config, SignInError};
 use simple_logger::SimpleLogger;
 use std::env;
 use std::io::{self, BufRead as _, Write as _};
+use std::sync::Arc;
 use tokio::runtime;
 
 type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
@@ -45,8 +46,9 @@ async fn async_main() -> Result<()> {
     let api_hash = env!("TG_HASH").to_string();
 
     println!("Connecting to Telegram...");
+    let session = Arc::new(Session::load_file_or_create(SESSION_FILE)?);
     let client = Client::connect(Config {
-        session: Session::load_file_or_create(SESSION_FILE)?,
+        session: session.clone(),
         api_id,
         api_hash: api_hash.clone(),
         params: Default::default(),
@@ -79,7 +81,8 @@ async fn async_main() -> Result<()> {
             Err(e) => panic!("{}", e),
         };
         println!("Signed in!");
-        match client.session().save_to_file(SESSION_FILE) {
+        client.sync_update_state();
+        match session.save_to_file(SESSION_FILE) {
             Ok(_) => {}
             Err(e) => {
                 println!("NOTE: failed to save the session, will sign out when done: {e}");
@@ -16,6 +16,7 @@ use grammers_client::{Client, Config, InitParams, Update};
 use simple_logger::SimpleLogger;
 use std::env;
 use std::pin::pin;
+use std::sync::Arc;
 use tokio::{runtime, task};
 
 type Result = std::result::Result<(), Box<dyn std::error::Error>>;
@@ -46,8 +47,9 @@ async fn async_main() -> Result {
     let token = env::args().nth(1).expect("token missing");
 
     println!("Connecting to Telegram...");
+    let session = Arc::new(Session::load_file_or_create(SESSION_FILE)?);
     let client = Client::connect(Config {
-        session: Session::load_file_or_create(SESSION_FILE)?,
+        session: session.clone(),
         api_id,
         api_hash: api_hash.clone(),
         params: InitParams {
@@ -62,7 +64,8 @@ async fn async_main() -> Result {
     if !client.is_authorized().await? {
         println!("Signing in...");
         client.bot_sign_in(&token).await?;
-        client.session().save_to_file(SESSION_FILE)?;
+        client.sync_update_state();
+        session.save_to_file(SESSION_FILE)?;
         println!("Signed in!");
     }
 
@@ -94,7 +97,8 @@ async fn async_main() -> Result {
     }
 
     println!("Saving session file and exiting...");
-    client.session().save_to_file(SESSION_FILE)?;
+    client.sync_update_state();
+    session.save_to_file(SESSION_FILE)?;
     Ok(())
 }
 
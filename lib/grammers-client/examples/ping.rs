@@ -7,6 +7,7 @@
 use grammers_client::session::Session;
 use grammers_client::{Client, Config};
 use grammers_tl_types as tl;
+use std::sync::Arc;
 use tokio::runtime;
 
 type Result = std::result::Result<(), Box<dyn std::error::Error>>;
@@ -14,7 +15,7 @@ type Result = std::result::Result<(), Box<dyn std::error::Error>>;
 async fn async_main() -> Result {
     println!("Connecting to Telegram...");
     let client = Client::connect(Config {
-        session: Session::load_file_or_create("ping.session")?,
+        session: Arc::new(Session::load_file_or_create("ping.session")?),
         api_id: 1, // not actually logging in, but has to look real
         api_hash: "".to_string(),
         params: Default::default(),
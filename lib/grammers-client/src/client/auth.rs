@@ -5,7 +5,6 @@
 // <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
-use super::net::connect_sender;
 use super::Client;
 use crate::types::{LoginToken, PasswordToken, TermsOfService, User};
 use crate::utils;
@@ -95,6 +94,7 @@ impl Client {
                 .config
                 .session
                 .set_user(user.id(), state.dc_id, user.is_bot());
+            state.session_changed = true;
 
             state.chat_hashes.set_self_user(user.pack());
             if let Some(us) = update_state {
@@ -108,6 +108,7 @@ impl Client {
         if sync_state {
             self.sync_update_state();
         }
+        self.autosave_session();
 
         Ok(user)
     }
@@ -148,21 +149,8 @@ impl Client {
             bot_auth_token: token.to_string(),
         };
 
-        let result = match self.invoke(&request).await {
-            Ok(x) => x,
-            Err(InvocationError::Rpc(err)) if err.code == 303 => {
-                let dc_id = err.value.unwrap() as i32;
-                let (sender, request_tx) = connect_sender(dc_id, &self.0.config).await?;
-                {
-                    *self.0.conn.sender.lock().await = sender;
-                    *self.0.conn.request_tx.write().unwrap() = request_tx;
-                    let mut state = self.0.state.write().unwrap();
-                    state.dc_id = dc_id;
-                }
-                self.invoke(&request).await?
-            }
-            Err(e) => return Err(e.into()),
-        };
+        // `Client::invoke` already connects to the right datacenter on `USER_MIGRATE`.
+        let result = self.invoke(&request).await?;
 
         match result {
             tl::enums::auth::Authorization::Authorization(x) => {
@@ -219,32 +207,12 @@ impl Client {
 
         use tl::enums::auth::SentCode as SC;
 
-        let sent_code: tl::types::auth::SentCode = match self.invoke(&request).await {
-            Ok(x) => match x {
-                SC::Code(code) => code,
-                SC::Success(_) => panic!("should not have logged in yet"),
-            },
-            Err(InvocationError::Rpc(err)) if err.code == 303 => {
-                // Since we are not logged in (we're literally requesting for
-                // the code to login now), there's no need to export the current
-                // authorization and re-import it at a different datacenter.
-                //
-                // Just connect and generate a new authorization key with it
-                // before trying again.
-                let dc_id = err.value.unwrap() as i32;
-                let (sender, request_tx) = connect_sender(dc_id, &self.0.config).await?;
-                {
-                    *self.0.conn.sender.lock().await = sender;
-                    *self.0.conn.request_tx.write().unwrap() = request_tx;
-                    let mut state = self.0.state.write().unwrap();
-                    state.dc_id = dc_id;
-                }
-                match self.invoke(&request).await? {
-                    SC::Code(code) => code,
-                    SC::Success(_) => panic!("should not have logged in yet"),
-                }
-            }
-            Err(e) => return Err(e.into()),
+        // Since we are not logged in (we're literally requesting for the code to login now),
+        // `Client::invoke` doesn't need to export the current authorization on `PHONE_MIGRATE`.
+        // It just connects and generates a new authorization key before trying again.
+        let sent_code: tl::types::auth::SentCode = match self.invoke(&request).await? {
+            SC::Code(code) => code,
+            SC::Success(_) => panic!("should not have logged in yet"),
         };
 
         Ok(LoginToken {
@@ -301,14 +269,7 @@ impl Client {
                 crate::types::QrWaitResult::Token((x.token, x.expires))
             }
             tl::enums::auth::LoginToken::MigrateTo(x) => {
-                let (sender, request_tx) =
-                    crate::client::net::connect_sender(x.dc_id, &self.0.config).await?;
-                {
-                    *self.0.conn.sender.lock().await = sender;
-                    *self.0.conn.request_tx.write().unwrap() = request_tx;
-                    let mut state = self.0.state.write().unwrap();
-                    state.dc_id = x.dc_id;
-                }
+                self.switch_home_dc(x.dc_id).await?;
                 let token = self
                     .invoke(&tl::functions::auth::ImportLoginToken { token: x.token })
                     .await?;
@@ -516,14 +477,16 @@ impl Client {
         self.invoke(&tl::functions::auth::LogOut {}).await
     }
 
-    /// Synchronize all state to the session file and provide mutable access to it.
+    /// Synchronize all state to the session storage and provide access to it.
     ///
-    /// You can use this to temporarily access the session and save it wherever you want to.
+    /// To save storages that need it explicitly, such as [`Session`], keep a clone of the `Arc`
+    /// given in the [`Config`] and save it after calling this method.
     ///
-    /// Panics if the type parameter does not match the actual session type.
-    pub fn session(&self) -> &grammers_session::Session {
+    /// [`Session`]: grammers_session::Session
+    /// [`Config`]: crate::Config
+    pub fn session(&self) -> &dyn grammers_session::SessionStorage {
         self.sync_update_state();
-        &self.0.config.session
+        self.0.config.session.as_ref()
     }
 
     /// Calls [`Client::sign_out`] and disconnects.
@@ -5,18 +5,23 @@
 // <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
-use grammers_mtproto::{mtp, transport};
-use grammers_mtsender::{self as sender, ReconnectionPolicy, Sender};
-use grammers_session::{ChatHashCache, MessageBox, Session};
+use super::{FloodPolicy, FloodSleep};
+use grammers_mtproto::mtp;
+use grammers_mtproto::transport::Transport;
+#[cfg(feature = "proxy")]
+use grammers_mtsender::MtProxy;
+use grammers_mtsender::{self as sender, ConnectionEvent, ReconnectionPolicy, Sender};
+use grammers_session::{ChatHashCache, MessageBox, SessionStorage, UpdateState};
 use grammers_tl_types as tl;
+use log::warn;
 use sender::Enqueuer;
 use std::collections::{HashMap, VecDeque};
 use std::fmt;
 use std::net::SocketAddr;
 use std::sync::atomic::AtomicU32;
-use std::sync::{Arc, RwLock};
-use std::time::Instant;
-use tokio::sync::{Mutex as AsyncMutex, RwLock as AsyncRwLock};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, Mutex as AsyncMutex, RwLock as AsyncRwLock};
 
 /// When no locale is found, use this one instead.
 const DEFAULT_LOCALE: &str = "en";
@@ -27,7 +32,12 @@ const DEFAULT_LOCALE: &str = "en";
 pub struct Config {
     /// Session storage where data should persist, such as authorization key, server address,
     /// and other required information by the client.
-    pub session: Session,
+    ///
+    /// Any [`SessionStorage`] may be used. Keep a clone of the `Arc` around if you need to access
+    /// the concrete storage later on (for example, to save a [`Session`] to a file).
+    ///
+    /// [`Session`]: grammers_session::Session
+    pub session: Arc<dyn SessionStorage>,
 
     /// Developer's API ID, required to interact with the Telegram's API.
     ///
@@ -43,6 +53,28 @@ pub struct Config {
     pub params: InitParams,
 }
 
+/// The [transports] that can be used to frame the packets of a connection.
+///
+/// [transports]: https://core.telegram.org/mtproto/mtproto-transports
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TransportKind {
+    /// See [`crate::transport::Abridged`].
+    Abridged,
+    /// See [`crate::transport::Intermediate`].
+    Intermediate,
+    /// See [`crate::transport::PaddedIntermediate`].
+    PaddedIntermediate,
+    /// See [`crate::transport::Full`].
+    #[default]
+    Full,
+    /// See [`crate::transport::Http`].
+    ///
+    /// This transport cannot be obfuscated, so [`InitParams::obfuscated`] has no effect on it.
+    /// Only plain HTTP is supported, which can be combined with an HTTP `proxy_url` when
+    /// connections must go through a gateway.
+    Http,
+}
+
 /// Optional initialization parameters, required when initializing a connection to Telegram's
 /// API.
 #[derive(Clone)]
@@ -63,19 +95,43 @@ pub struct InitParams {
     /// field can be used to override said address, and is most commonly used to connect to one
     /// of Telegram's test servers instead.
     pub server_addr: Option<SocketAddr>,
-    /// The threshold below which the library should automatically sleep on flood-wait and slow
-    /// mode wait errors (inclusive). For instance, if an
-    /// `RpcError { name: "FLOOD_WAIT", value: Some(17) }` (flood, must wait 17 seconds) occurs
-    /// and `flood_sleep_threshold` is 20 (seconds), the library will `sleep` automatically for
-    /// 17 seconds. If the error was for 21s, it would propagate the error instead.
-    ///
-    /// By default, the library will sleep on flood-waits below or equal to one minute (60
-    /// seconds), but this can be disabled by passing `0` (since all flood errors would be
-    /// higher and exceed the threshold).
-    ///
-    /// On flood, the library will retry *once*. If the flood error occurs a second time after
-    /// sleeping, the error will be returned.
-    pub flood_sleep_threshold: u32,
+    /// The policy used to decide whether the library should automatically sleep on flood-wait
+    /// and slow mode wait errors, and retry the request afterwards.
+    ///
+    /// By default, this is [`FloodSleep`] with its default values, which will sleep on
+    /// flood-waits below or equal to one minute (60 seconds) and retry *once*. If the flood
+    /// error occurs a second time after sleeping, the error will be returned. Use
+    /// [`NoFloodSleep`] to always return the error instead.
+    ///
+    /// Flood-waits apply to the method used for some time, so further calls to the same method
+    /// before the wait is over are handled by the policy without being sent to Telegram.
+    ///
+    /// [`FloodSleep`]: super::FloodSleep
+    /// [`NoFloodSleep`]: super::NoFloodSleep
+    pub flood_policy: Arc<dyn FloodPolicy>,
+    /// How long to wait for the response to a request before giving up with
+    /// [`InvocationError::Timeout`], unless a different timeout is given with
+    /// [`Client::invoke_with_timeout`].
+    ///
+    /// The time spent sleeping on flood-wait errors or following migrations also counts. When
+    /// a request times out or is cancelled, it is not sent if it didn't go out yet, and the
+    /// server is told to drop its response otherwise.
+    ///
+    /// By default, this is `None`, and requests wait for as long as it takes.
+    ///
+    /// [`InvocationError::Timeout`]: grammers_mtsender::InvocationError::Timeout
+    pub request_timeout: Option<Duration>,
+    /// If set, use temporary authorization keys which expire after the given duration, bound
+    /// to the permanent key stored in the session, in order to achieve [Perfect Forward
+    /// Secrecy]. Leaked traffic cannot be decrypted with the permanent key alone.
+    ///
+    /// The library generates a new temporary key when connecting, and again before the
+    /// current one expires. Telegram will not accept keys living for longer than a day or so.
+    ///
+    /// By default, this is `None`, and the permanent key is used directly.
+    ///
+    /// [Perfect Forward Secrecy]: https://core.telegram.org/api/pfs
+    pub temp_auth_key_lifetime: Option<Duration>,
     /// How many updates may be buffered by the client at any given time.
     ///
     /// Telegram passively sends updates to the client through the open connection, so they must
@@ -106,9 +162,19 @@ pub struct InitParams {
     #[cfg(feature = "proxy")]
     pub proxy_url: Option<String>,
 
+    /// MTProxy server to connect through. Requires the `proxy` feature to be enabled.
+    ///
+    /// It can be parsed from a `tg://proxy` or `https://t.me/proxy` link with
+    /// [`MtProxy::from_link`]. When set, it takes precedence over both [`InitParams::proxy_url`]
+    /// and [`InitParams::obfuscated`], as the transport depends on the secret of the proxy.
+    ///
+    /// [`MtProxy::from_link`]: crate::MtProxy::from_link
+    #[cfg(feature = "proxy")]
+    pub mtproxy: Option<MtProxy>,
+
     /// specify the reconnection policy which will be used by client to determine whether to re-connect on failure or not.
     ///
-    ///it can be one of the 2 default implementation [`NoReconnect`] and [`FixedReconnect`];
+    ///it can be one of the 3 default implementation [`NoReconnect`], [`FixedReconnect`] and [`ExponentialBackoff`];
     ///
     /// **OR** your own custom implementation of trait [`ReconnectionPolicy`].
     ///
@@ -116,8 +182,52 @@ pub struct InitParams {
     ///
     /// [`NoReconnect`]: grammers_mtsender::NoReconnect
     /// [`FixedReconnect`]: grammers_mtsender::FixedReconnect
+    /// [`ExponentialBackoff`]: grammers_mtsender::ExponentialBackoff
     /// [`ReconnectionPolicy`]: grammers_mtsender::ReconnectionPolicy
-    pub reconnection_policy: &'static dyn ReconnectionPolicy,
+    pub reconnection_policy: Arc<dyn ReconnectionPolicy>,
+
+    /// The transport used to frame the packets sent and received through the connection.
+    ///
+    /// Smaller framings save some bandwidth, while the padded intermediate transport makes
+    /// the length of the packets harder to use for identifying the protocol.
+    ///
+    /// By default, the [`TransportKind::Full`] transport is used.
+    pub transport: TransportKind,
+
+    /// Should the connection be obfuscated to make it harder to identify and block?
+    ///
+    /// Obfuscated connections use the [intermediate transport] if the selected transport is
+    /// the full one, because it cannot be obfuscated.
+    ///
+    /// By default, the connection is not obfuscated.
+    ///
+    /// [intermediate transport]: crate::transport::Intermediate
+    pub obfuscated: bool,
+
+    /// Should the connection be made to the WebSocket endpoint of the server, for environments
+    /// where only WebSocket traffic is allowed?
+    ///
+    /// Telegram only accepts obfuscated transports over WebSocket, so the connection is always
+    /// obfuscated when this is enabled, and the HTTP transport cannot be used. Only plain
+    /// WebSocket connections are supported, and this has no effect when using [MTProxy].
+    ///
+    /// Connections are made to the `*.web.telegram.org` host of each datacenter on port 80,
+    /// unless [`InitParams::server_addr`] is set, in which case that address is used instead.
+    ///
+    /// By default, WebSocket is not used.
+    ///
+    /// [MTProxy]: https://core.telegram.org/proxy
+    pub websocket: bool,
+
+    /// Persist the session automatically, by calling [`SessionStorage::flush`], whenever the
+    /// authorization key, datacenter, logged-in user or update state changes.
+    ///
+    /// Saving happens at most once per interval, so changes made in between are batched. Any
+    /// pending change is also saved when the last [`Client`] instance is dropped. Automatic saves
+    /// run on Tokio's blocking thread pool, so they don't stall the task stepping the client.
+    ///
+    /// By default, this is `None`, and the session must be saved manually.
+    pub autosave_interval: Option<Duration>,
 }
 
 pub(crate) struct ClientInner {
@@ -125,6 +235,8 @@ pub(crate) struct ClientInner {
     pub(crate) id: i64,
     pub(crate) config: Config,
     pub(crate) conn: Connection,
+    // Connection events of the home datacenter, kept across reconnections and migrations.
+    pub(crate) events: broadcast::Sender<ConnectionEvent>,
     pub(crate) state: RwLock<ClientState>,
     // Stores per-datacenter downloader instances
     pub(crate) downloader_map: AsyncRwLock<HashMap<i32, Arc<Connection>>>,
@@ -138,12 +250,27 @@ pub(crate) struct ClientState {
     // This is used to avoid spamming the log.
     pub(crate) last_update_limit_warn: Option<Instant>,
     pub(crate) updates: VecDeque<(tl::enums::Update, Arc<crate::types::ChatMap>)>,
+    // Whether the session changed since it was last saved, when that happened, which update
+    // state was saved back then, and whether a save is already scheduled. These are only used
+    // when `autosave_interval` is set.
+    pub(crate) session_changed: bool,
+    pub(crate) last_session_save: Option<Instant>,
+    pub(crate) saved_update_state: Option<UpdateState>,
+    pub(crate) session_save_scheduled: bool,
+    // Whether the server forgot the authorization key of the home datacenter while logged in,
+    // and the application has yet to be told that it was logged out.
+    pub(crate) logged_out: bool,
 }
 
 pub(crate) struct Connection {
-    pub(crate) sender: AsyncMutex<Sender<transport::Full, mtp::Encrypted>>,
+    pub(crate) sender: AsyncMutex<Sender<Box<dyn Transport + Send>, mtp::Encrypted>>,
     pub(crate) request_tx: RwLock<Enqueuer>,
     pub(crate) step_counter: AtomicU32,
+    // When will the flood-waits for each method (by constructor identifier) be over.
+    pub(crate) flood_deadlines: Mutex<HashMap<u32, Instant>>,
+    // Future salts received while stepping which have yet to be persisted, along with the
+    // authorization key they belong to.
+    pub(crate) new_salts: Mutex<Option<([u8; 256], Vec<tl::types::FutureSalt>)>>,
 }
 
 /// A client capable of connecting to Telegram and invoking requests.
@@ -153,10 +280,10 @@ pub(crate) struct Connection {
 /// This structure owns all the necessary connections to Telegram, and has implementations for the
 /// most basic methods, such as connecting, signing in, or processing network events.
 ///
-/// On drop, all state is synchronized to the session. The [`Session`] must be explicitly saved
-/// to disk with [`Session::save_to_file`] for persistence
+/// On drop, all state is synchronized to the session. If the storage does not persist changes on
+/// its own, it must be explicitly saved (for example, with [`Session::save_to_file`]).
 ///
-/// [`Session`]: grammers_session::Session
+/// [`Session::save_to_file`]: grammers_session::Session::save_to_file
 #[derive(Clone)]
 pub struct Client(pub(crate) Arc<ClientInner>);
 
@@ -187,11 +314,19 @@ impl Default for InitParams {
             lang_code,
             catch_up: false,
             server_addr: None,
-            flood_sleep_threshold: 60,
+            flood_policy: Arc::new(FloodSleep::default()),
+            request_timeout: None,
+            temp_auth_key_lifetime: None,
             update_queue_limit: Some(100),
             #[cfg(feature = "proxy")]
             proxy_url: None,
-            reconnection_policy: &grammers_mtsender::NoReconnect,
+            #[cfg(feature = "proxy")]
+            mtproxy: None,
+            reconnection_policy: Arc::new(grammers_mtsender::NoReconnect),
+            transport: TransportKind::default(),
+            obfuscated: false,
+            websocket: false,
+            autosave_interval: None,
         }
     }
 }
@@ -203,6 +338,22 @@ impl Drop for Client {
     }
 }
 
+impl Drop for ClientInner {
+    fn drop(&mut self) {
+        // Final save, in case there were changes since the last automatic one.
+        if self.config.params.autosave_interval.is_some() {
+            if let Ok(state) = self.state.get_mut() {
+                self.config
+                    .session
+                    .set_state(state.message_box.session_state());
+            }
+            if let Err(e) = self.config.session.flush() {
+                warn!("failed to save session: {}", e);
+            }
+        }
+    }
+}
+
 impl fmt::Debug for Client {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         // TODO show more info, like user id and session name if present
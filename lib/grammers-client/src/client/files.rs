@@ -22,13 +22,14 @@ use tokio::{
 
 pub const MIN_CHUNK_SIZE: i32 = 4 * 1024;
 pub const MAX_CHUNK_SIZE: i32 = 512 * 1024;
-const FILE_MIGRATE_ERROR: i32 = 303;
 const BIG_FILE_SIZE: usize = 10 * 1024 * 1024;
 const WORKER_COUNT: usize = 4;
 
 pub struct DownloadIter {
     client: Client,
     done: bool,
+    // Datacenter where the file lives, if it's not the home datacenter.
+    dc: Option<i32>,
     request: tl::functions::upload::GetFile,
     photo_size_data: Option<Vec<u8>>,
 }
@@ -53,6 +54,7 @@ impl DownloadIter {
         Self {
             client: client.clone(),
             done: false,
+            dc: None,
             request: tl::functions::upload::GetFile {
                 precise: false,
                 cdn_supported: false,
@@ -68,6 +70,7 @@ impl DownloadIter {
         Self {
             client: client.clone(),
             done: false,
+            dc: None,
             // request is not needed, so fake one
             request: tl::functions::upload::GetFile {
                 precise: false,
@@ -122,34 +125,27 @@ impl DownloadIter {
         use tl::enums::upload::File;
 
         // TODO handle maybe FILEREF_UPGRADE_NEEDED
-        let mut dc: Option<u32> = None;
-        loop {
-            let result = match dc.take() {
-                None => self.client.invoke(&self.request).await,
-                Some(dc) => self.client.invoke_in_dc(&self.request, dc as i32).await,
-            };
-
-            break match result {
-                Ok(File::File(f)) => {
-                    if f.bytes.len() < self.request.limit as usize {
-                        self.done = true;
-                        if f.bytes.is_empty() {
-                            return Ok(None);
-                        }
+        let result = self
+            .client
+            .invoke_following_migrations(&self.request, &mut self.dc)
+            .await;
+
+        match result {
+            Ok(File::File(f)) => {
+                if f.bytes.len() < self.request.limit as usize {
+                    self.done = true;
+                    if f.bytes.is_empty() {
+                        return Ok(None);
                     }
-
-                    self.request.offset += self.request.limit as i64;
-                    Ok(Some(f.bytes))
                 }
-                Ok(File::CdnRedirect(_)) => {
-                    panic!("API returned File::CdnRedirect even though cdn_supported = false");
-                }
-                Err(InvocationError::Rpc(err)) if err.code == FILE_MIGRATE_ERROR => {
-                    dc = err.value;
-                    continue;
-                }
-                Err(e) => Err(e),
-            };
+
+                self.request.offset += self.request.limit as i64;
+                Ok(Some(f.bytes))
+            }
+            Ok(File::CdnRedirect(_)) => {
+                panic!("API returned File::CdnRedirect even though cdn_supported = false");
+            }
+            Err(e) => Err(e),
         }
     }
 }
@@ -276,19 +272,13 @@ impl Client {
             let part_index = part_index.clone();
             let client = self.clone();
             let task = tokio::task::spawn(async move {
-                let mut retry_offset = None;
                 let mut dc = None;
                 loop {
                     // Calculate file offset
                     let offset: i64 = {
-                        if let Some(offset) = retry_offset {
-                            retry_offset = None;
-                            offset
-                        } else {
-                            let mut i = part_index.lock().await;
-                            *i += 1;
-                            (MAX_CHUNK_SIZE * (*i - 1)) as i64
-                        }
+                        let mut i = part_index.lock().await;
+                        *i += 1;
+                        (MAX_CHUNK_SIZE * (*i - 1)) as i64
                     };
                     if offset > size {
                         break;
@@ -301,10 +291,7 @@ impl Client {
                         offset,
                         limit: MAX_CHUNK_SIZE,
                     };
-                    let res = match dc {
-                        None => client.invoke(request).await,
-                        Some(dc) => client.invoke_in_dc(request, dc as i32).await,
-                    };
+                    let res = client.invoke_following_migrations(request, &mut dc).await;
                     match res {
                         Ok(tl::enums::upload::File::File(file)) => {
                             tx.send((offset as u64, file.bytes)).unwrap();
@@ -314,14 +301,6 @@ impl Client {
                                 "API returned File::CdnRedirect even though cdn_supported = false"
                             );
                         }
-                        Err(InvocationError::Rpc(err)) => {
-                            if err.code == FILE_MIGRATE_ERROR {
-                                dc = err.value;
-                                retry_offset = Some(offset);
-                                continue;
-                            }
-                            return Err(InvocationError::Rpc(err));
-                        }
                         Err(e) => return Err(e),
                     }
                 }
@@ -0,0 +1,233 @@
+// Copyright 2020 - developers of the `grammers` project.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+use grammers_mtsender::RpcError;
+use grammers_tl_types as tl;
+use std::collections::HashMap;
+use std::ops::ControlFlow;
+use std::time::Duration;
+
+/// The RPC error code used by all errors which ask to wait before retrying, such as
+/// `FLOOD_WAIT`, `FLOOD_PREMIUM_WAIT` or `SLOWMODE_WAIT`.
+const FLOOD_ERROR_CODE: i32 = 420;
+
+/// A request which could not be processed because it was made too often, and must wait before
+/// being retried.
+#[derive(Clone, Debug)]
+pub struct FloodWait {
+    /// The constructor identifier of the request which caused the error.
+    pub request_id: u32,
+
+    /// The error returned by Telegram, such as `FLOOD_WAIT`, `FLOOD_PREMIUM_WAIT` or
+    /// `SLOWMODE_WAIT`.
+    ///
+    /// If the library did not send the request because it knew the method was still under a
+    /// flood wait, the error is built as if Telegram had returned `FLOOD_WAIT` instead.
+    pub error: RpcError,
+
+    /// How long the request must wait before it can be sent again.
+    pub wait: Duration,
+}
+
+impl FloodWait {
+    /// Extract the flood wait from the error caused by the given request, if it is one.
+    pub(crate) fn from_error(request_id: u32, error: &RpcError) -> Option<Self> {
+        match error {
+            RpcError {
+                code: FLOOD_ERROR_CODE,
+                value: Some(seconds),
+                ..
+            } => Some(Self {
+                request_id,
+                error: error.clone(),
+                wait: Duration::from_secs(*seconds as _),
+            }),
+            _ => None,
+        }
+    }
+
+    /// The name of the request which caused the error, as found in the `.tl` definitions.
+    pub fn request_name(&self) -> &'static str {
+        tl::name_for_id(self.request_id)
+    }
+
+    /// Whether the wait applies to the method as a whole, rather than to the specific chat the
+    /// request was made in (as is the case with slow mode).
+    pub(crate) fn is_method_wide(&self) -> bool {
+        !self.error.is("SLOWMODE_WAIT")
+    }
+}
+
+/// Decides what to do when a request fails because it was made too often.
+///
+/// Implementing this trait and passing it to the `InitParams` inside the `Client` allows
+/// customizing how flood waits are handled. The default is [`FloodSleep`] with its default
+/// values, and [`NoFloodSleep`] can be used to always return the error instead.
+///
+/// Note that once a method is known to be under a flood wait, further calls to that method are
+/// also handled by the policy, without bothering Telegram until the wait is over.
+pub trait FloodPolicy: Send + Sync {
+    /// Indicates whether the request should be retried after sleeping for the given `Duration`
+    /// (`Continue`), or the error should be returned instead (`Break`).
+    ///
+    /// `attempts` is the amount of times the library already slept on a flood wait for this
+    /// same request.
+    fn should_sleep(&self, flood: &FloodWait, attempts: usize) -> ControlFlow<(), Duration>;
+}
+
+/// A policy which never sleeps, so that flood wait errors are always returned.
+pub struct NoFloodSleep;
+
+/// A policy which sleeps on flood waits, as long as they're short enough.
+///
+/// By default, the library will sleep on flood waits below or equal to one minute, and retry
+/// the request *once*. If the flood wait occurs a second time after sleeping, the error will be
+/// returned.
+pub struct FloodSleep {
+    /// The threshold below which flood waits are slept on (inclusive). For instance, if a
+    /// `FLOOD_WAIT` of 17 seconds occurs and the threshold is 20 seconds, the library will
+    /// sleep for 17 seconds and retry. If the wait was of 21 seconds, the error would be
+    /// returned instead.
+    pub threshold: Duration,
+
+    /// A different threshold to use for specific requests, by their constructor identifier
+    /// (for example, [`tl::functions::messages::SendMessage::CONSTRUCTOR_ID`]).
+    ///
+    /// [`tl::functions::messages::SendMessage::CONSTRUCTOR_ID`]: grammers_tl_types::Identifiable::CONSTRUCTOR_ID
+    pub method_thresholds: HashMap<u32, Duration>,
+
+    /// How many times the same request may be retried after sleeping.
+    pub max_retries: usize,
+
+    /// A function called right before sleeping, which can be used to log or report floods.
+    #[allow(clippy::type_complexity)]
+    pub on_sleep: Option<Box<dyn Fn(&FloodWait) + Send + Sync>>,
+}
+
+impl Default for FloodSleep {
+    fn default() -> Self {
+        Self {
+            threshold: Duration::from_secs(60),
+            method_thresholds: HashMap::new(),
+            max_retries: 1,
+            on_sleep: None,
+        }
+    }
+}
+
+impl FloodPolicy for NoFloodSleep {
+    fn should_sleep(&self, _: &FloodWait, _: usize) -> ControlFlow<(), Duration> {
+        ControlFlow::Break(())
+    }
+}
+
+impl FloodPolicy for FloodSleep {
+    fn should_sleep(&self, flood: &FloodWait, attempts: usize) -> ControlFlow<(), Duration> {
+        let threshold = self
+            .method_thresholds
+            .get(&flood.request_id)
+            .copied()
+            .unwrap_or(self.threshold);
+
+        if attempts < self.max_retries && flood.wait <= threshold {
+            if let Some(on_sleep) = self.on_sleep.as_ref() {
+                on_sleep(flood);
+            }
+            ControlFlow::Continue(flood.wait)
+        } else {
+            ControlFlow::Break(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use grammers_tl_types::Identifiable;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    fn flood(name: &str, request_id: u32, seconds: u32) -> FloodWait {
+        FloodWait::from_error(
+            request_id,
+            &RpcError {
+                code: 420,
+                name: name.to_string(),
+                value: Some(seconds),
+                caused_by: Some(request_id),
+            },
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn only_waits_are_floods() {
+        let error = RpcError {
+            code: 400,
+            name: "MESSAGE_ID_INVALID".to_string(),
+            value: None,
+            caused_by: None,
+        };
+        assert!(FloodWait::from_error(0, &error).is_none());
+        assert!(flood("FLOOD_WAIT", 0, 5).is_method_wide());
+        assert!(flood("FLOOD_PREMIUM_WAIT", 0, 5).is_method_wide());
+        assert!(!flood("SLOWMODE_WAIT", 0, 5).is_method_wide());
+    }
+
+    #[test]
+    fn flood_sleep_honors_thresholds() {
+        let send_message = tl::functions::messages::SendMessage::CONSTRUCTOR_ID;
+        let policy = FloodSleep {
+            method_thresholds: HashMap::from([(send_message, Duration::from_secs(5))]),
+            max_retries: 2,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            policy.should_sleep(&flood("FLOOD_WAIT", 0, 60), 0),
+            ControlFlow::Continue(Duration::from_secs(60))
+        );
+        assert_eq!(
+            policy.should_sleep(&flood("FLOOD_WAIT", 0, 61), 0),
+            ControlFlow::Break(())
+        );
+        assert_eq!(
+            policy.should_sleep(&flood("FLOOD_WAIT", send_message, 6), 0),
+            ControlFlow::Break(())
+        );
+        assert_eq!(
+            policy.should_sleep(&flood("FLOOD_WAIT", 0, 1), 1),
+            ControlFlow::Continue(Duration::from_secs(1))
+        );
+        assert_eq!(
+            policy.should_sleep(&flood("FLOOD_WAIT", 0, 1), 2),
+            ControlFlow::Break(())
+        );
+    }
+
+    #[test]
+    fn flood_sleep_reports_before_sleeping() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let policy = FloodSleep {
+            on_sleep: Some(Box::new({
+                let calls = Arc::clone(&calls);
+                move |_| {
+                    calls.fetch_add(1, Ordering::SeqCst);
+                }
+            })),
+            ..Default::default()
+        };
+
+        assert!(policy
+            .should_sleep(&flood("FLOOD_WAIT", 0, 1), 0)
+            .is_continue());
+        assert!(policy
+            .should_sleep(&flood("FLOOD_WAIT", 0, 1000), 0)
+            .is_break());
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+}
@@ -16,6 +16,7 @@ pub mod contacts;
 pub mod dialogs;
 pub mod files;
 mod filters;
+mod flood;
 pub mod messages;
 pub mod net;
 pub mod send_multi_media;
@@ -24,7 +25,8 @@ pub mod user;
 
 pub use auth::SignInError;
 pub(crate) use client::ClientInner;
-pub use client::{Client, Config, InitParams};
+pub use client::{Client, Config, InitParams, TransportKind};
 pub use filters::Filters;
+pub use flood::{FloodPolicy, FloodSleep, FloodWait, NoFloodSleep};
 pub use grammers_session::{PackedChat, PackedType};
 pub use user::EditTwoFaError;
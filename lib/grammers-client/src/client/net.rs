@@ -6,21 +6,29 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 use super::client::{ClientState, Connection};
-use super::{Client, ClientInner, Config};
+use super::{Client, ClientInner, Config, FloodPolicy, FloodWait, InitParams, TransportKind};
 use crate::utils;
+use futures_util::future::{select, Either};
 use grammers_mtproto::mtp;
-use grammers_mtproto::transport;
-use grammers_mtsender::{self as sender, AuthorizationError, InvocationError, RpcError, Sender};
-use grammers_session::{ChatHashCache, MessageBox};
+use grammers_mtproto::transport::{self, Transport};
+use grammers_mtsender::{
+    self as sender, AuthorizationError, ConnectionEvent, InvocationError, RpcError, Sender,
+};
+use grammers_session::{ChatHashCache, DcOption, FutureSalt, MessageBox, SessionStorage};
 use grammers_tl_types::{self as tl, Deserializable};
-use log::{debug, info};
+use log::{debug, info, warn};
 use sender::Enqueuer;
 use std::collections::{HashMap, VecDeque};
-use std::net::{Ipv4Addr, SocketAddr};
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::ops::ControlFlow;
+use std::pin::pin;
 use std::sync::atomic::{AtomicU32, Ordering};
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
 use tokio::sync::oneshot::error::TryRecvError;
-use tokio::sync::{Mutex as AsyncMutex, RwLock as AsyncRwLock};
+use tokio::sync::{broadcast, Mutex as AsyncMutex, RwLock as AsyncRwLock};
 
 /// Socket addresses to Telegram datacenters, where the index into this array
 /// represents the data center ID.
@@ -38,85 +46,355 @@ const DC_ADDRESSES: [(Ipv4Addr, u16); 6] = [
 
 const DEFAULT_DC: i32 = 2;
 
-pub(crate) async fn connect_sender(
+/// Error code used by the `*_MIGRATE` errors, which indicate that a different datacenter
+/// should be used.
+const MIGRATE_ERROR: i32 = 303;
+
+/// How many connection events can be buffered before slow receivers start missing them.
+const CONNECTION_EVENT_CAPACITY: usize = 32;
+
+/// Create the transport to use for a new connection to the datacenter, as configured in the
+/// parameters.
+fn new_transport(params: &InitParams, dc_id: i32, addr: SocketAddr) -> Box<dyn Transport + Send> {
+    #[cfg(feature = "proxy")]
+    if let Some(proxy) = params.mtproxy.as_ref() {
+        return proxy.transport(dc_id as i16);
+    }
+    #[cfg(not(feature = "proxy"))]
+    let _ = dc_id;
+
+    // Telegram only accepts obfuscated transports over WebSocket.
+    match (params.transport, params.obfuscated || params.websocket) {
+        (TransportKind::Http, _) if !params.websocket => {
+            Box::new(transport::Http::new(&addr.to_string()))
+        }
+        (TransportKind::Http, _) => {
+            Box::new(transport::Obfuscated::new(transport::Intermediate::new()))
+        }
+        (TransportKind::Abridged, false) => Box::new(transport::Abridged::new()),
+        (TransportKind::Abridged, true) => {
+            Box::new(transport::Obfuscated::new(transport::Abridged::new()))
+        }
+        (TransportKind::Intermediate, false) => Box::new(transport::Intermediate::new()),
+        (TransportKind::Intermediate | TransportKind::Full, true) => {
+            Box::new(transport::Obfuscated::new(transport::Intermediate::new()))
+        }
+        (TransportKind::PaddedIntermediate, false) => {
+            Box::new(transport::PaddedIntermediate::new())
+        }
+        (TransportKind::PaddedIntermediate, true) => Box::new(transport::Obfuscated::new(
+            transport::PaddedIntermediate::new(),
+        )),
+        (TransportKind::Full, false) => Box::new(transport::Full::new()),
+    }
+}
+
+/// Connect to the given datacenter, generating a new authorization key for it.
+async fn connect_sender_with_new_key(
     dc_id: i32,
+    addr: SocketAddr,
     config: &Config,
-) -> Result<(Sender<transport::Full, mtp::Encrypted>, Enqueuer), AuthorizationError> {
-    let transport = transport::Full::new();
+) -> Result<(Sender<Box<dyn Transport + Send>, mtp::Encrypted>, Enqueuer), AuthorizationError> {
+    let transport = new_transport(&config.params, dc_id, addr);
+
+    info!(
+        "creating a new sender and auth key in dc {} {:?}",
+        dc_id, addr
+    );
+
+    #[cfg(feature = "proxy")]
+    let (sender, tx) = if let Some(proxy) = config.params.mtproxy.as_ref() {
+        sender::connect_via_mtproxy(
+            transport,
+            addr,
+            proxy,
+            config.params.reconnection_policy.clone(),
+        )
+        .await?
+    } else if config.params.websocket {
+        sender::connect_via_websocket(
+            transport,
+            addr,
+            websocket_host(dc_id, config),
+            config.params.reconnection_policy.clone(),
+        )
+        .await?
+    } else if let Some(url) = config.params.proxy_url.as_ref() {
+        sender::connect_via_proxy(
+            transport,
+            addr,
+            url,
+            config.params.reconnection_policy.clone(),
+        )
+        .await?
+    } else {
+        sender::connect(transport, addr, config.params.reconnection_policy.clone()).await?
+    };
 
-    let addr: SocketAddr = if let Some(ip) = config.params.server_addr {
-        ip
+    #[cfg(not(feature = "proxy"))]
+    let (sender, tx) = if config.params.websocket {
+        sender::connect_via_websocket(
+            transport,
+            addr,
+            websocket_host(dc_id, config),
+            config.params.reconnection_policy.clone(),
+        )
+        .await?
     } else {
-        DC_ADDRESSES[dc_id as usize].into()
+        sender::connect(transport, addr, config.params.reconnection_policy.clone()).await?
     };
 
-    let (mut sender, request_tx) = if let Some(auth_key) = config.session.dc_auth_key(dc_id) {
+    config.session.insert_dc(dc_id, addr, sender.auth_key());
+    Ok((sender, tx))
+}
+
+/// Connect to the given datacenter and initialize the connection.
+///
+/// If the server no longer knows about the authorization key stored in the session, a new one
+/// is generated and stored in its place. Callers can detect this by comparing the key in the
+/// session before and after connecting.
+pub(crate) async fn connect_sender(
+    dc_id: i32,
+    config: &Config,
+) -> Result<(Sender<Box<dyn Transport + Send>, mtp::Encrypted>, Enqueuer), AuthorizationError> {
+    let addr = dc_address(dc_id, config);
+    let transport = new_transport(&config.params, dc_id, addr);
+
+    let (mut sender, mut request_tx) = if let Some(auth_key) = config.session.dc_auth_key(dc_id) {
         info!(
             "creating a new sender with existing auth key to dc {} {:?}",
             dc_id, addr
         );
+        let salts = config
+            .session
+            .get_dc_salts(dc_id)
+            .into_iter()
+            .map(|salt| tl::types::FutureSalt {
+                valid_since: salt.valid_since,
+                valid_until: salt.valid_until,
+                salt: salt.salt,
+            })
+            .collect::<Vec<_>>();
 
         #[cfg(feature = "proxy")]
-        if let Some(url) = config.params.proxy_url.as_ref() {
+        if let Some(proxy) = config.params.mtproxy.as_ref() {
+            sender::connect_via_mtproxy_with_auth(
+                transport,
+                addr,
+                auth_key,
+                salts,
+                proxy,
+                config.params.reconnection_policy.clone(),
+            )
+            .await?
+        } else if config.params.websocket {
+            sender::connect_via_websocket_with_auth(
+                transport,
+                addr,
+                websocket_host(dc_id, config),
+                auth_key,
+                salts,
+                config.params.reconnection_policy.clone(),
+            )
+            .await?
+        } else if let Some(url) = config.params.proxy_url.as_ref() {
             sender::connect_via_proxy_with_auth(
                 transport,
                 addr,
                 auth_key,
+                salts,
                 url,
-                config.params.reconnection_policy,
+                config.params.reconnection_policy.clone(),
             )
             .await?
         } else {
-            sender::connect_with_auth(transport, addr, auth_key, config.params.reconnection_policy)
-                .await?
+            sender::connect_with_auth(
+                transport,
+                addr,
+                auth_key,
+                salts,
+                config.params.reconnection_policy.clone(),
+            )
+            .await?
         }
 
         #[cfg(not(feature = "proxy"))]
-        sender::connect_with_auth(transport, addr, auth_key, config.params.reconnection_policy)
+        if config.params.websocket {
+            sender::connect_via_websocket_with_auth(
+                transport,
+                addr,
+                websocket_host(dc_id, config),
+                auth_key,
+                salts,
+                config.params.reconnection_policy.clone(),
+            )
+            .await?
+        } else {
+            sender::connect_with_auth(
+                transport,
+                addr,
+                auth_key,
+                salts,
+                config.params.reconnection_policy.clone(),
+            )
             .await?
+        }
     } else {
-        info!(
-            "creating a new sender and auth key in dc {} {:?}",
-            dc_id, addr
-        );
+        connect_sender_with_new_key(dc_id, addr, config).await?
+    };
 
-        #[cfg(feature = "proxy")]
-        let (sender, tx) = if let Some(url) = config.params.proxy_url.as_ref() {
-            sender::connect_via_proxy(transport, addr, url, config.params.reconnection_policy)
-                .await?
-        } else {
-            sender::connect(transport, addr, config.params.reconnection_policy).await?
-        };
+    let init_connection = tl::functions::InvokeWithLayer {
+        layer: tl::LAYER,
+        query: tl::functions::InitConnection {
+            api_id: config.api_id,
+            device_model: config.params.device_model.clone(),
+            system_version: config.params.system_version.clone(),
+            app_version: config.params.app_version.clone(),
+            system_lang_code: config.params.system_lang_code.clone(),
+            lang_pack: "".into(),
+            lang_code: config.params.lang_code.clone(),
+            proxy: None,
+            params: None,
+            query: tl::functions::help::GetConfig {},
+        },
+    };
+    if let Some(lifetime) = config.params.temp_auth_key_lifetime {
+        sender
+            .use_temp_auth_keys(dc_id, lifetime, &init_connection)
+            .await
+            .map_err(InvocationError::from)?;
+    }
+    let remote_config = match sender.invoke(&init_connection).await {
+        Err(InvocationError::Read(ref e)) if is_auth_key_unknown(e) => {
+            warn!(
+                "dc {} no longer knows about our auth key; generating a new one",
+                dc_id
+            );
+            (sender, request_tx) = connect_sender_with_new_key(dc_id, addr, config).await?;
+            if let Some(lifetime) = config.params.temp_auth_key_lifetime {
+                sender
+                    .use_temp_auth_keys(dc_id, lifetime, &init_connection)
+                    .await
+                    .map_err(InvocationError::from)?;
+            }
+            sender.invoke(&init_connection).await?
+        }
+        result => result?,
+    };
+    let tl::enums::Config::Config(remote_config) =
+        tl::enums::Config::from_bytes(&remote_config).map_err(InvocationError::from)?;
 
-        #[cfg(not(feature = "proxy"))]
-        let (sender, tx) =
-            sender::connect(transport, addr, config.params.reconnection_policy).await?;
+    config.session.set_dc_options(
+        &remote_config
+            .dc_options
+            .into_iter()
+            .filter_map(|tl::enums::DcOption::Option(option)| {
+                // Addresses that only work with obfuscated connections cannot be used yet.
+                if option.tcpo_only {
+                    return None;
+                }
+                let (ipv4, ipv6) = match option.ip_address.parse().ok()? {
+                    IpAddr::V4(ip) => (Some(i32::from_le_bytes(ip.octets())), None),
+                    IpAddr::V6(ip) => (None, Some(ip.octets())),
+                };
+                Some(DcOption {
+                    id: option.id,
+                    ipv4,
+                    ipv6,
+                    port: option.port,
+                    media_only: option.media_only,
+                    cdn: option.cdn,
+                    r#static: option.r#static,
+                })
+            })
+            .collect::<Vec<_>>(),
+    );
+    if let Some(salts) = sender.take_new_salts() {
+        store_salts(config.session.as_ref(), dc_id, salts);
+    }
 
-        config.session.insert_dc(dc_id, addr, sender.auth_key());
-        (sender, tx)
-    };
+    Ok((sender, request_tx))
+}
 
-    // TODO handle -404 (we had a previously-valid authkey, but server no longer knows about it)
-    // TODO all up-to-date server addresses should be stored in the session for future initial connections
-    let _remote_config = sender
-        .invoke(&tl::functions::InvokeWithLayer {
-            layer: tl::LAYER,
-            query: tl::functions::InitConnection {
-                api_id: config.api_id,
-                device_model: config.params.device_model.clone(),
-                system_version: config.params.system_version.clone(),
-                app_version: config.params.app_version.clone(),
-                system_lang_code: config.params.system_lang_code.clone(),
-                lang_pack: "".into(),
-                lang_code: config.params.lang_code.clone(),
-                proxy: None,
-                params: None,
-                query: tl::functions::help::GetConfig {},
-            },
+/// Persist the future salts of the authorization key used with the datacenter, so that the
+/// next connection can use a valid salt right away.
+fn store_salts(session: &dyn SessionStorage, dc_id: i32, salts: Vec<tl::types::FutureSalt>) {
+    session.set_dc_salts(
+        dc_id,
+        &salts
+            .into_iter()
+            .map(|salt| FutureSalt {
+                valid_since: salt.valid_since,
+                valid_until: salt.valid_until,
+                salt: salt.salt,
+            })
+            .collect::<Vec<_>>(),
+    );
+}
+
+/// Turn a failure to connect to a datacenter into an error that can be returned by a request.
+///
+/// The server may misbehave while generating a new authorization key, which must not abort the
+/// process.
+fn authorization_to_invocation_error(error: AuthorizationError) -> InvocationError {
+    match error {
+        AuthorizationError::Invoke(e) => e,
+        AuthorizationError::Gen(e) => InvocationError::Read(sender::ReadError::Io(io::Error::new(
+            io::ErrorKind::InvalidData,
+            e,
+        ))),
+    }
+}
+
+/// Whether the error means the server forgot about the authorization key, which it signals with
+/// a transport-level 404.
+fn is_auth_key_unknown(error: &sender::ReadError) -> bool {
+    matches!(
+        error,
+        sender::ReadError::Transport(transport::Error::BadStatus { status: 404 })
+    )
+}
+
+/// The error reported to the application when it was logged out because the server forgot about
+/// the authorization key. It mimics the error Telegram sends when using an unauthorized key.
+pub(crate) fn logged_out_error() -> InvocationError {
+    InvocationError::Rpc(RpcError {
+        code: 401,
+        name: "AUTH_KEY_UNREGISTERED".to_string(),
+        value: None,
+        caused_by: None,
+    })
+}
+
+/// Picks the address to use when connecting to the given datacenter.
+///
+/// The addresses received from Telegram are preferred over the hard-coded ones, as long as they
+/// can be used for any request (so neither media-only nor CDN addresses are used).
+fn dc_address(dc_id: i32, config: &Config) -> SocketAddr {
+    if let Some(addr) = config.params.server_addr {
+        return addr;
+    }
+
+    config
+        .session
+        .get_dc_options(dc_id)
+        .into_iter()
+        .filter(|option| !option.media_only && !option.cdn)
+        .find_map(|option| {
+            let ip = Ipv4Addr::from(option.ipv4?.to_le_bytes());
+            Some(SocketAddr::from((ip, option.port as u16)))
         })
-        .await?;
+        .unwrap_or_else(|| DC_ADDRESSES[dc_id as usize].into())
+}
 
-    Ok((sender, request_tx))
+/// The host serving the WebSocket endpoint of the given datacenter, unless the client must
+/// connect to a specific server address, in which case it is used directly.
+fn websocket_host(dc_id: i32, config: &Config) -> Option<&'static str> {
+    if config.params.server_addr.is_some() {
+        None
+    } else {
+        sender::websocket_host(dc_id)
+    }
 }
 
 /// Method implementations directly related with network connectivity.
@@ -128,11 +406,16 @@ impl Client {
     ///
     /// The connection will be initialized with the data from the input configuration.
     ///
+    /// If the server no longer knows about the authorization key in the session, a new one is
+    /// generated. The user is no longer logged in when this happens, which will be reported by
+    /// [`Client::next_update`] with an `AUTH_KEY_UNREGISTERED` error.
+    ///
     /// # Examples
     ///
     /// ```
     /// use grammers_client::{Client, Config};
     /// use grammers_session::Session;
+    /// use std::sync::Arc;
     ///
     /// // Note: these are example values and are not actually valid.
     /// //       Obtain your own with the developer's phone at https://my.telegram.org.
@@ -141,7 +424,7 @@ impl Client {
     ///
     /// # async fn f() -> Result<(), Box<dyn std::error::Error>> {
     /// let client = Client::connect(Config {
-    ///     session: Session::load_file_or_create("hello-world.session")?,
+    ///     session: Arc::new(Session::load_file_or_create("hello-world.session")?),
     ///     api_id: API_ID,
     ///     api_hash: API_HASH.to_string(),
     ///     params: Default::default(),
@@ -155,7 +438,16 @@ impl Client {
             .get_user()
             .map(|u| u.dc)
             .unwrap_or(DEFAULT_DC);
-        let (sender, request_tx) = connect_sender(dc_id, &config).await?;
+        let old_auth_key = config.session.dc_auth_key(dc_id);
+        let (mut sender, request_tx) = connect_sender(dc_id, &config).await?;
+        let (events, _) = broadcast::channel(CONNECTION_EVENT_CAPACITY);
+        sender.set_connection_events(events.clone());
+        let logged_out =
+            config.session.signed_in() && old_auth_key.is_some_and(|key| key != sender.auth_key());
+        if logged_out {
+            warn!("the new auth key is not authorized; the user was logged out");
+            config.session.remove_user();
+        }
         let message_box = if config.params.catch_up {
             if let Some(state) = config.session.get_state() {
                 MessageBox::load(state)
@@ -181,21 +473,32 @@ impl Client {
         }
 
         let self_user = config.session.get_user();
+        let chat_hashes = ChatHashCache::with_storage(
+            self_user.map(|u| (u.id, u.bot)),
+            Arc::clone(&config.session),
+        );
 
         // Don't bother getting pristine update state if we're not logged in.
-        let should_get_state = message_box.is_empty() && config.session.signed_in();
+        let should_get_state = message_box.is_empty() && config.session.signed_in() && !logged_out;
 
         // TODO Sender doesn't have a way to handle backpressure yet
         let client = Self(Arc::new(ClientInner {
             id: utils::generate_random_id(),
             config,
             conn: Connection::new(sender, request_tx),
+            events,
             state: RwLock::new(ClientState {
                 dc_id,
                 message_box,
-                chat_hashes: ChatHashCache::new(self_user.map(|u| (u.id, u.bot))),
+                chat_hashes,
                 last_update_limit_warn: None,
                 updates,
+                // The authorization key and datacenter options were just stored.
+                session_changed: true,
+                last_session_save: None,
+                saved_update_state: None,
+                session_save_scheduled: false,
+                logged_out,
             }),
             downloader_map: AsyncRwLock::new(HashMap::new()),
         }));
@@ -214,6 +517,7 @@ impl Client {
                 }
             }
         }
+        client.autosave_session();
 
         Ok(client)
     }
@@ -245,24 +549,244 @@ impl Client {
         &self,
         request: &R,
     ) -> Result<R::Return, InvocationError> {
-        self.0
-            .conn
-            .invoke(
-                request,
-                self.0.config.params.flood_sleep_threshold,
-                |updates| self.process_socket_updates(updates),
-            )
+        match self.0.config.params.request_timeout {
+            Some(timeout) => self.invoke_with_timeout(request, timeout).await,
+            None => self.invoke_following_migrations(request, &mut None).await,
+        }
+    }
+
+    /// Like [`Client::invoke`], but failing with [`InvocationError::Timeout`] if the response
+    /// does not arrive in time. This overrides [`InitParams::request_timeout`].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # async fn f(client: grammers_client::Client) -> Result<(), Box<dyn std::error::Error>> {
+    /// use grammers_tl_types as tl;
+    /// use std::time::Duration;
+    ///
+    /// let request = tl::functions::Ping { ping_id: 0 };
+    /// dbg!(client.invoke_with_timeout(&request, Duration::from_secs(5)).await?);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn invoke_with_timeout<R: tl::RemoteCall>(
+        &self,
+        request: &R,
+        timeout: Duration,
+    ) -> Result<R::Return, InvocationError> {
+        tokio::time::timeout(
+            timeout,
+            self.invoke_following_migrations(request, &mut None),
+        )
+        .await
+        .unwrap_or(Err(InvocationError::Timeout))
+    }
+
+    /// Like [`Client::invoke`], but failing with [`InvocationError::Cancelled`] if `cancel`
+    /// completes before the response arrives.
+    ///
+    /// Simply dropping the future returned by [`Client::invoke`] also cancels the request, but
+    /// this makes the outcome explicit.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # async fn f(client: grammers_client::Client) -> Result<(), Box<dyn std::error::Error>> {
+    /// use grammers_tl_types as tl;
+    /// use tokio::sync::oneshot;
+    ///
+    /// let (cancel_tx, cancel_rx) = oneshot::channel::<()>();
+    /// // Calling `cancel_tx.send(())` (or dropping it) from elsewhere will cancel the request.
+    /// let request = tl::functions::Ping { ping_id: 0 };
+    /// dbg!(client.invoke_with_cancel(&request, async { drop(cancel_rx.await) }).await?);
+    /// # drop(cancel_tx);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn invoke_with_cancel<R: tl::RemoteCall, C: Future<Output = ()>>(
+        &self,
+        request: &R,
+        cancel: C,
+    ) -> Result<R::Return, InvocationError> {
+        match select(pin!(self.invoke(request)), pin!(cancel)).await {
+            Either::Left((result, _)) => result,
+            Either::Right(_) => Err(InvocationError::Cancelled),
+        }
+    }
+
+    /// Invoke a request in the home datacenter, or in `file_dc` if it is set.
+    ///
+    /// `PHONE_MIGRATE`, `USER_MIGRATE` and `NETWORK_MIGRATE` errors cause the home datacenter
+    /// to be switched before the request is sent again. `FILE_MIGRATE` errors cause the request
+    /// to be sent to the datacenter indicated by the error instead, which is then stored in
+    /// `file_dc` so that further requests for the same file can go there directly.
+    pub(crate) async fn invoke_following_migrations<R: tl::RemoteCall>(
+        &self,
+        request: &R,
+        file_dc: &mut Option<i32>,
+    ) -> Result<R::Return, InvocationError> {
+        if let Some(dc_id) = *file_dc {
+            return self.invoke_in_dc(request, dc_id).await;
+        }
+
+        let mut migrated = false;
+        let mut recovered = false;
+        loop {
+            let result = self
+                .0
+                .conn
+                .invoke(
+                    request,
+                    self.0.config.params.flood_policy.as_ref(),
+                    |updates| {
+                        // Like `step`, for clients that only invoke requests.
+                        self.process_socket_updates(updates);
+                        self.store_new_salts();
+                        self.autosave_session();
+                    },
+                )
+                .await;
+
+            match result {
+                Err(InvocationError::Rpc(RpcError {
+                    code: MIGRATE_ERROR,
+                    name,
+                    value: Some(dc_id),
+                    ..
+                })) if !migrated && name == "FILE_MIGRATE" => {
+                    *file_dc = Some(dc_id as i32);
+                    break self.invoke_in_dc(request, dc_id as i32).await;
+                }
+                Err(InvocationError::Rpc(RpcError {
+                    code: MIGRATE_ERROR,
+                    name,
+                    value: Some(dc_id),
+                    ..
+                })) if !migrated
+                    && matches!(
+                        name.as_str(),
+                        "PHONE_MIGRATE" | "USER_MIGRATE" | "NETWORK_MIGRATE"
+                    ) =>
+                {
+                    migrated = true;
+                    match self.switch_home_dc(dc_id as i32).await {
+                        Ok(()) => continue,
+                        Err(e) => break Err(authorization_to_invocation_error(e)),
+                    }
+                }
+                Err(InvocationError::Read(ref e)) if !recovered && is_auth_key_unknown(e) => {
+                    recovered = true;
+                    match self.recover_home_auth_key().await {
+                        Ok(true) => break Err(logged_out_error()),
+                        Ok(false) => continue,
+                        Err(e) => break Err(e),
+                    }
+                }
+                result => break result,
+            }
+        }
+    }
+
+    /// Reconnect to the home datacenter after the server forgot about its authorization key,
+    /// which causes a new one to be generated.
+    ///
+    /// Returns `true` if the user was logged in, because the new key is not authorized.
+    async fn recover_home_auth_key(&self) -> Result<bool, InvocationError> {
+        let dc_id = self.0.state.read().unwrap().dc_id;
+
+        // Hold the lock so that concurrent failures don't generate more than one key.
+        let mut sender = self.0.conn.sender.lock().await;
+        if self.0.config.session.dc_auth_key(dc_id) != Some(sender.auth_key()) {
+            // A different task already replaced the key.
+            return Ok(false);
+        }
+
+        let (mut new_sender, request_tx) = connect_sender(dc_id, &self.0.config)
             .await
+            .map_err(authorization_to_invocation_error)?;
+        new_sender.set_connection_events(self.0.events.clone());
+        *sender = new_sender;
+        *self.0.conn.request_tx.write().unwrap() = request_tx;
+        drop(sender);
+        drop(self.0.events.send(ConnectionEvent::Connected));
+
+        let logged_out = self.0.config.session.signed_in();
+        if logged_out {
+            warn!("the new auth key is not authorized; the user was logged out");
+            self.0.config.session.remove_user();
+        }
+        {
+            let mut state = self.0.state.write().unwrap();
+            state.logged_out = logged_out;
+            state.session_changed = true;
+        }
+        self.autosave_session();
+        Ok(logged_out)
+    }
+
+    /// Make `dc_id` the home datacenter, replacing the connection to the previous one.
+    ///
+    /// If the user is logged in, the authorization is exported from the previous datacenter and
+    /// imported into the new one. The session is updated to remember the new home datacenter.
+    pub(crate) async fn switch_home_dc(&self, dc_id: i32) -> Result<(), AuthorizationError> {
+        info!("switching home datacenter to {}", dc_id);
+        let authorization = if self.0.config.session.signed_in() {
+            Some(self.export_authorization(dc_id).await?)
+        } else {
+            None
+        };
+
+        let (mut sender, request_tx) = connect_sender(dc_id, &self.0.config).await?;
+        sender.set_connection_events(self.0.events.clone());
+        *self.0.conn.sender.lock().await = sender;
+        *self.0.conn.request_tx.write().unwrap() = request_tx;
+        drop(self.0.events.send(ConnectionEvent::Connected));
+
+        if let Some(authorization) = authorization {
+            self.0
+                .conn
+                .invoke(
+                    &tl::functions::auth::ImportAuthorization {
+                        id: authorization.id,
+                        bytes: authorization.bytes,
+                    },
+                    self.0.config.params.flood_policy.as_ref(),
+                    |updates| self.process_socket_updates(updates),
+                )
+                .await?;
+        }
+
+        if let Some(user) = self.0.config.session.get_user() {
+            self.0.config.session.set_user(user.id, dc_id, user.bot);
+        }
+        {
+            let mut state = self.0.state.write().unwrap();
+            state.dc_id = dc_id;
+            state.session_changed = true;
+        }
+        self.autosave_session();
+        Ok(())
     }
 
     async fn export_authorization(
         &self,
         target_dc_id: i32,
     ) -> Result<tl::types::auth::ExportedAuthorization, InvocationError> {
+        // Exporting is done in the home datacenter, so there is no migration to follow.
         let request = tl::functions::auth::ExportAuthorization {
             dc_id: target_dc_id,
         };
-        match self.invoke(&request).await {
+        match self
+            .0
+            .conn
+            .invoke(
+                &request,
+                self.0.config.params.flood_policy.as_ref(),
+                |updates| self.process_socket_updates(updates),
+            )
+            .await
+        {
             Ok(tl::enums::auth::ExportedAuthorization::Authorization(exported_auth)) => {
                 Ok(exported_auth)
             }
@@ -286,10 +810,12 @@ impl Client {
                     bytes: authorization.bytes,
                 };
                 new_downloader
-                    .invoke(&request, self.0.config.params.flood_sleep_threshold, drop)
+                    .invoke(&request, self.0.config.params.flood_policy.as_ref(), drop)
                     .await?;
 
                 mutex.insert(dc_id, new_downloader.clone());
+                self.0.state.write().unwrap().session_changed = true;
+                self.autosave_session();
                 Ok(new_downloader.clone())
             }
             Err(AuthorizationError::Invoke(e)) => Err(e),
@@ -311,12 +837,25 @@ impl Client {
         request: &R,
         dc_id: i32,
     ) -> Result<R::Return, InvocationError> {
+        // A migration may point to the home datacenter, which needs no extra connection.
+        if dc_id == self.0.state.read().unwrap().dc_id {
+            return self
+                .0
+                .conn
+                .invoke(
+                    request,
+                    self.0.config.params.flood_policy.as_ref(),
+                    |updates| self.process_socket_updates(updates),
+                )
+                .await;
+        }
+
         let downloader = match self.get_downloader(dc_id).await? {
             None => self.connect_sender(dc_id).await?,
             Some(fd) => fd,
         };
         downloader
-            .invoke(request, self.0.config.params.flood_sleep_threshold, drop)
+            .invoke(request, self.0.config.params.flood_policy.as_ref(), drop)
             .await
     }
 
@@ -337,11 +876,107 @@ impl Client {
     /// # }
     /// ```
     pub async fn step(&self) -> Result<(), sender::ReadError> {
-        let updates = self.0.conn.step().await?;
+        let updates = match self.0.conn.step().await {
+            Ok(updates) => updates,
+            Err(e) if is_auth_key_unknown(&e) => match self.recover_home_auth_key().await {
+                Ok(_) => Vec::new(),
+                Err(InvocationError::Read(e)) => return Err(e),
+                Err(_) => return Err(e),
+            },
+            Err(e) => return Err(e),
+        };
         self.process_socket_updates(updates);
+        self.store_new_salts();
+        self.autosave_session();
         Ok(())
     }
 
+    /// Persist the future salts received by the connection to the home datacenter, if any.
+    fn store_new_salts(&self) {
+        let Some((auth_key, salts)) = self.0.conn.new_salts.lock().unwrap().take() else {
+            return;
+        };
+
+        let mut state = self.0.state.write().unwrap();
+        // The salts are useless if the connection changed to a different key in the meantime.
+        if self.0.config.session.dc_auth_key(state.dc_id) == Some(auth_key) {
+            store_salts(self.0.config.session.as_ref(), state.dc_id, salts);
+            state.session_changed = true;
+        }
+    }
+
+    /// Subscribe to changes in the state of the connection to the home datacenter.
+    ///
+    /// Events are only received while the client is stepped (for example, by
+    /// [`Client::next_update`]), and only those which occur after subscribing. If the receiver
+    /// falls too far behind, the oldest events are dropped and it will report them as lagged.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # async fn f(client: grammers_client::Client) -> Result<(), Box<dyn std::error::Error>> {
+    /// use grammers_client::ConnectionEvent;
+    ///
+    /// let mut events = client.connection_events();
+    /// tokio::spawn(async move {
+    ///     while let Ok(event) = events.recv().await {
+    ///         match event {
+    ///             ConnectionEvent::Disconnected(e) => println!("connection lost: {e}"),
+    ///             ConnectionEvent::Connected => println!("connection restored"),
+    ///             _ => {}
+    ///         }
+    ///     }
+    /// });
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn connection_events(&self) -> broadcast::Receiver<ConnectionEvent> {
+        self.0.events.subscribe()
+    }
+
+    /// Schedule saving the session if [`InitParams::autosave_interval`] is set and something
+    /// changed since the last save.
+    ///
+    /// The save happens as soon as the interval since the last one has elapsed, so changes made
+    /// in the meantime are saved together, even if the client is not stepped anymore.
+    ///
+    /// [`InitParams::autosave_interval`]: crate::InitParams::autosave_interval
+    pub(crate) fn autosave_session(&self) {
+        let Some(interval) = self.0.config.params.autosave_interval else {
+            return;
+        };
+
+        let delay = {
+            let state = &mut *self.0.state.write().unwrap();
+            if state.session_save_scheduled
+                || (!state.session_changed
+                    && state.saved_update_state.as_ref()
+                        == Some(&state.message_box.session_state()))
+            {
+                return;
+            }
+            state.session_save_scheduled = true;
+            state
+                .last_session_save
+                .map(|instant| (instant + interval).saturating_duration_since(Instant::now()))
+                .unwrap_or_default()
+        };
+
+        let Ok(handle) = tokio::runtime::Handle::try_current() else {
+            self.0.save_session();
+            return;
+        };
+        // Don't keep the client alive just to save it. If it's dropped first, the final save
+        // takes care of the pending changes.
+        let inner = Arc::downgrade(&self.0);
+        drop(handle.spawn(async move {
+            tokio::time::sleep(delay).await;
+            if let Some(inner) = inner.upgrade() {
+                inner.save_session();
+            }
+        }));
+    }
+
     /// Run the client by repeatedly calling [`Client::step`] until a graceful disconnection
     /// occurs, or a network error occurs. Incoming updates are ignored and simply dropped.
     /// instead.
@@ -362,48 +997,108 @@ impl Client {
     }
 }
 
+impl ClientInner {
+    /// Save the session now, as scheduled by [`Client::autosave_session`].
+    fn save_session(&self) {
+        let update_state = {
+            let state = &mut *self.state.write().unwrap();
+            let update_state = state.message_box.session_state();
+            state.session_save_scheduled = false;
+            state.session_changed = false;
+            state.last_session_save = Some(Instant::now());
+            state.saved_update_state = Some(update_state.clone());
+            update_state
+        };
+
+        self.config.session.set_state(update_state);
+
+        // Flushing usually means writing to disk, which must not block the executor. The
+        // session itself makes sure an older save can't overwrite a newer one.
+        let session = Arc::clone(&self.config.session);
+        let flush = move || {
+            if let Err(e) = session.flush() {
+                warn!("failed to save session: {}", e);
+            }
+        };
+        match tokio::runtime::Handle::try_current() {
+            Ok(handle) => drop(handle.spawn_blocking(flush)),
+            Err(_) => flush(),
+        }
+    }
+}
+
 impl Connection {
-    fn new(sender: Sender<transport::Full, mtp::Encrypted>, request_tx: Enqueuer) -> Self {
+    fn new(
+        sender: Sender<Box<dyn Transport + Send>, mtp::Encrypted>,
+        request_tx: Enqueuer,
+    ) -> Self {
         Self {
             sender: AsyncMutex::new(sender),
             request_tx: RwLock::new(request_tx),
             step_counter: AtomicU32::new(0),
+            flood_deadlines: Mutex::new(HashMap::new()),
+            new_salts: Mutex::new(None),
         }
     }
 
     pub(crate) async fn invoke<R: tl::RemoteCall, F: Fn(Vec<tl::enums::Updates>)>(
         &self,
         request: &R,
-        flood_sleep_threshold: u32,
+        flood_policy: &dyn FloodPolicy,
         on_updates: F,
     ) -> Result<R::Return, InvocationError> {
-        let mut slept_flood = false;
+        let body = request.to_bytes();
+        let request_id = u32::from_le_bytes([body[0], body[1], body[2], body[3]]);
+        let mut attempts = 0;
 
-        let mut rx = { self.request_tx.read().unwrap().enqueue(request) };
         loop {
-            match rx.try_recv() {
-                Ok(response) => match response {
-                    Ok(body) => break R::Return::from_bytes(&body).map_err(|e| e.into()),
-                    Err(InvocationError::Rpc(RpcError {
-                        name,
-                        code: 420,
-                        value: Some(seconds),
-                        ..
-                    })) if !slept_flood && seconds <= flood_sleep_threshold => {
-                        let delay = std::time::Duration::from_secs(seconds as _);
-                        info!(
-                            "sleeping on {} for {:?} before retrying {}",
-                            name,
-                            delay,
-                            std::any::type_name::<R>()
-                        );
-                        tokio::time::sleep(delay).await;
-                        slept_flood = true;
-                        rx = self.request_tx.read().unwrap().enqueue(request);
-                        continue;
+            let flood = match self.pending_flood_wait(request_id) {
+                Some(flood) => flood,
+                None => match self.invoke_once::<R, F>(body.clone(), &on_updates).await {
+                    Err(InvocationError::Rpc(error)) => {
+                        match FloodWait::from_error(request_id, &error) {
+                            Some(flood) => {
+                                if flood.is_method_wide() {
+                                    self.flood_deadlines
+                                        .lock()
+                                        .unwrap()
+                                        .insert(request_id, Instant::now() + flood.wait);
+                                }
+                                flood
+                            }
+                            None => break Err(InvocationError::Rpc(error)),
+                        }
                     }
-                    Err(e) => break Err(e),
+                    result => break result,
                 },
+            };
+
+            match flood_policy.should_sleep(&flood, attempts) {
+                ControlFlow::Continue(delay) => {
+                    info!(
+                        "sleeping on {} for {:?} before retrying {}",
+                        flood.error.name,
+                        delay,
+                        flood.request_name()
+                    );
+                    tokio::time::sleep(delay).await;
+                    attempts += 1;
+                }
+                ControlFlow::Break(_) => break Err(InvocationError::Rpc(flood.error)),
+            }
+        }
+    }
+
+    /// Send the serialized request once, stepping the network until its response arrives.
+    async fn invoke_once<R: tl::RemoteCall, F: Fn(Vec<tl::enums::Updates>)>(
+        &self,
+        body: Vec<u8>,
+        on_updates: &F,
+    ) -> Result<R::Return, InvocationError> {
+        let mut rx = { self.request_tx.read().unwrap().enqueue_body(body) };
+        loop {
+            match rx.try_recv() {
+                Ok(response) => break response.and_then(|body| Ok(R::Return::from_bytes(&body)?)),
                 Err(TryRecvError::Empty) => {
                     on_updates(self.step().await?);
                 }
@@ -414,6 +1109,33 @@ impl Connection {
         }
     }
 
+    /// The flood-wait which still applies to the given method, if any, as if Telegram had
+    /// returned it.
+    fn pending_flood_wait(&self, request_id: u32) -> Option<FloodWait> {
+        let mut deadlines = self.flood_deadlines.lock().unwrap();
+        let wait = deadlines
+            .get(&request_id)?
+            .checked_duration_since(Instant::now())
+            .filter(|wait| !wait.is_zero());
+        let Some(wait) = wait else {
+            deadlines.remove(&request_id);
+            return None;
+        };
+
+        // Round up, so that sleeping for the value in the error is enough.
+        let seconds = wait.as_secs() as u32 + u32::from(wait.subsec_nanos() != 0);
+        Some(FloodWait {
+            request_id,
+            error: RpcError {
+                code: 420,
+                name: "FLOOD_WAIT".to_string(),
+                value: Some(seconds),
+                caused_by: Some(request_id),
+            },
+            wait,
+        })
+    }
+
     async fn step(&self) -> Result<Vec<tl::enums::Updates>, sender::ReadError> {
         let ticket_number = self.step_counter.load(Ordering::SeqCst);
         let mut sender = self.sender.lock().await;
@@ -424,8 +1146,148 @@ impl Connection {
             Ordering::SeqCst,
             Ordering::SeqCst,
         ) {
-            Ok(_) => sender.step().await, // We're the one to drive IO.
-            Err(_) => Ok(Vec::new()),     // A different task drove IO.
+            Ok(_) => {
+                // We're the one to drive IO.
+                let updates = sender.step().await;
+                if let Some(salts) = sender.take_new_salts() {
+                    *self.new_salts.lock().unwrap() = Some((sender.auth_key(), salts));
+                }
+                updates
+            }
+            Err(_) => Ok(Vec::new()), // A different task drove IO.
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use grammers_mtsender::NoReconnect;
+    use grammers_tl_types::Identifiable;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+    use tokio::runtime;
+
+    /// Records the flood-waits it is asked about, and never sleeps on them.
+    #[derive(Default)]
+    struct RecordFloods(Mutex<Vec<FloodWait>>);
+
+    impl FloodPolicy for RecordFloods {
+        fn should_sleep(&self, flood: &FloodWait, _: usize) -> ControlFlow<(), Duration> {
+            self.0.lock().unwrap().push(flood.clone());
+            ControlFlow::Break(())
         }
     }
+
+    /// Connect to a stand-in server, which is never actually talked to.
+    async fn stand_in_connection(listener: &TcpListener) -> Connection {
+        let (sender, request_tx) = sender::connect_with_auth(
+            Box::new(transport::Intermediate::new()) as Box<dyn Transport + Send>,
+            listener.local_addr().unwrap(),
+            [0; 256],
+            Vec::new(),
+            Arc::new(NoReconnect),
+        )
+        .await
+        .unwrap();
+        Connection::new(sender, request_tx)
+    }
+
+    #[test]
+    fn session_changes_are_saved_without_stepping() {
+        let rt = runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let path = std::env::temp_dir()
+                .join(format!("grammers-autosave-{}.session", std::process::id()));
+            let session = Arc::new(grammers_session::Session::load_file_or_create(&path).unwrap());
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let interval = Duration::from_millis(100);
+            let client = Client(Arc::new(ClientInner {
+                id: 0,
+                config: Config {
+                    session: session.clone(),
+                    api_id: 0,
+                    api_hash: String::new(),
+                    params: InitParams {
+                        autosave_interval: Some(interval),
+                        ..Default::default()
+                    },
+                },
+                conn: stand_in_connection(&listener).await,
+                events: broadcast::channel(1).0,
+                state: RwLock::new(ClientState {
+                    dc_id: 2,
+                    message_box: MessageBox::new(),
+                    chat_hashes: ChatHashCache::new(None),
+                    last_update_limit_warn: None,
+                    updates: VecDeque::new(),
+                    session_changed: false,
+                    // As if the session had just been saved.
+                    last_session_save: Some(Instant::now()),
+                    saved_update_state: None,
+                    session_save_scheduled: false,
+                    logged_out: false,
+                }),
+                downloader_map: AsyncRwLock::new(HashMap::new()),
+            }));
+
+            // The change is saved once the interval elapses, even if the client is not used.
+            session.set_user(123, 2, false);
+            client.0.state.write().unwrap().session_changed = true;
+            client.autosave_session();
+            let load_user = || {
+                grammers_session::Session::load_file(&path)
+                    .unwrap()
+                    .get_user()
+                    .map(|user| user.id)
+            };
+            assert_eq!(load_user(), None);
+
+            tokio::time::sleep(interval * 3).await;
+            assert_eq!(load_user(), Some(123));
+            drop(client);
+            std::fs::remove_file(&path).unwrap();
+        });
+    }
+
+    #[test]
+    fn pending_flood_wait_is_not_sent() {
+        let rt = runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let conn = stand_in_connection(&listener).await;
+            let (mut stream, _) = listener.accept().await.unwrap();
+
+            // As if a previous call to the same method had been told to wait.
+            let ping = tl::functions::Ping { ping_id: 1 };
+            conn.flood_deadlines.lock().unwrap().insert(
+                tl::functions::Ping::CONSTRUCTOR_ID,
+                Instant::now() + Duration::from_secs(60),
+            );
+
+            let policy = RecordFloods::default();
+            let result = conn.invoke(&ping, &policy, drop).await;
+            assert!(matches!(
+                result,
+                Err(InvocationError::Rpc(RpcError { code: 420, .. }))
+            ));
+
+            let floods = policy.0.into_inner().unwrap();
+            assert_eq!(floods.len(), 1);
+            assert_eq!(floods[0].request_id, tl::functions::Ping::CONSTRUCTOR_ID);
+            assert_eq!(floods[0].error.value, Some(60));
+
+            // Nothing was sent to the server.
+            drop(conn);
+            let mut received = Vec::new();
+            stream.read_to_end(&mut received).await.unwrap();
+            assert!(received.is_empty());
+        });
+    }
 }
@@ -8,6 +8,7 @@
 
 //! Methods to deal with and offer access to updates.
 
+use super::net::logged_out_error;
 use super::Client;
 use crate::types::{ChatMap, Update};
 use futures_util::future::{select, Either};
@@ -26,6 +27,10 @@ const UPDATE_LIMIT_EXCEEDED_LOG_COOLDOWN: Duration = Duration::from_secs(300);
 impl Client {
     /// Returns the next update from the buffer where they are queued until used.
     ///
+    /// If the server forgot about the authorization key while logged in, a new key is generated
+    /// and a `401 AUTH_KEY_UNREGISTERED` error is returned once, to signal that the user needs
+    /// to sign in again.
+    ///
     /// # Example
     ///
     /// ```
@@ -80,6 +85,9 @@ impl Client {
         loop {
             let (deadline, get_diff, get_channel_diff) = {
                 let state = &mut *self.0.state.write().unwrap();
+                if std::mem::take(&mut state.logged_out) {
+                    return Err(logged_out_error());
+                }
                 if let Some(update) = state.updates.pop_front() {
                     return Ok(update);
                 }
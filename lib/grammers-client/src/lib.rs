@@ -46,10 +46,18 @@ pub mod parsers;
 pub mod types;
 pub(crate) mod utils;
 
-pub use client::{Client, Config, EditTwoFaError, Filters, InitParams, SignInError};
+pub use client::{
+    Client, Config, EditTwoFaError, Filters, FloodPolicy, FloodSleep, FloodWait, InitParams,
+    NoFloodSleep, SignInError, TransportKind,
+};
 pub use types::{button, reply_markup, ChatMap, InputMessage, Update};
 
 pub use grammers_mtproto::transport;
-pub use grammers_mtsender::{FixedReconnect, InvocationError, NoReconnect, ReconnectionPolicy};
+pub use grammers_mtsender::{
+    ConnectionEvent, ExponentialBackoff, FixedReconnect, InvocationError, NoReconnect, ReadError,
+    ReconnectionPolicy,
+};
+#[cfg(feature = "proxy")]
+pub use grammers_mtsender::{MtProxy, ProxySecret};
 pub use grammers_session as session;
 pub use grammers_tl_types;
@@ -84,3 +84,64 @@ pub fn ige_decrypt(ciphertext: &[u8], key: &[u8; 32], iv: &[u8; 32]) -> Vec<u8>
 
     plaintext
 }
+
+/// AES-256 in CTR mode, with a 128-bit big-endian counter.
+///
+/// This is a stream cipher, so the same instance must be used to process the whole stream in
+/// order, as it keeps track of its position within the keystream.
+pub struct AesCtr {
+    cipher: aes::Aes256,
+    counter: [u8; 16],
+    keystream: [u8; 16],
+    pos: usize,
+}
+
+impl AesCtr {
+    pub fn new(key: &[u8; 32], iv: &[u8; 16]) -> Self {
+        Self {
+            cipher: aes::Aes256::new(GenericArray::from_slice(key)),
+            counter: *iv,
+            keystream: [0; 16],
+            pos: 16,
+        }
+    }
+
+    /// Encrypt or decrypt the input buffer in-place, advancing the position in the keystream.
+    pub fn apply(&mut self, buffer: &mut [u8]) {
+        for byte in buffer {
+            if self.pos == 16 {
+                self.keystream = self.counter;
+                self.cipher
+                    .encrypt_block(GenericArray::from_mut_slice(&mut self.keystream));
+                self.counter = (u128::from_be_bytes(self.counter).wrapping_add(1)).to_be_bytes();
+                self.pos = 0;
+            }
+            *byte ^= self.keystream[self.pos];
+            self.pos += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hex;
+
+    #[test]
+    fn ctr_nist_vector() {
+        // NIST SP 800-38A, F.5.5 CTR-AES256.Encrypt.
+        let key = hex::from_hex("603deb1015ca71be2b73aef0857d77811f352c073b6108d72d9810a30914dff4");
+        let iv = hex::from_hex("f0f1f2f3f4f5f6f7f8f9fafbfcfdfeff");
+        let mut buffer =
+            hex::from_hex("6bc1bee22e409f96e93d7e117393172aae2d8a571e03ac9c9eb76fac45af8e51");
+        let expected =
+            hex::from_hex("601ec313775789a5b7a7f504bbf3d228f443e3ca4d62b59aca84e990cacaf5c5");
+
+        // Processing the stream in uneven pieces must produce the same output.
+        let mut ctr = AesCtr::new(&key.try_into().unwrap(), &iv.try_into().unwrap());
+        let (left, right) = buffer.split_at_mut(7);
+        ctr.apply(left);
+        ctr.apply(right);
+        assert_eq!(buffer, expected);
+    }
+}
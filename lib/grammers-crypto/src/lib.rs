@@ -95,6 +95,51 @@ fn calc_key(auth_key: &AuthKey, msg_key: &[u8; 16], side: Side) -> ([u8; 32], [u
     (aes_key, aes_iv)
 }
 
+/// Calculate the key based on Telegram [guidelines for MTProto 1], which are
+/// still used to encrypt the message binding a temporary key to a permanent one.
+///
+/// [guidelines for MTProto 1]: https://core.telegram.org/mtproto/description_v1#defining-aes-key-and-initialization-vector
+fn calc_key_v1(auth_key: &AuthKey, msg_key: &[u8; 16], side: Side) -> ([u8; 32], [u8; 32]) {
+    let x = side.x();
+
+    // sha1_a = SHA1 (msg_key + substr (auth_key, x, 32));
+    let sha1_a = sha1!(msg_key, &auth_key.data[x..x + 32]);
+
+    // sha1_b = SHA1 (substr (auth_key, 32+x, 16) + msg_key + substr (auth_key, 48+x, 16));
+    let sha1_b = sha1!(
+        &auth_key.data[32 + x..32 + x + 16],
+        msg_key,
+        &auth_key.data[48 + x..48 + x + 16]
+    );
+
+    // sha1_c = SHA1 (substr (auth_key, 64+x, 32) + msg_key);
+    let sha1_c = sha1!(&auth_key.data[64 + x..64 + x + 32], msg_key);
+
+    // sha1_d = SHA1 (msg_key + substr (auth_key, 96+x, 32));
+    let sha1_d = sha1!(msg_key, &auth_key.data[96 + x..96 + x + 32]);
+
+    // aes_key = substr (sha1_a, 0, 8) + substr (sha1_b, 8, 12) + substr (sha1_c, 4, 12);
+    let aes_key = {
+        let mut buffer = [0; 32];
+        buffer[0..8].copy_from_slice(&sha1_a[0..8]);
+        buffer[8..8 + 12].copy_from_slice(&sha1_b[8..8 + 12]);
+        buffer[20..20 + 12].copy_from_slice(&sha1_c[4..4 + 12]);
+        buffer
+    };
+
+    // aes_iv = substr (sha1_a, 8, 12) + substr (sha1_b, 0, 8) + substr (sha1_c, 16, 4) + substr (sha1_d, 0, 8);
+    let aes_iv = {
+        let mut buffer = [0; 32];
+        buffer[0..12].copy_from_slice(&sha1_a[8..8 + 12]);
+        buffer[12..12 + 8].copy_from_slice(&sha1_b[0..8]);
+        buffer[20..20 + 4].copy_from_slice(&sha1_c[16..16 + 4]);
+        buffer[24..24 + 8].copy_from_slice(&sha1_d[0..8]);
+        buffer
+    };
+
+    (aes_key, aes_iv)
+}
+
 /// Determines the padding length needed for a plaintext of a certain length,
 /// according to the following citation:
 ///
@@ -148,6 +193,49 @@ pub fn encrypt_data_v2(buffer: &mut DequeBuffer<u8>, auth_key: &AuthKey) {
     do_encrypt_data_v2(buffer, auth_key, &random_padding)
 }
 
+// Inner body of `encrypt_data_v1`, separated for testing purposes.
+fn do_encrypt_data_v1(plaintext: &[u8], auth_key: &AuthKey, random_padding: &[u8; 16]) -> Vec<u8> {
+    // Encryption is done by the client
+    let side = Side::Client;
+
+    // msg_key = substr (SHA1 (plaintext), 4, 16);
+    let msg_key = {
+        let sha = sha1!(plaintext);
+        let mut buffer = [0; 16];
+        buffer.copy_from_slice(&sha[4..4 + 16]);
+        buffer
+    };
+
+    // "[...] padding 0-15 bytes so that the length is divisible by 16"
+    let mut padded = plaintext.to_vec();
+    let padding_len = (16 - (plaintext.len() % 16)) % 16;
+    padded.extend(random_padding.iter().take(padding_len));
+
+    let (key, iv) = calc_key_v1(auth_key, &msg_key, side);
+    aes::ige_encrypt(&mut padded[..], &key, &iv);
+
+    let mut ciphertext = Vec::with_capacity(8 + 16 + padded.len());
+    ciphertext.extend(auth_key.key_id);
+    ciphertext.extend(msg_key);
+    ciphertext.extend(padded);
+    ciphertext
+}
+
+/// This function implements the [MTProto 1.0 algorithm] for encrypting messages, which is
+/// only still used for the message that binds a temporary authorization key to the permanent
+/// one (the `encrypted_message` of `auth.bindTempAuthKey`).
+///
+/// [MTProto 1.0 algorithm]: https://core.telegram.org/mtproto/description_v1
+pub fn encrypt_data_v1(plaintext: &[u8], auth_key: &AuthKey) -> Vec<u8> {
+    let random_padding = {
+        let mut rnd = [0; 16];
+        getrandom(&mut rnd).expect("failed to generate a secure padding");
+        rnd
+    };
+
+    do_encrypt_data_v1(plaintext, auth_key, &random_padding)
+}
+
 /// This method is the inverse of `encrypt_data_v2`.
 pub fn decrypt_data_v2(ciphertext: &[u8], auth_key: &AuthKey) -> Result<Vec<u8>, Error> {
     // Decryption is done from the server
@@ -238,6 +326,23 @@ pub fn decrypt_ige(padded_ciphertext: &[u8], key: &[u8; 32], iv: &[u8; 32]) -> V
     aes::ige_decrypt(padded_ciphertext, key, iv)
 }
 
+/// Derive a 512-bit key from a password using PBKDF2 with HMAC-SHA512.
+pub fn pbkdf2_sha512(password: &[u8], salt: &[u8], rounds: u32) -> [u8; 64] {
+    let mut key = [0u8; 64];
+    pbkdf2::pbkdf2::<hmac::Hmac<sha2::Sha512>>(password, salt, rounds, &mut key)
+        .expect("HMAC can be initialized with any key length");
+    key
+}
+
+/// Compute the HMAC-SHA256 of the given data.
+pub fn hmac_sha256(key: &[u8], data: &[u8]) -> [u8; 32] {
+    use hmac::Mac;
+    let mut mac = hmac::Hmac::<sha2::Sha256>::new_from_slice(key)
+        .expect("HMAC can be initialized with any key length");
+    mac.update(data);
+    mac.finalize().into_bytes().into()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -324,6 +429,27 @@ mod tests {
         assert_eq!(&buffer[..], expected);
     }
 
+    #[test]
+    fn encrypt_client_data_v1() {
+        let auth_key = get_test_auth_key();
+        let random_padding = [0; 16];
+        let expected = vec![
+            50, 209, 88, 110, 164, 87, 223, 200, 200, 130, 39, 170, 72, 144, 14, 82, 149, 202, 203,
+            166, 167, 177, 152, 142, 94, 205, 2, 62, 218, 159, 151, 93, 42, 140, 133, 195, 37, 141,
+            127, 222, 221, 235, 127, 237, 97, 83, 16, 66, 108, 78, 65, 76, 40, 0, 172, 28, 206,
+            194, 236, 25, 117, 229, 123, 165, 74, 184, 223, 132, 148, 113, 164, 144,
+        ];
+
+        assert_eq!(
+            do_encrypt_data_v1(
+                b"Hello, world! This data should remain secure!",
+                &auth_key,
+                &random_padding
+            ),
+            expected
+        );
+    }
+
     #[test]
     fn decrypt_server_data_v2() {
         let ciphertext = vec![
@@ -425,4 +551,13 @@ mod tests {
         ];
         assert_eq!(decrypt_ige(&ciphertext, &key, &iv), expected);
     }
+
+    #[test]
+    fn check_hmac_sha256() {
+        // RFC 4231, test case 2.
+        assert_eq!(
+            hmac_sha256(b"Jefe", b"what do ya want for nothing?").to_vec(),
+            hex::from_hex("5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843")
+        );
+    }
 }
@@ -6,10 +6,8 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 use glass_pumpkin::safe_prime;
-use hmac::Hmac;
 use num_bigint::{BigInt, BigUint, Sign};
 use num_traits::ops::euclid::Euclid;
-use sha2::Sha512;
 
 // H(data) := sha256(data)
 use crate::sha256 as h;
@@ -165,8 +163,7 @@ fn ph2(password: impl AsRef<[u8]>, salt1: &[u8], salt2: &[u8]) -> [u8; 32] {
     let hash1 = ph1(password, salt1, salt2);
 
     // 512-bit derived key
-    let mut dk = [0u8; 64];
-    pbkdf2::pbkdf2::<Hmac<Sha512>>(&hash1, salt1, 100000, &mut dk).unwrap();
+    let dk = crate::pbkdf2_sha512(&hash1, salt1, 100000);
 
     sh(dk, salt2)
 }
@@ -243,6 +243,30 @@ fn do_step1(random_bytes: &[u8; 16]) -> Result<(Vec<u8>, Step1), Error> {
 
 /// The second step of the process to generate an authorization key.
 pub fn step2(data: Step1, response: &[u8]) -> Result<(Vec<u8>, Step2), Error> {
+    step2_with(data, response, None)
+}
+
+/// The second step of the process to generate a temporary authorization key.
+///
+/// The key will be used to talk with the datacenter `dc_id` and expire after `expires_in`
+/// seconds. It needs to be bound to a permanent key before it can be used to invoke most
+/// requests, which [`Encrypted`] can take care of.
+///
+/// [`Encrypted`]: crate::mtp::Encrypted
+pub fn step2_temp(
+    data: Step1,
+    response: &[u8],
+    dc_id: i32,
+    expires_in: i32,
+) -> Result<(Vec<u8>, Step2), Error> {
+    step2_with(data, response, Some((dc_id, expires_in)))
+}
+
+fn step2_with(
+    data: Step1,
+    response: &[u8],
+    temp: Option<(i32, i32)>,
+) -> Result<(Vec<u8>, Step2), Error> {
     if TRACE_AUTH_GEN {
         println!("< {}", hex::to_hex(response));
     }
@@ -257,7 +281,7 @@ pub fn step2(data: Step1, response: &[u8]) -> Result<(Vec<u8>, Step2), Error> {
         println!("r {}", hex::to_hex(&random_bytes));
     }
 
-    let res = do_step2(data, response, &random_bytes);
+    let res = do_step2(data, response, &random_bytes, temp);
     if TRACE_AUTH_GEN {
         if let Ok((x, _)) = &res {
             println!("> {}", hex::to_hex(x));
@@ -270,6 +294,7 @@ fn do_step2(
     data: Step1,
     response: &[u8],
     random_bytes: &[u8; 32 + 224],
+    temp: Option<(i32, i32)>,
 ) -> Result<(Vec<u8>, Step2), Error> {
     // Step 2. Validate the PQ response. Return `(p, q)` if it's valid.
     let Step1 { nonce } = data;
@@ -323,14 +348,29 @@ fn do_step2(
 
     // "pq is a representation of a natural number (in binary big endian format)"
     // https://core.telegram.org/mtproto/auth_key#dh-exchange-initiation
-    let pq_inner_data = tl::enums::PQInnerData::Data(tl::types::PQInnerData {
-        pq: pq.to_be_bytes().to_vec(),
-        p: p_bytes.clone(),
-        q: q_bytes.clone(),
-        nonce,
-        server_nonce: res_pq.server_nonce,
-        new_nonce,
-    })
+    //
+    // Temporary keys use a different constructor, which also carries the datacenter they will
+    // be used in and how long they should live for.
+    let pq_inner_data = match temp {
+        None => tl::enums::PQInnerData::Data(tl::types::PQInnerData {
+            pq: pq.to_be_bytes().to_vec(),
+            p: p_bytes.clone(),
+            q: q_bytes.clone(),
+            nonce,
+            server_nonce: res_pq.server_nonce,
+            new_nonce,
+        }),
+        Some((dc, expires_in)) => tl::enums::PQInnerData::TempDc(tl::types::PQInnerDataTempDc {
+            pq: pq.to_be_bytes().to_vec(),
+            p: p_bytes.clone(),
+            q: q_bytes.clone(),
+            nonce,
+            server_nonce: res_pq.server_nonce,
+            new_nonce,
+            dc,
+            expires_in,
+        }),
+    }
     .to_bytes();
 
     // sha_digest + data + random_bytes
@@ -753,7 +793,7 @@ mod tests {
         assert_eq!(request, step1_request.to_vec());
         let response = step1_response;
 
-        let (request, data) = do_step2(data, &response, &step2_random)?;
+        let (request, data) = do_step2(data, &response, &step2_random, None)?;
         assert_eq!(request, step2_request.to_vec());
         let response = step2_response;
 
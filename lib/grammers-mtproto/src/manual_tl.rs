@@ -178,10 +178,8 @@ impl Deserializable for MessageContainer {
 /// ```
 ///
 /// Note that this is "not used", in favour of `msg_container`.
-// Even though we use `MessageCopy::CONSTRUCTOR_ID, the dead code lint fires.
-#[allow(dead_code)]
 pub(crate) struct MessageCopy {
-    pub orig_message: Vec<Message>,
+    pub orig_message: Message,
 }
 
 impl Identifiable for MessageCopy {
@@ -189,6 +187,19 @@ impl Identifiable for MessageCopy {
     const CONSTRUCTOR_ID: u32 = 0xe06046b2;
 }
 
+impl Deserializable for MessageCopy {
+    fn deserialize(buf: &mut Cursor) -> Result<Self, tl::deserialize::Error> {
+        let constructor_id = u32::deserialize(buf)?;
+        if constructor_id != Self::CONSTRUCTOR_ID {
+            return Err(tl::deserialize::Error::UnexpectedConstructor { id: constructor_id });
+        }
+
+        Ok(Self {
+            orig_message: Message::deserialize(buf)?,
+        })
+    }
+}
+
 /// This struct represents the following TL definition:
 ///
 /// ```tl
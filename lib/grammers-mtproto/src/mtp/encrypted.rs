@@ -6,14 +6,16 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 use super::{
-    Deserialization, DeserializationFailure, DeserializeError, Mtp, RpcResult, RpcResultError,
+    Deserialization, DeserializationFailure, DeserializeError, Mtp, NewSession, ResendRequest,
+    RpcResult, RpcResultError,
 };
 use crate::utils::StackBuffer;
 use crate::{manual_tl, MsgId};
 use getrandom::getrandom;
-use grammers_crypto::{decrypt_data_v2, encrypt_data_v2, AuthKey, DequeBuffer};
+use grammers_crypto::{decrypt_data_v2, encrypt_data_v1, encrypt_data_v2, AuthKey, DequeBuffer};
 use grammers_tl_types::{self as tl, Cursor, Deserializable, Identifiable, Serializable};
-use log::info;
+use log::{debug, info, warn};
+use std::collections::VecDeque;
 use std::mem;
 use std::time::{Instant, SystemTime, UNIX_EPOCH};
 
@@ -28,6 +30,19 @@ const NUM_FUTURE_SALTS: i32 = 64;
 /// Used to prevent small fluctuations in the system clock.
 const SALT_USE_DELAY: i32 = 60;
 
+/// How many identifiers of the messages received from the server to remember.
+///
+/// Used to answer questions about their status, and to tell whether a message was already
+/// received.
+const MAX_RECEIVED_MSG_IDS: usize = 512;
+
+// Message status codes used by `msgs_state_info` and `msgs_all_info`.
+const MSG_STATUS_UNKNOWN: u8 = 1;
+const MSG_STATUS_NOT_RECEIVED: u8 = 2;
+const MSG_STATUS_NOT_RECEIVED_YET: u8 = 3;
+const MSG_STATUS_RECEIVED: u8 = 4;
+const MSG_STATUS_ACKNOWLEDGED: u8 = 8;
+
 static UPDATE_IDS: [u32; 8] = [
     tl::types::UpdateShortMessage::CONSTRUCTOR_ID,
     tl::types::UpdateShortChatMessage::CONSTRUCTOR_ID,
@@ -48,7 +63,9 @@ static UPDATE_IDS: [u32; 8] = [
 pub struct Builder {
     time_offset: i32,
     first_salt: i64,
+    salts: Vec<tl::types::FutureSalt>,
     compression_threshold: Option<usize>,
+    binding: Option<(AuthKey, i32)>,
 }
 
 /// An implementation of the [Mobile Transport Protocol] for ciphertext
@@ -74,9 +91,28 @@ pub struct Encrypted {
     /// Internal request for salts which should not be propagated.
     salt_request_msg_id: Option<i64>,
 
+    /// Whether future salts were received since they were last taken with
+    /// [`Encrypted::take_new_salts`].
+    new_salts: bool,
+
+    /// If the authorization key is temporary and not bound yet, the permanent key it must be
+    /// bound to, along with the Unix timestamp at which the temporary key expires.
+    pending_binding: Option<(AuthKey, i32)>,
+
+    /// Internal request to bind the temporary key which should not be propagated.
+    bind_request_msg_id: Option<i64>,
+
     /// The secure, random identifier for this instance.
     client_id: i64,
 
+    /// The identifier of the session that was abandoned by [`Encrypted::start_new_session`],
+    /// whose late messages from the server are ignored.
+    previous_client_id: Option<i64>,
+
+    /// Whether the payload being deserialized belongs to a session that was abandoned while
+    /// processing it, so that its messages don't start yet another session.
+    in_abandoned_session: bool,
+
     /// The current message sequence number.
     sequence: i32,
 
@@ -92,6 +128,16 @@ pub struct Encrypted {
     /// [Content-related Message]: https://core.telegram.org/mtproto/description#content-related-message
     pending_ack: Vec<i64>,
 
+    /// Identifiers of the last messages received from the server, oldest first.
+    received_msg_ids: VecDeque<i64>,
+
+    /// Answers to the server's requests for the status of messages, to be sent along with the
+    /// next requests.
+    pending_state_info: Vec<tl::types::MsgsStateInfo>,
+
+    /// Identifiers of server messages which were missed and must be requested again.
+    pending_resend: Vec<i64>,
+
     /// If present, the threshold in bytes at which a message will be
     /// considered large enough to attempt compressing it. Otherwise,
     /// outgoing messages will never be compressed.
@@ -116,33 +162,79 @@ impl Builder {
         self
     }
 
+    /// Configures the future salts known from a previous instance, as returned by
+    /// [`Encrypted::take_new_salts`].
+    ///
+    /// Salts which have already expired are dropped. If any remain, they are used instead of
+    /// the first salt, so that the server doesn't need to tell the right one.
+    pub fn salts(mut self, salts: Vec<tl::types::FutureSalt>) -> Self {
+        self.salts = salts;
+        self
+    }
+
     /// Configures the compression threshold for outgoing messages.
     pub fn compression_threshold(mut self, threshold: Option<usize>) -> Self {
         self.compression_threshold = threshold;
         self
     }
 
+    /// Indicates that the authorization key given to [`Builder::finish`] is a temporary key,
+    /// which expires at the Unix timestamp `expires_at` and has yet to be bound to the
+    /// permanent key `perm_auth_key`.
+    ///
+    /// The binding request is sent before anything else, and no other request will be sent
+    /// until Telegram has answered it.
+    pub fn bind_to(mut self, perm_auth_key: [u8; 256], expires_at: i32) -> Self {
+        self.binding = Some((AuthKey::from_bytes(perm_auth_key), expires_at));
+        self
+    }
+
     /// Finishes the builder and returns the `MTProto` instance with all
     /// the configuration changes applied.
     pub fn finish(self, auth_key: [u8; 256]) -> Encrypted {
-        Encrypted {
-            auth_key: AuthKey::from_bytes(auth_key),
-            time_offset: self.time_offset,
-            salts: vec![tl::types::FutureSalt {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("system time is before epoch")
+            .as_secs() as i32
+            + self.time_offset;
+
+        let mut salts = self.salts;
+        salts.retain(|salt| salt.valid_until > now);
+        salts.sort_by_key(|salt| -salt.valid_since);
+        let start_salt_time = if salts.is_empty() {
+            salts.push(tl::types::FutureSalt {
                 valid_since: 0,
                 valid_until: i32::MAX,
                 salt: self.first_salt,
-            }],
-            start_salt_time: None,
+            });
+            None
+        } else {
+            info!("using {} previously known future salts", salts.len());
+            Some((now, Instant::now()))
+        };
+
+        Encrypted {
+            auth_key: AuthKey::from_bytes(auth_key),
+            time_offset: self.time_offset,
+            salts,
+            start_salt_time,
             salt_request_msg_id: None,
+            new_salts: false,
+            pending_binding: self.binding,
+            bind_request_msg_id: None,
             client_id: {
                 let mut buffer = [0u8; 8];
                 getrandom(&mut buffer).expect("failed to generate a secure client_id");
                 i64::from_le_bytes(buffer)
             },
+            previous_client_id: None,
+            in_abandoned_session: false,
             sequence: 0,
             last_msg_id: 0,
             pending_ack: vec![],
+            received_msg_ids: VecDeque::new(),
+            pending_state_info: Vec::new(),
+            pending_resend: Vec::new(),
             compression_threshold: self.compression_threshold,
             deserialization: Vec::new(),
             msg_count: 0,
@@ -157,6 +249,8 @@ impl Encrypted {
             time_offset: 0,
             compression_threshold: crate::DEFAULT_COMPRESSION_THRESHOLD,
             first_salt: 0,
+            salts: Vec::new(),
+            binding: None,
         }
     }
 
@@ -165,6 +259,19 @@ impl Encrypted {
         self.auth_key.to_bytes()
     }
 
+    /// The future salts that are still known to be valid, if new ones were received from the
+    /// server since the last call.
+    ///
+    /// These can be persisted and given to [`Builder::salts`] when connecting again, which saves
+    /// a round trip to learn the right salt.
+    pub fn take_new_salts(&mut self) -> Option<Vec<tl::types::FutureSalt>> {
+        if mem::take(&mut self.new_salts) {
+            Some(self.salts.clone())
+        } else {
+            None
+        }
+    }
+
     /// Correct our time offset based on a known valid message ID.
     fn correct_time_offset(&mut self, msg_id: i64) {
         let now = SystemTime::now()
@@ -176,6 +283,19 @@ impl Encrypted {
         self.time_offset = correct - now;
     }
 
+    /// Abandon the current session and start a fresh one, re-synchronizing the time offset
+    /// with the given server message ID.
+    ///
+    /// Every message sent in the previous session must be sent again, because the server
+    /// will answer them (if at all) in that session.
+    fn start_new_session(&mut self, server_msg_id: i64) {
+        let previous_client_id = self.client_id;
+        self.reset();
+        self.previous_client_id = Some(previous_client_id);
+        self.in_abandoned_session = true;
+        self.correct_time_offset(server_msg_id);
+    }
+
     /// Generates a new unique message ID based on the current
     /// time (in ms) since epoch, applying a known time offset.
     fn get_new_msg_id(&mut self) -> i64 {
@@ -213,24 +333,36 @@ impl Encrypted {
         content_related: bool,
     ) -> MsgId {
         let msg_id = self.get_new_msg_id();
+        self.serialize_msg_with_id(buffer, msg_id, body, content_related);
+        MsgId(msg_id)
+    }
 
+    fn serialize_msg_with_id(
+        &mut self,
+        buffer: &mut DequeBuffer<u8>,
+        msg_id: i64,
+        body: &[u8],
+        content_related: bool,
+    ) {
         msg_id.serialize(buffer);
         self.get_seq_no(content_related).serialize(buffer);
         (body.len() as i32).serialize(buffer);
         buffer.extend(body);
 
         self.msg_count += 1;
-        MsgId(msg_id)
     }
 
     fn get_current_salt(&self) -> i64 {
         self.salts.last().map(|s| s.salt).unwrap_or(0)
     }
 
-    fn try_request_salts(&mut self, buffer: &mut DequeBuffer<u8>) {
+    /// Serialize a request for more future salts if only the current one is left, returning
+    /// `true` if the request was added to the buffer.
+    fn try_request_salts(&mut self, buffer: &mut DequeBuffer<u8>) -> bool {
         if self.salts.len() == 1
             && self.salt_request_msg_id.is_none()
             && self.get_current_salt() != 0
+            && self.msg_count == 0
         {
             // If salts are requested in a container leading to bad_msg,
             // the bad_msg_id will refer to the container, not the salts request.
@@ -238,18 +370,142 @@ impl Encrypted {
             // We don't keep track of containers and content-related messages they contain for simplicity.
             // This would break, because we couldn't identify the response.
             //
-            // So salts are only requested once we have a valid salt to reduce the chances of this happening.
-            if self.salts.len() == 1 {
-                info!("only one future salt remaining; asking for more salts");
-            }
+            // So salts are only requested once we have a valid salt, and on their own.
+            info!("only one future salt remaining; asking for more salts");
             let body = tl::functions::GetFutureSalts {
                 num: NUM_FUTURE_SALTS,
             }
             .to_bytes();
+            let msg_id = self.serialize_msg(buffer, &body, true);
+            self.salt_request_msg_id = Some(msg_id.0);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Serialize the request to [bind the temporary key] to the permanent one, if it's needed
+    /// and was not sent yet.
+    ///
+    /// The binding message is encrypted with the permanent key following MTProto 1.0, and must
+    /// share its `msg_id` with the message containing the request itself.
+    ///
+    /// [bind the temporary key]: https://core.telegram.org/method/auth.bindTempAuthKey
+    fn try_request_binding(&mut self, buffer: &mut DequeBuffer<u8>) {
+        let (perm_auth_key, expires_at) = match &self.pending_binding {
+            Some((key, expires_at)) if self.bind_request_msg_id.is_none() => {
+                (key.clone(), *expires_at)
+            }
+            _ => return,
+        };
+
+        let nonce = {
+            let mut buffer = [0u8; 8];
+            getrandom(&mut buffer).expect("failed to generate a secure binding nonce");
+            i64::from_le_bytes(buffer)
+        };
+        let msg_id = self.get_new_msg_id();
+
+        let inner = tl::enums::BindAuthKeyInner::Inner(tl::types::BindAuthKeyInner {
+            nonce,
+            temp_auth_key_id: self.auth_key.id(),
+            perm_auth_key_id: perm_auth_key.id(),
+            temp_session_id: self.client_id,
+            expires_at,
+        })
+        .to_bytes();
+
+        // random:int128 msg_id:long seqno:int msg_len:int message_data
+        let mut plaintext = Vec::with_capacity(16 + 8 + 4 + 4 + inner.len());
+        plaintext.extend({
+            let mut random = [0u8; 16];
+            getrandom(&mut random).expect("failed to generate secure data for binding");
+            random
+        });
+        plaintext.extend(msg_id.to_le_bytes());
+        plaintext.extend(0i32.to_le_bytes());
+        plaintext.extend((inner.len() as i32).to_le_bytes());
+        plaintext.extend(inner);
+
+        let body = tl::functions::auth::BindTempAuthKey {
+            perm_auth_key_id: perm_auth_key.id(),
+            nonce,
+            expires_at,
+            encrypted_message: encrypt_data_v1(&plaintext, &perm_auth_key),
+        }
+        .to_bytes();
+
+        info!("binding temporary auth key to the permanent one");
+        self.serialize_msg_with_id(buffer, msg_id, &body, true);
+        self.bind_request_msg_id = Some(msg_id);
+    }
+
+    /// Serialize an acknowledgement for all the messages pending one, if any.
+    fn push_pending_ack(&mut self, buffer: &mut DequeBuffer<u8>) {
+        if !self.pending_ack.is_empty() {
+            // TODO avoid to_bytes here, serialize it in-place
+            let body = tl::enums::MsgsAck::Ack(tl::types::MsgsAck {
+                msg_ids: mem::take(&mut self.pending_ack),
+            })
+            .to_bytes();
+            self.serialize_msg(buffer, &body, false);
+        }
+    }
+
+    /// Serialize the answers to the server's requests about messages, and the requests to
+    /// re-send the messages which were missed, if any.
+    fn push_pending_answers(&mut self, buffer: &mut DequeBuffer<u8>) {
+        for info in mem::take(&mut self.pending_state_info) {
+            let body = tl::enums::MsgsStateInfo::Info(info).to_bytes();
+            self.serialize_msg(buffer, &body, false);
+        }
+
+        if !self.pending_resend.is_empty() {
+            let body = tl::enums::MsgResendReq::Req(tl::types::MsgResendReq {
+                msg_ids: mem::take(&mut self.pending_resend),
+            })
+            .to_bytes();
             self.serialize_msg(buffer, &body, true);
         }
     }
 
+    /// Determine the status of a message which the server claims to have sent, as used by
+    /// `msgs_state_info`.
+    fn received_msg_status(&self, msg_id: i64) -> u8 {
+        if self.received_msg_ids.contains(&msg_id) {
+            if self.pending_ack.contains(&msg_id) {
+                MSG_STATUS_RECEIVED
+            } else {
+                MSG_STATUS_RECEIVED | MSG_STATUS_ACKNOWLEDGED
+            }
+        } else {
+            match (
+                self.received_msg_ids.iter().min(),
+                self.received_msg_ids.iter().max(),
+            ) {
+                (Some(&lowest), _) if msg_id < lowest => MSG_STATUS_UNKNOWN,
+                (_, Some(&highest)) if msg_id > highest => MSG_STATUS_NOT_RECEIVED_YET,
+                (Some(_), Some(_)) => MSG_STATUS_NOT_RECEIVED,
+                _ => MSG_STATUS_UNKNOWN,
+            }
+        }
+    }
+
+    /// Ask the sender to re-send our messages which the server reports it did not receive.
+    fn resend_unreceived(&mut self, msg_ids: &[i64], info: &[u8]) {
+        for (&msg_id, &status) in msg_ids.iter().zip(info.iter()) {
+            if matches!(
+                status & 7,
+                MSG_STATUS_UNKNOWN | MSG_STATUS_NOT_RECEIVED | MSG_STATUS_NOT_RECEIVED_YET
+            ) {
+                self.deserialization
+                    .push(Deserialization::Resend(ResendRequest {
+                        msg_id: MsgId(msg_id),
+                    }));
+            }
+        }
+    }
+
     /// `finalize`, but without encryption.
     ///
     /// The buffer is *not* cleared, but is instead returned.
@@ -291,6 +547,10 @@ impl Encrypted {
         if message.requires_ack() {
             self.pending_ack.push(message.msg_id);
         }
+        if self.received_msg_ids.len() == MAX_RECEIVED_MSG_IDS {
+            self.received_msg_ids.pop_front();
+        }
+        self.received_msg_ids.push_back(message.msg_id);
 
         // Handle all the possible Service Messages:
         // * https://core.telegram.org/mtproto/service_messages
@@ -434,6 +694,24 @@ impl Encrypted {
         let manual_tl::RpcResult { req_msg_id, result } = rpc_result;
         let msg_id = MsgId(req_msg_id);
 
+        if self.bind_request_msg_id == Some(req_msg_id) {
+            // Response to internal request, do not propagate.
+            self.bind_request_msg_id = None;
+            if let Ok(true) = bool::from_bytes(&result) {
+                info!("temporary auth key bound successfully");
+                self.pending_binding = None;
+                return Ok(());
+            }
+
+            // Every other request would fail with an unbound key, so let the caller decide what
+            // to do (for example, generating a different temporary key).
+            let error = match tl::enums::RpcError::from_bytes(&result) {
+                Ok(tl::enums::RpcError::Error(error)) => Some(error.error_message),
+                Err(_) => None,
+            };
+            return Err(DeserializeError::BindFailed { error });
+        }
+
         // Any error during a RPC result will be given to the user,
         // which means this method itself is doing its job `Ok`.
         let inner_constructor = match inner_constructor {
@@ -648,6 +926,12 @@ impl Encrypted {
         {
             // Response to internal request, do not propagate.
             self.salt_request_msg_id = None;
+        } else if self
+            .bind_request_msg_id
+            .is_some_and(|msg_id| msg_id == bad_msg.bad_msg_id())
+        {
+            // Response to internal request, do not propagate. It will be sent again.
+            self.bind_request_msg_id = None;
         } else {
             self.deserialization
                 .push(Deserialization::BadMessage(super::BadMessage {
@@ -673,23 +957,24 @@ impl Encrypted {
         };
 
         match bad_msg.error_code {
-            16 => {
-                // Sent `msg_id` was too low (our `time_offset` is wrong).
-                self.correct_time_offset(message.msg_id);
-            }
-            17 => {
-                // Sent `msg_id` was too high (our `time_offset` is wrong).
-                self.correct_time_offset(message.msg_id);
-            }
-            32 => {
-                // Sent `seq_no` was too low. Bump it by some large-ish value.
-                // TODO start with a fresh session rather than guessing
-                self.sequence += 64;
+            // Sent `msg_id` was too low or too high (our `time_offset` is wrong), or
+            // sent `seq_no` was too low or too high. Rather than guessing a correction,
+            // start over with a fresh session, which the server will accept.
+            16 | 17 | 32 | 33 if self.in_abandoned_session => {
+                // The message was sent in a session that has already been abandoned (for
+                // example, because of a previous notification in the same container), so it
+                // will be sent again in the new session anyway.
+                debug!(
+                    "bad msg notification with code {} for the previous session; ignoring",
+                    bad_msg.error_code
+                );
             }
-            33 => {
-                // Sent `seq_no` was too high (this error doesn't seem to occur).
-                // TODO start with a fresh session rather than guessing
-                self.sequence -= 16;
+            16 | 17 | 32 | 33 => {
+                warn!(
+                    "bad msg notification with code {}; starting a new session",
+                    bad_msg.error_code
+                );
+                self.start_new_session(message.msg_id);
             }
             _ => {
                 // Just notify about it.
@@ -710,8 +995,18 @@ impl Encrypted {
     /// ```
     ///
     /// [Request for Message Status Information]: https://core.telegram.org/mtproto/service_messages_about_messages#request-for-message-status-information
-    fn handle_state_req(&self, _message: manual_tl::Message) -> Result<(), DeserializeError> {
-        // TODO implement
+    fn handle_state_req(&mut self, message: manual_tl::Message) -> Result<(), DeserializeError> {
+        let tl::enums::MsgsStateReq::Req(req) = tl::enums::MsgsStateReq::from_bytes(&message.body)?;
+        let info = req
+            .msg_ids
+            .iter()
+            .map(|&msg_id| self.received_msg_status(msg_id))
+            .collect();
+
+        self.pending_state_info.push(tl::types::MsgsStateInfo {
+            req_msg_id: message.msg_id,
+            info,
+        });
         Ok(())
     }
 
@@ -751,8 +1046,15 @@ impl Encrypted {
     /// valid, the message is to be wrapped in `msg_copy`).
     ///
     /// [Informational Message regarding Status of Messages]: https://core.telegram.org/mtproto/service_messages_about_messages#informational-message-regarding-status-of-messages
-    fn handle_state_info(&mut self, _message: manual_tl::Message) -> Result<(), DeserializeError> {
-        // TODO implement
+    fn handle_state_info(&mut self, message: manual_tl::Message) -> Result<(), DeserializeError> {
+        // We never send `msgs_state_req`, so there are no statuses we could act upon.
+        let tl::enums::MsgsStateInfo::Info(info) =
+            tl::enums::MsgsStateInfo::from_bytes(&message.body)?;
+        info!(
+            "got status of {} message(s) for {}",
+            info.info.len(),
+            info.req_msg_id
+        );
         Ok(())
     }
 
@@ -773,8 +1075,9 @@ impl Encrypted {
     /// This message does not require an acknowledgment.
     ///
     /// [Voluntary Communication of Status of Messages]: https://core.telegram.org/mtproto/service_messages_about_messages#voluntary-communication-of-status-of-messages
-    fn handle_msg_all(&mut self, _message: manual_tl::Message) -> Result<(), DeserializeError> {
-        // TODO implement
+    fn handle_msg_all(&mut self, message: manual_tl::Message) -> Result<(), DeserializeError> {
+        let tl::enums::MsgsAllInfo::Info(all) = tl::enums::MsgsAllInfo::from_bytes(&message.body)?;
+        self.resend_unreceived(&all.msg_ids, &all.info);
         Ok(())
     }
 
@@ -805,16 +1108,18 @@ impl Encrypted {
         &mut self,
         message: manual_tl::Message,
     ) -> Result<(), DeserializeError> {
-        // TODO https://github.com/telegramdesktop/tdesktop/blob/8f82880b938e06b7a2a27685ef9301edb12b4648/Telegram/SourceFiles/mtproto/connection.cpp#L1790-L1820
-        // TODO https://github.com/telegramdesktop/tdesktop/blob/8f82880b938e06b7a2a27685ef9301edb12b4648/Telegram/SourceFiles/mtproto/connection.cpp#L1822-L1845
-        let msg_detailed = tl::enums::MsgDetailedInfo::from_bytes(&message.body)?;
-        match msg_detailed {
-            tl::enums::MsgDetailedInfo::Info(x) => {
-                self.pending_ack.push(x.answer_msg_id);
-            }
-            tl::enums::MsgDetailedInfo::MsgNewDetailedInfo(x) => {
-                self.pending_ack.push(x.answer_msg_id);
-            }
+        // If the answer was already received, the server only needs to know that (it probably
+        // did not get the acknowledgement). Otherwise, the answer has to be requested again.
+        let answer_msg_id = match tl::enums::MsgDetailedInfo::from_bytes(&message.body)? {
+            tl::enums::MsgDetailedInfo::Info(x) => x.answer_msg_id,
+            tl::enums::MsgDetailedInfo::MsgNewDetailedInfo(x) => x.answer_msg_id,
+        };
+
+        if self.received_msg_ids.contains(&answer_msg_id) {
+            self.pending_ack.push(answer_msg_id);
+        } else {
+            info!("answer {} was missed; asking to resend it", answer_msg_id);
+            self.pending_resend.push(answer_msg_id);
         }
         Ok(())
     }
@@ -847,9 +1152,31 @@ impl Encrypted {
     ///
     /// [Explicit Request to Re-Send Answers]: https://core.telegram.org/mtproto/service_messages_about_messages#explicit-request-to-re-send-answers
     /// [Explicit Request to Re-Send Messages]: https://core.telegram.org/mtproto/service_messages_about_messages#explicit-request-to-re-send-messages
-    fn handle_msg_resend(&self, _message: manual_tl::Message) -> Result<(), DeserializeError> {
-        // TODO implement
-        // `msg_resend_ans_req` seems to never occur (it was even missing from `mtproto.tl`)
+    fn handle_msg_resend(&mut self, message: manual_tl::Message) -> Result<(), DeserializeError> {
+        match tl::enums::MsgResendReq::from_bytes(&message.body)? {
+            tl::enums::MsgResendReq::Req(req) => {
+                for msg_id in req.msg_ids {
+                    self.deserialization
+                        .push(Deserialization::Resend(ResendRequest {
+                            msg_id: MsgId(msg_id),
+                        }));
+                }
+            }
+            // We never send answers, so there is nothing to re-send, and the request is
+            // treated as a `msgs_state_req` (it seems to never occur anyway).
+            tl::enums::MsgResendReq::MsgResendAnsReq(req) => {
+                let info = req
+                    .msg_ids
+                    .iter()
+                    .map(|&msg_id| self.received_msg_status(msg_id))
+                    .collect();
+
+                self.pending_state_info.push(tl::types::MsgsStateInfo {
+                    req_msg_id: message.msg_id,
+                    info,
+                });
+            }
+        }
         Ok(())
     }
 
@@ -896,6 +1223,7 @@ impl Encrypted {
         self.start_salt_time = Some((salts.now, Instant::now()));
         self.salts = salts.salts.0;
         self.salts.sort_by_key(|salt| -salt.valid_since);
+        self.new_salts = true;
         info!("got {} future salts", self.salts.len());
 
         Ok(())
@@ -961,8 +1289,16 @@ impl Encrypted {
     /// ```
     ///
     /// [Request to Destroy Session]: https://core.telegram.org/mtproto/service_messages#request-to-destroy-session
-    fn handle_destroy_session(&self, _message: manual_tl::Message) -> Result<(), DeserializeError> {
-        // TODO implement
+    fn handle_destroy_session(&self, message: manual_tl::Message) -> Result<(), DeserializeError> {
+        // The result does not refer to the request, so it cannot be propagated as such.
+        match tl::enums::DestroySessionRes::from_bytes(&message.body)? {
+            tl::enums::DestroySessionRes::DestroySessionOk(x) => {
+                info!("session {} was destroyed", x.session_id);
+            }
+            tl::enums::DestroySessionRes::DestroySessionNone(x) => {
+                info!("session {} to destroy did not exist", x.session_id);
+            }
+        }
         Ok(())
     }
 
@@ -1002,7 +1338,6 @@ impl Encrypted {
         &mut self,
         message: manual_tl::Message,
     ) -> Result<(), DeserializeError> {
-        // TODO notify upper layers about the need to use getDifference
         let new_session = tl::enums::NewSession::from_bytes(&message.body)?;
         match new_session {
             tl::enums::NewSession::Created(x) => {
@@ -1012,6 +1347,10 @@ impl Encrypted {
                     valid_until: i32::MAX,
                     salt: x.server_salt,
                 });
+                self.deserialization
+                    .push(Deserialization::NewSession(NewSession {
+                        first_msg_id: MsgId(x.first_msg_id),
+                    }));
             }
         }
         Ok(())
@@ -1083,8 +1422,14 @@ impl Encrypted {
     /// in a simple container with the same result.
     ///
     /// [Message Copies]: https://core.telegram.org/mtproto/service_messages#message-copies
-    fn handle_msg_copy(&self, _message: manual_tl::Message) -> Result<(), DeserializeError> {
-        panic!("msg_copy should not be used")
+    fn handle_msg_copy(&mut self, message: manual_tl::Message) -> Result<(), DeserializeError> {
+        let copy = manual_tl::MessageCopy::from_bytes(&message.body)?;
+        if self.received_msg_ids.contains(&copy.orig_message.msg_id) {
+            self.pending_ack.push(copy.orig_message.msg_id);
+            Ok(())
+        } else {
+            self.process_message(copy.orig_message)
+        }
     }
 
     /// **[Packed Object]**
@@ -1157,9 +1502,12 @@ impl Encrypted {
     /// sense to set `max_delay` to a value that is comparable in magnitude
     /// to ping time.
     ///
+    /// This query is only ever sent by clients (see [`Mtp::push_http_wait`]), so there is
+    /// nothing to do if the server were to send it.
+    ///
     /// [HTTP Wait/Long Poll]: https://core.telegram.org/mtproto/service_messages#http-wait-long-poll
     fn handle_http_wait(&mut self, _message: manual_tl::Message) -> Result<(), DeserializeError> {
-        // TODO implement
+        warn!("ignoring http_wait sent by the server");
         Ok(())
     }
 
@@ -1197,34 +1545,33 @@ impl Mtp for Encrypted {
         // TODO rather than taking in bytes, take requests, serialize them in place, and if too large drop the last part of the buffer
 
         // Check to see if the next salt can be used already. If it can, drop the current one and,
-        // if the next salt is the last one, fetch more.
+        // if the next salt is the last one, fetch more. Several salts may be dropped at once if
+        // they were restored from a previous instance.
         if let Some((start_secs, start_instant)) = self.start_salt_time {
-            if self.salts.len() > 1 {
-                let salt = &self.salts[self.salts.len() - 2];
-                let now = start_secs + start_instant.elapsed().as_secs() as i32;
-                if now >= salt.valid_since + SALT_USE_DELAY {
-                    self.salts.pop();
-                }
+            let now = start_secs + start_instant.elapsed().as_secs() as i32;
+            while self.salts.len() > 1
+                && now >= self.salts[self.salts.len() - 2].valid_since + SALT_USE_DELAY
+            {
+                self.salts.pop();
             }
         }
 
-        self.try_request_salts(buffer);
-        if self.salt_request_msg_id.is_some() {
-            // Don't add anything else to the container while we still need new salts.
+        // A temporary key cannot be used for anything else until it's bound.
+        self.try_request_binding(buffer);
+        if self.pending_binding.is_some() {
+            return None;
+        }
+
+        if self.try_request_salts(buffer) {
+            // Don't add anything else to the container with the request for new salts.
             return None;
         }
 
         // If we need to acknowledge messages, this notification goes in with the rest of requests
         // so that we can also include it. It has priority over user requests because these should
         // be sent out as soon as possible.
-        if !self.pending_ack.is_empty() {
-            // TODO avoid to_bytes here, serialize it in-place
-            let body = tl::enums::MsgsAck::Ack(tl::types::MsgsAck {
-                msg_ids: mem::take(&mut self.pending_ack),
-            })
-            .to_bytes();
-            self.serialize_msg(buffer, &body, false);
-        }
+        self.push_pending_ack(buffer);
+        self.push_pending_answers(buffer);
 
         // Serialize `MAXIMUM_LENGTH` requests at most.
         if self.msg_count == manual_tl::MessageContainer::MAXIMUM_LENGTH {
@@ -1274,6 +1621,26 @@ impl Mtp for Encrypted {
         }
     }
 
+    fn push_http_wait(&mut self, buffer: &mut DequeBuffer<u8>, max_wait: i32) -> bool {
+        if self.msg_count == manual_tl::MessageContainer::MAXIMUM_LENGTH {
+            return false;
+        }
+
+        // Acknowledgements are sent along, or the server would keep resending the messages.
+        self.push_pending_ack(buffer);
+        self.push_pending_answers(buffer);
+
+        // Reply as soon as there is anything to send, but wait up to `max_wait` otherwise.
+        let body = tl::enums::HttpWait::Wait(tl::types::HttpWait {
+            max_delay: 0,
+            wait_after: 0,
+            max_wait,
+        })
+        .to_bytes();
+        self.serialize_msg(buffer, &body, false);
+        true
+    }
+
     /// Processes an encrypted response from the server.
     fn deserialize(&mut self, payload: &[u8]) -> Result<Vec<Deserialization>, DeserializeError> {
         crate::utils::check_message_buffer(payload)?;
@@ -1284,8 +1651,15 @@ impl Mtp for Encrypted {
         let _salt = i64::deserialize(&mut buffer)?;
         let client_id = i64::deserialize(&mut buffer)?;
         if client_id != self.client_id {
-            panic!("wrong session id");
+            if self.previous_client_id == Some(client_id) {
+                // Messages that were sent in the previous session are sent again in this one.
+                debug!("ignoring message from the previous session");
+            } else {
+                warn!("ignoring message from unknown session {client_id}");
+            }
+            return Ok(Vec::new());
         }
+        self.in_abandoned_session = false;
 
         self.process_message(manual_tl::Message::deserialize(&mut buffer)?)?;
 
@@ -1302,9 +1676,15 @@ impl Mtp for Encrypted {
             getrandom(&mut buffer).expect("failed to generate a secure client_id");
             i64::from_le_bytes(buffer)
         };
+        self.previous_client_id = None;
         self.sequence = 0;
+        self.salt_request_msg_id = None;
         self.last_msg_id = 0;
+        self.bind_request_msg_id = None;
         self.pending_ack.clear();
+        self.received_msg_ids.clear();
+        self.pending_state_info.clear();
+        self.pending_resend.clear();
         self.msg_count = 0;
     }
 }
@@ -1329,6 +1709,69 @@ mod tests {
         [0; 256]
     }
 
+    /// Encrypt a message as if it was sent by the server, so that it can be deserialized.
+    fn server_payload(mtproto: &Encrypted, msg_id: i64, body: Vec<u8>) -> Vec<u8> {
+        use sha2::{Digest, Sha256};
+
+        let mut plaintext = Vec::new();
+        mtproto.get_current_salt().serialize(&mut plaintext);
+        mtproto.client_id.serialize(&mut plaintext);
+        manual_tl::Message {
+            msg_id,
+            seq_no: 1,
+            body,
+        }
+        .serialize(&mut plaintext);
+        plaintext.extend(vec![0; 16 + (16 - plaintext.len() % 16)]);
+
+        // Same as `encrypt_data_v2`, but from the server's side (x = 8).
+        let key = auth_key();
+        let msg_key_large: [u8; 32] = Sha256::new()
+            .chain_update(&key[96..96 + 32])
+            .chain_update(&plaintext)
+            .finalize()
+            .into();
+        let msg_key = &msg_key_large[8..8 + 16];
+        let sha256_a: [u8; 32] = Sha256::new()
+            .chain_update(msg_key)
+            .chain_update(&key[8..8 + 36])
+            .finalize()
+            .into();
+        let sha256_b: [u8; 32] = Sha256::new()
+            .chain_update(&key[48..48 + 36])
+            .chain_update(msg_key)
+            .finalize()
+            .into();
+        let mut aes_key = [0; 32];
+        aes_key[..8].copy_from_slice(&sha256_a[..8]);
+        aes_key[8..24].copy_from_slice(&sha256_b[8..24]);
+        aes_key[24..].copy_from_slice(&sha256_a[24..]);
+        let mut aes_iv = [0; 32];
+        aes_iv[..8].copy_from_slice(&sha256_b[..8]);
+        aes_iv[8..24].copy_from_slice(&sha256_a[8..24]);
+        aes_iv[24..].copy_from_slice(&sha256_b[24..]);
+
+        let mut payload = AuthKey::from_bytes(key).id().to_le_bytes().to_vec();
+        payload.extend(msg_key);
+        payload.extend(grammers_crypto::encrypt_ige(&plaintext, &aes_key, &aes_iv));
+        payload
+    }
+
+    /// Deserialize a message from the server, returning the identifiers of the requests the
+    /// server asked to re-send.
+    fn deserialize_resends(mtproto: &mut Encrypted, msg_id: i64, body: Vec<u8>) -> Vec<i64> {
+        let payload = server_payload(mtproto, msg_id, body);
+        mtproto
+            .deserialize(&payload)
+            .unwrap()
+            .into_iter()
+            .map(|result| match result {
+                Deserialization::Resend(resend) => resend.msg_id.0,
+                _ => panic!("unexpected deserialization result"),
+            })
+            .collect()
+    }
+
     fn ensure_buffer_is_message(buffer: &[u8], body: &[u8], seq_no: u8) {
         // buffer[0..8] is the msg_id, based on `SystemTime::now()`
         assert_ne!(&buffer[0..8], [0, 0, 0, 0, 0, 0, 0, 0]);
@@ -1370,6 +1813,25 @@ mod tests {
         ensure_buffer_is_message(buffer, REQUEST, 1);
     }
 
+    #[test]
+    fn ensure_correct_http_wait_serialization() {
+        let mut buffer = DequeBuffer::with_capacity(0, 0);
+        let mut mtproto = Encrypted::build().finish(auth_key());
+
+        assert!(mtproto.push_http_wait(&mut buffer, 25000));
+        mtproto.finalize_plain(&mut buffer);
+
+        // It is not content-related, so the sequence number is not incremented.
+        let buffer = &buffer[MESSAGE_PREFIX_LEN..];
+        let body = tl::enums::HttpWait::Wait(tl::types::HttpWait {
+            max_delay: 0,
+            wait_after: 0,
+            max_wait: 25000,
+        })
+        .to_bytes();
+        ensure_buffer_is_message(buffer, &body, 0);
+    }
+
     #[test]
     fn ensure_correct_multi_serialization() {
         let mut buffer = DequeBuffer::with_capacity(0, 0);
@@ -1497,4 +1959,445 @@ mod tests {
             assert!(buffer.as_ref().windows(4).any(|w| w == GZIP_PACKED_HEADER));
         }
     }
+
+    #[test]
+    fn ensure_temp_key_is_bound_first() {
+        let mut buffer = DequeBuffer::with_capacity(0, 0);
+        let mut mtproto = Encrypted::build()
+            .bind_to([1; 256], 86400)
+            .finish(auth_key());
+
+        // The binding request is the only one sent.
+        assert!(mtproto.push(&mut buffer, REQUEST).is_none());
+        let bind_msg_id = i64::from_le_bytes(buffer[0..8].try_into().unwrap());
+        assert_eq!(
+            &buffer[16..20],
+            tl::functions::auth::BindTempAuthKey::CONSTRUCTOR_ID.to_le_bytes()
+        );
+        mtproto.finalize_plain(&mut buffer);
+
+        // Nothing else is sent while waiting for the binding to complete.
+        let mut buffer = DequeBuffer::with_capacity(0, 0);
+        assert!(mtproto.push(&mut buffer, REQUEST).is_none());
+        assert!(buffer.is_empty());
+
+        let mut body = Vec::new();
+        manual_tl::RpcResult::CONSTRUCTOR_ID.serialize(&mut body);
+        bind_msg_id.serialize(&mut body);
+        true.serialize(&mut body);
+        mtproto
+            .process_message(manual_tl::Message {
+                msg_id: bind_msg_id + 1,
+                seq_no: 1,
+                body,
+            })
+            .unwrap();
+
+        // The result of the internal request is not propagated, and requests can now be sent.
+        assert!(mtproto.deserialization.is_empty());
+        assert!(mtproto.push(&mut buffer, REQUEST).is_some());
+    }
+
+    #[test]
+    fn ensure_failed_binding_is_an_error() {
+        let mut buffer = DequeBuffer::with_capacity(0, 0);
+        let mut mtproto = Encrypted::build()
+            .bind_to([1; 256], 86400)
+            .finish(auth_key());
+
+        assert!(mtproto.push(&mut buffer, REQUEST).is_none());
+        let bind_msg_id = i64::from_le_bytes(buffer[0..8].try_into().unwrap());
+        mtproto.finalize_plain(&mut buffer);
+
+        let mut body = Vec::new();
+        manual_tl::RpcResult::CONSTRUCTOR_ID.serialize(&mut body);
+        bind_msg_id.serialize(&mut body);
+        tl::enums::RpcError::Error(tl::types::RpcError {
+            error_code: 400,
+            error_message: "ENCRYPTED_MESSAGE_INVALID".into(),
+        })
+        .serialize(&mut body);
+        let result = mtproto.process_message(manual_tl::Message {
+            msg_id: bind_msg_id + 1,
+            seq_no: 1,
+            body,
+        });
+
+        assert_eq!(
+            result,
+            Err(DeserializeError::BindFailed {
+                error: Some("ENCRYPTED_MESSAGE_INVALID".into())
+            })
+        );
+        assert!(mtproto.deserialization.is_empty());
+
+        // The key is still not bound, so the binding is sent again rather than the request.
+        let mut buffer = DequeBuffer::with_capacity(0, 0);
+        assert!(mtproto.push(&mut buffer, REQUEST).is_none());
+        assert_eq!(
+            &buffer[16..20],
+            tl::functions::auth::BindTempAuthKey::CONSTRUCTOR_ID.to_le_bytes()
+        );
+    }
+
+    /// Make the server reject a request with the given `bad_msg_notification` code, which
+    /// should start a new session.
+    fn ensure_bad_msg_starts_new_session(error_code: i32) {
+        let mut mtproto = Encrypted::build().finish(auth_key());
+        let mut buffer = DequeBuffer::with_capacity(0, 0);
+        let msg_id = mtproto.push(&mut buffer, REQUEST).unwrap();
+        mtproto.finalize_plain(&mut buffer);
+        let old_client_id = mtproto.client_id;
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+        let server_msg_id = ((now + 1000) << 32) | 1;
+        let bad_msg = tl::enums::BadMsgNotification::Notification(tl::types::BadMsgNotification {
+            bad_msg_id: msg_id.0,
+            bad_msg_seqno: 1,
+            error_code,
+        })
+        .to_bytes();
+        let late_answer = server_payload(&mtproto, server_msg_id + 4, Vec::new());
+        let payload = server_payload(&mtproto, server_msg_id, bad_msg);
+
+        match mtproto.deserialize(&payload).unwrap().as_slice() {
+            [Deserialization::BadMessage(bad_msg)] => {
+                assert_eq!(bad_msg.msg_id, msg_id);
+                assert_eq!(bad_msg.code, error_code);
+                assert!(bad_msg.resets_session());
+            }
+            _ => panic!("unexpected deserialization result"),
+        }
+        assert_ne!(mtproto.client_id, old_client_id);
+        assert_eq!(mtproto.sequence, 0);
+        assert!((999..=1000).contains(&mtproto.time_offset));
+
+        // Messages for the old session may still arrive, but should be ignored.
+        assert!(mtproto.deserialize(&late_answer).unwrap().is_empty());
+
+        // The next message should be the first one of the new session.
+        buffer.clear();
+        mtproto.push(&mut buffer, REQUEST);
+        mtproto.finalize_plain(&mut buffer);
+        assert_eq!(&buffer[8..16], mtproto.client_id.to_le_bytes());
+        ensure_buffer_is_message(&buffer[MESSAGE_PREFIX_LEN..], REQUEST, 1);
+    }
+
+    #[test]
+    fn ensure_msg_id_too_low_starts_new_session() {
+        ensure_bad_msg_starts_new_session(16);
+    }
+
+    #[test]
+    fn ensure_msg_id_too_high_starts_new_session() {
+        ensure_bad_msg_starts_new_session(17);
+    }
+
+    #[test]
+    fn ensure_seq_no_too_low_starts_new_session() {
+        ensure_bad_msg_starts_new_session(32);
+    }
+
+    #[test]
+    fn ensure_seq_no_too_high_starts_new_session() {
+        ensure_bad_msg_starts_new_session(33);
+    }
+
+    #[test]
+    fn ensure_bad_msgs_in_one_container_start_a_single_new_session() {
+        let mut mtproto = Encrypted::build().finish(auth_key());
+        let mut buffer = DequeBuffer::with_capacity(0, 0);
+        let msg_ids = [
+            mtproto.push(&mut buffer, REQUEST).unwrap(),
+            mtproto.push(&mut buffer, REQUEST_B).unwrap(),
+        ];
+        mtproto.finalize_plain(&mut buffer);
+        let old_client_id = mtproto.client_id;
+
+        let server_msg_id = ((unix_now() as i64) << 32) | 1;
+        let mut body = MSG_CONTAINER_HEADER.to_vec();
+        (msg_ids.len() as i32).serialize(&mut body);
+        for (i, msg_id) in msg_ids.into_iter().enumerate() {
+            manual_tl::Message {
+                msg_id: server_msg_id + 4 * i as i64,
+                seq_no: 1,
+                body: tl::enums::BadMsgNotification::Notification(tl::types::BadMsgNotification {
+                    bad_msg_id: msg_id.0,
+                    bad_msg_seqno: 1,
+                    error_code: 32,
+                })
+                .to_bytes(),
+            }
+            .serialize(&mut body);
+        }
+        let late_answer = server_payload(&mtproto, server_msg_id + 12, Vec::new());
+        let payload = server_payload(&mtproto, server_msg_id + 8, body);
+
+        assert_eq!(mtproto.deserialize(&payload).unwrap().len(), 2);
+        assert_ne!(mtproto.client_id, old_client_id);
+        assert_eq!(mtproto.previous_client_id, Some(old_client_id));
+        assert!(mtproto.deserialize(&late_answer).unwrap().is_empty());
+    }
+
+    #[test]
+    fn ensure_unknown_session_is_ignored() {
+        let mut mtproto = Encrypted::build().finish(auth_key());
+        let client_id = mtproto.client_id;
+        mtproto.client_id = client_id.wrapping_add(1);
+        let payload = server_payload(&mtproto, ((unix_now() as i64) << 32) | 1, Vec::new());
+        mtproto.client_id = client_id;
+
+        assert!(mtproto.deserialize(&payload).unwrap().is_empty());
+    }
+
+    fn unix_now() -> i32 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i32
+    }
+
+    #[test]
+    fn ensure_salts_are_requested_alone() {
+        let mut mtproto = Encrypted::build().first_salt(123).finish(auth_key());
+        let mut buffer = DequeBuffer::with_capacity(0, 0);
+
+        // Only the request for salts should be sent.
+        assert_eq!(mtproto.push(&mut buffer, REQUEST), None);
+        let request_msg_id = mtproto.salt_request_msg_id.unwrap();
+        mtproto.finalize_plain(&mut buffer);
+        let body = tl::functions::GetFutureSalts {
+            num: NUM_FUTURE_SALTS,
+        }
+        .to_bytes();
+        ensure_buffer_is_message(&buffer[MESSAGE_PREFIX_LEN..], &body, 1);
+        assert_eq!(
+            &buffer[MESSAGE_PREFIX_LEN..][..8],
+            request_msg_id.to_le_bytes()
+        );
+
+        // Requests should not wait for the salts to arrive.
+        buffer.clear();
+        assert!(mtproto.push(&mut buffer, REQUEST).is_some());
+        mtproto.finalize_plain(&mut buffer);
+
+        let now = unix_now();
+        let salts = (0..3)
+            .map(|i| tl::types::FutureSalt {
+                valid_since: now + i * 1800,
+                valid_until: now + (i + 1) * 1800,
+                salt: i as i64,
+            })
+            .collect::<Vec<_>>();
+        let future_salts = tl::enums::FutureSalts::Salts(tl::types::FutureSalts {
+            req_msg_id: request_msg_id,
+            now,
+            salts: tl::RawVec(salts.clone()),
+        })
+        .to_bytes();
+        let payload = server_payload(&mtproto, 101, future_salts);
+
+        // The answer to the internal request is not propagated, but can be persisted.
+        assert!(mtproto.deserialize(&payload).unwrap().is_empty());
+        assert_eq!(mtproto.salt_request_msg_id, None);
+        let mut expected = salts;
+        expected.reverse();
+        assert_eq!(mtproto.take_new_salts(), Some(expected));
+        assert_eq!(mtproto.take_new_salts(), None);
+    }
+
+    #[test]
+    fn ensure_stored_salts_are_used() {
+        let now = unix_now();
+        let salt = |salt, valid_since, valid_until| tl::types::FutureSalt {
+            valid_since: now + valid_since,
+            valid_until: now + valid_until,
+            salt,
+        };
+        let mut mtproto = Encrypted::build()
+            .first_salt(123)
+            .salts(vec![
+                salt(1, -7200, -3600),
+                salt(2, -1800, 600),
+                salt(3, -300, 2400),
+                salt(4, 1800, 5400),
+            ])
+            .finish(auth_key());
+
+        // The expired salt is dropped, and the newest valid salt is used straight away.
+        let mut buffer = DequeBuffer::with_capacity(0, 0);
+        assert!(mtproto.push(&mut buffer, REQUEST).is_some());
+        mtproto.finalize_plain(&mut buffer);
+        assert_eq!(&buffer[0..8], 3i64.to_le_bytes());
+        assert_eq!(
+            mtproto.salts,
+            vec![salt(4, 1800, 5400), salt(3, -300, 2400)]
+        );
+
+        // If none of the salts are valid anymore, the first salt is used.
+        let mut mtproto = Encrypted::build()
+            .first_salt(123)
+            .salts(vec![salt(1, -7200, -3600)])
+            .finish(auth_key());
+        assert_eq!(mtproto.get_current_salt(), 123);
+        assert_eq!(mtproto.take_new_salts(), None);
+    }
+
+    #[test]
+    fn ensure_state_req_is_answered() {
+        let mut mtproto = Encrypted::build().finish(auth_key());
+        let pong = tl::enums::Pong::Pong(tl::types::Pong {
+            msg_id: 4,
+            ping_id: 0,
+        })
+        .to_bytes();
+        mtproto
+            .deserialize(&server_payload(&mtproto, 101, pong.clone()))
+            .unwrap();
+        mtproto
+            .deserialize(&server_payload(&mtproto, 201, pong))
+            .unwrap();
+
+        let state_req = tl::enums::MsgsStateReq::Req(tl::types::MsgsStateReq {
+            msg_ids: vec![101, 1, 151, 501],
+        })
+        .to_bytes();
+        assert!(deserialize_resends(&mut mtproto, 401, state_req).is_empty());
+
+        // 101 was received, but not acknowledged yet.
+        let expected = tl::types::MsgsStateInfo {
+            req_msg_id: 401,
+            info: vec![4, 1, 2, 3],
+        };
+        assert_eq!(mtproto.pending_state_info, vec![expected.clone()]);
+
+        let mut buffer = DequeBuffer::with_capacity(0, 0);
+        mtproto.push(&mut buffer, REQUEST);
+        let expected = tl::enums::MsgsStateInfo::Info(expected).to_bytes();
+        assert!(buffer
+            .as_ref()
+            .windows(expected.len())
+            .any(|w| w == expected));
+        assert!(mtproto.pending_state_info.is_empty());
+    }
+
+    #[test]
+    fn ensure_resend_req_is_propagated() {
+        let mut mtproto = Encrypted::build().finish(auth_key());
+        let resend_req = tl::enums::MsgResendReq::Req(tl::types::MsgResendReq {
+            msg_ids: vec![4, 8],
+        })
+        .to_bytes();
+
+        assert_eq!(deserialize_resends(&mut mtproto, 101, resend_req), [4, 8]);
+    }
+
+    #[test]
+    fn ensure_msgs_all_info_resends_unreceived() {
+        let mut mtproto = Encrypted::build().finish(auth_key());
+        let all_info = tl::enums::MsgsAllInfo::Info(tl::types::MsgsAllInfo {
+            msg_ids: vec![4, 8, 12, 16],
+            info: vec![4 | 8, 1, 2, 3 | 16],
+        })
+        .to_bytes();
+
+        assert_eq!(
+            deserialize_resends(&mut mtproto, 101, all_info),
+            [8, 12, 16]
+        );
+    }
+
+    #[test]
+    fn ensure_missed_detailed_info_is_requested() {
+        let mut mtproto = Encrypted::build().finish(auth_key());
+        let pong = tl::enums::Pong::Pong(tl::types::Pong {
+            msg_id: 4,
+            ping_id: 0,
+        })
+        .to_bytes();
+        mtproto
+            .deserialize(&server_payload(&mtproto, 101, pong))
+            .unwrap();
+
+        // The answer was already received, so it only needs to be acknowledged.
+        let detailed = tl::enums::MsgDetailedInfo::Info(tl::types::MsgDetailedInfo {
+            msg_id: 4,
+            answer_msg_id: 101,
+            bytes: 28,
+            status: 0,
+        })
+        .to_bytes();
+        assert!(deserialize_resends(&mut mtproto, 201, detailed).is_empty());
+        assert!(mtproto.pending_resend.is_empty());
+        assert_eq!(mtproto.pending_ack, [101, 201, 101]);
+
+        // The answer was never received, so it's requested again.
+        let detailed =
+            tl::enums::MsgDetailedInfo::MsgNewDetailedInfo(tl::types::MsgNewDetailedInfo {
+                answer_msg_id: 301,
+                bytes: 28,
+                status: 0,
+            })
+            .to_bytes();
+        assert!(deserialize_resends(&mut mtproto, 401, detailed).is_empty());
+        assert_eq!(mtproto.pending_resend, [301]);
+
+        let mut buffer = DequeBuffer::with_capacity(0, 0);
+        mtproto.push(&mut buffer, REQUEST);
+        let expected =
+            tl::enums::MsgResendReq::Req(tl::types::MsgResendReq { msg_ids: vec![301] }).to_bytes();
+        assert!(buffer
+            .as_ref()
+            .windows(expected.len())
+            .any(|w| w == expected));
+    }
+
+    #[test]
+    fn ensure_msg_copy_is_processed_once() {
+        let mut mtproto = Encrypted::build().finish(auth_key());
+        let mut copy = Vec::new();
+        manual_tl::MessageCopy::CONSTRUCTOR_ID.serialize(&mut copy);
+        manual_tl::Message {
+            msg_id: 101,
+            seq_no: 1,
+            body: tl::enums::Pong::Pong(tl::types::Pong {
+                msg_id: 4,
+                ping_id: 0,
+            })
+            .to_bytes(),
+        }
+        .serialize(&mut copy);
+
+        let result = mtproto
+            .deserialize(&server_payload(&mtproto, 201, copy.clone()))
+            .unwrap();
+        assert!(matches!(
+            result.as_slice(),
+            [Deserialization::RpcResult(RpcResult {
+                msg_id: MsgId(4),
+                ..
+            })]
+        ));
+
+        let result = mtproto
+            .deserialize(&server_payload(&mtproto, 301, copy))
+            .unwrap();
+        assert!(result.is_empty());
+        assert!(mtproto.pending_ack.contains(&101));
+    }
+
+    #[test]
+    fn ensure_destroy_session_is_not_propagated() {
+        let mut mtproto = Encrypted::build().finish(auth_key());
+        let destroyed =
+            tl::enums::DestroySessionRes::DestroySessionOk(tl::types::DestroySessionOk {
+                session_id: 1,
+            })
+            .to_bytes();
+
+        assert!(deserialize_resends(&mut mtproto, 101, destroyed).is_empty());
+    }
 }
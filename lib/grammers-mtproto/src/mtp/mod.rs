@@ -45,6 +45,17 @@ pub struct BadMessage {
     pub code: i32,
 }
 
+/// The server created a new session, so any updates that occurred before `first_msg_id` may
+/// have been lost.
+pub struct NewSession {
+    pub first_msg_id: MsgId,
+}
+
+/// The server asked for the message with the given `msg_id` to be sent again.
+pub struct ResendRequest {
+    pub msg_id: MsgId,
+}
+
 pub struct DeserializationFailure {
     pub msg_id: MsgId,
     pub error: DeserializeError,
@@ -56,6 +67,8 @@ pub enum Deserialization {
     RpcResult(RpcResult),
     RpcError(RpcResultError),
     BadMessage(BadMessage),
+    NewSession(NewSession),
+    Resend(ResendRequest),
     Failure(DeserializationFailure),
 }
 
@@ -79,11 +92,17 @@ impl BadMessage {
     }
 
     pub fn retryable(&self) -> bool {
-        [16, 17, 48].contains(&self.code)
+        self.resets_session() || self.code == 48
     }
 
     pub fn fatal(&self) -> bool {
-        !self.retryable() && ![32, 33].contains(&self.code)
+        !self.retryable()
+    }
+
+    /// Whether the error caused a fresh session to be started, in which case every message
+    /// sent in the previous session must be sent again, not just the one this error is about.
+    pub fn resets_session(&self) -> bool {
+        [16, 17, 32, 33].contains(&self.code)
     }
 }
 
@@ -117,6 +136,11 @@ pub enum DeserializeError {
 
     /// Attempting to decrypt the message failed in some way.
     DecryptionError(crypto::Error),
+
+    /// The server refused to bind the temporary authorization key to the permanent one, so the
+    /// temporary key cannot be used. The error message is contained within this variant, if
+    /// the server sent one.
+    BindFailed { error: Option<String> },
 }
 
 impl std::error::Error for DeserializeError {}
@@ -142,6 +166,12 @@ impl fmt::Display for DeserializeError {
             Self::DecompressionFailed => write!(f, "failed to decompress server's data"),
             Self::UnexpectedConstructor { id } => write!(f, "unexpected constructor: {id:08x}"),
             Self::DecryptionError(ref error) => write!(f, "failed to decrypt message: {error}"),
+            Self::BindFailed {
+                error: Some(ref error),
+            } => {
+                write!(f, "failed to bind temporary auth key: {error}")
+            }
+            Self::BindFailed { error: None } => write!(f, "failed to bind temporary auth key"),
         }
     }
 }
@@ -201,6 +231,16 @@ pub trait Mtp {
     /// This will either belong to the container (if used) or the last serialized message.
     fn finalize(&mut self, buffer: &mut DequeBuffer<u8>) -> Option<MsgId>;
 
+    /// Serializes an `http_wait` message to the input buffer, which lets the server hold on to
+    /// the response for up to `max_wait` milliseconds until it has messages to send. It should
+    /// only be used with transports where the server cannot send messages otherwise, like HTTP.
+    ///
+    /// Returns `false` if the protocol does not support long polling, in which case nothing is
+    /// serialized.
+    fn push_http_wait(&mut self, _buffer: &mut DequeBuffer<u8>, _max_wait: i32) -> bool {
+        false
+    }
+
     /// Deserializes a single incoming message payload into zero or more responses.
     fn deserialize(&mut self, payload: &[u8]) -> Result<Vec<Deserialization>, DeserializeError>;
 
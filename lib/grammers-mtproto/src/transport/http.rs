@@ -0,0 +1,202 @@
+// Copyright 2020 - developers of the `grammers` project.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+use super::{Error, Transport, UnpackedOffset};
+use grammers_crypto::DequeBuffer;
+
+/// Marks the end of the headers of an HTTP message.
+const HEADER_END: &[u8] = b"\r\n\r\n";
+
+/// A transport which sends every packet as the body of an HTTP `POST` request to `/api`,
+/// and receives them as the body of the responses. This is an implementation of the
+/// [HTTP transport].
+///
+/// * Overhead: large.
+/// * Minimum envelope length: over 100 bytes.
+/// * Maximum envelope length: over 100 bytes.
+///
+/// The server can only send packets in response to those it receives, so a request must be
+/// kept pending at all times to receive updates (see [`Transport::needs_polling`]). Telegram
+/// holds on to the response of such requests until it has something to send, up to a
+/// timeout, which can be configured with an `http_wait` message.
+///
+/// [HTTP transport]: https://core.telegram.org/mtproto#http-transport
+pub struct Http {
+    host: String,
+}
+
+impl Http {
+    /// Creates a new HTTP transport, sending the given value in the `Host` header.
+    ///
+    /// This is usually the address being connected to, such as `149.154.167.51:80`.
+    pub fn new(host: &str) -> Self {
+        Self {
+            host: host.to_string(),
+        }
+    }
+}
+
+/// Find the value of the given header in the raw headers of an HTTP message.
+fn find_header<'a>(headers: &'a str, name: &str) -> Option<&'a str> {
+    headers.split("\r\n").skip(1).find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        key.trim()
+            .eq_ignore_ascii_case(name)
+            .then_some(value.trim())
+    })
+}
+
+impl Transport for Http {
+    fn pack(&mut self, buffer: &mut DequeBuffer<u8>) {
+        let len = buffer.len();
+        assert_eq!(len % 4, 0);
+
+        let header = format!(
+            "POST /api HTTP/1.1\r\n\
+             Host: {}\r\n\
+             Connection: keep-alive\r\n\
+             Content-Type: application/octet-stream\r\n\
+             Content-Length: {}\r\n\r\n",
+            self.host, len
+        );
+        buffer.extend_front(header.as_bytes());
+    }
+
+    fn unpack(&mut self, buffer: &mut [u8]) -> Result<UnpackedOffset, Error> {
+        let header_len = match buffer
+            .windows(HEADER_END.len())
+            .position(|window| window == HEADER_END)
+        {
+            Some(pos) => pos + HEADER_END.len(),
+            None => return Err(Error::MissingBytes),
+        };
+
+        let headers = std::str::from_utf8(&buffer[..header_len]).map_err(|_| Error::BadHttp)?;
+        let status = headers
+            .split(' ')
+            .nth(1)
+            .filter(|_| headers.starts_with("HTTP/1."))
+            .and_then(|status| status.parse::<u32>().ok())
+            .ok_or(Error::BadHttp)?;
+        if status != 200 {
+            return Err(Error::BadStatus { status });
+        }
+
+        let len = find_header(headers, "Content-Length")
+            .and_then(|len| len.parse::<usize>().ok())
+            .ok_or(Error::BadHttp)?;
+        if buffer.len() < header_len + len {
+            return Err(Error::MissingBytes);
+        }
+
+        // Transport errors are sent with a successful status, as the negative code alone.
+        if len == 4 {
+            let data = i32::from_le_bytes(buffer[header_len..header_len + 4].try_into().unwrap());
+            return Err(Error::BadStatus {
+                status: (-data) as u32,
+            });
+        }
+
+        Ok(UnpackedOffset {
+            data_start: header_len,
+            data_end: header_len + len,
+            next_offset: header_len + len,
+        })
+    }
+
+    fn reset(&mut self) {
+        // Every request is independent, so there's no state to reset.
+    }
+
+    fn needs_polling(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(body: &[u8]) -> Vec<u8> {
+        let mut buffer = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/octet-stream\r\ncontent-length: {}\r\n\r\n",
+            body.len()
+        )
+        .into_bytes();
+        buffer.extend(body);
+        buffer
+    }
+
+    #[test]
+    fn pack_normal() {
+        let mut buffer = DequeBuffer::with_capacity(8, 0);
+        buffer.extend([1; 8]);
+        let mut transport = Http::new("127.0.0.1:80");
+        transport.pack(&mut buffer);
+
+        let text = String::from_utf8_lossy(&buffer[..]).into_owned();
+        assert!(text.starts_with("POST /api HTTP/1.1\r\n"));
+        assert!(text.contains("\r\nHost: 127.0.0.1:80\r\n"));
+        assert!(text.contains("\r\nContent-Length: 8\r\n\r\n"));
+        assert_eq!(&buffer[buffer.len() - 8..], &[1; 8]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn pack_non_padded() {
+        let mut buffer = DequeBuffer::with_capacity(7, 0);
+        buffer.extend([1; 7]);
+        Http::new("127.0.0.1:80").pack(&mut buffer);
+    }
+
+    #[test]
+    fn unpack_normal() {
+        let mut buffer = response(&[2; 24]);
+        buffer.extend(b"HTTP/1.1"); // start of the next response
+        let offset = Http::new("").unpack(&mut buffer).unwrap();
+        assert_eq!(&buffer[offset.data_start..offset.data_end], &[2; 24]);
+        assert_eq!(offset.next_offset, buffer.len() - 8);
+    }
+
+    #[test]
+    fn unpack_partial() {
+        let buffer = response(&[2; 24]);
+        let mut transport = Http::new("");
+        for end in [10, buffer.len() - 25, buffer.len() - 1] {
+            assert_eq!(
+                transport.unpack(&mut buffer[..end].to_vec()),
+                Err(Error::MissingBytes)
+            );
+        }
+    }
+
+    #[test]
+    fn unpack_bad_status() {
+        let mut transport = Http::new("");
+        let mut buffer = b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n".to_vec();
+        assert_eq!(
+            transport.unpack(&mut buffer),
+            Err(Error::BadStatus { status: 404 })
+        );
+
+        let mut buffer = response(&(-429_i32).to_le_bytes());
+        assert_eq!(
+            transport.unpack(&mut buffer),
+            Err(Error::BadStatus { status: 429 })
+        );
+    }
+
+    #[test]
+    fn unpack_bad_http() {
+        let mut transport = Http::new("");
+        let mut buffer = b"HTTP/1.1 200 OK\r\n\r\n".to_vec();
+        assert_eq!(transport.unpack(&mut buffer), Err(Error::BadHttp));
+
+        let mut buffer = b"SSH-2.0\r\n\r\n".to_vec();
+        assert_eq!(transport.unpack(&mut buffer), Err(Error::BadHttp));
+    }
+}
@@ -13,12 +13,18 @@
 //! [MTProto transports]: https://core.telegram.org/mtproto#mtproto-transport
 mod abridged;
 mod full;
+mod http;
 mod intermediate;
+mod obfuscated;
+mod padded_intermediate;
 
 pub use abridged::Abridged;
 pub use full::Full;
 use grammers_crypto::DequeBuffer;
+pub use http::Http;
 pub use intermediate::Intermediate;
+pub use obfuscated::Obfuscated;
+pub use padded_intermediate::PaddedIntermediate;
 use std::fmt;
 
 /// The error type reported by the different transports when something is wrong.
@@ -53,6 +59,9 @@ pub enum Error {
     /// [transport-level error]: https://core.telegram.org/mtproto/mtproto-transports#transport-errors
     /// [HTTP status code]: https://developer.mozilla.org/en-US/docs/Web/HTTP/Status
     BadStatus { status: u32 },
+
+    /// The HTTP response could not be parsed, or it did not include the length of its body.
+    BadHttp,
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -79,6 +88,7 @@ impl fmt::Display for Error {
             Error::BadStatus { status } => {
                 write!(f, "bad status (negative length -{status})")
             }
+            Error::BadHttp => write!(f, "bad http response"),
         }
     }
 }
@@ -91,8 +101,45 @@ pub trait Transport {
     fn pack(&mut self, buffer: &mut DequeBuffer<u8>);
 
     /// Unpacks the input buffer in-place.
-    fn unpack(&mut self, buffer: &[u8]) -> Result<UnpackedOffset, Error>;
+    ///
+    /// The buffer must start at the first byte not consumed by a previous successful call, and
+    /// may end with bytes that were already given in calls which returned `MissingBytes`.
+    fn unpack(&mut self, buffer: &mut [u8]) -> Result<UnpackedOffset, Error>;
 
     /// Reset the state, as if a new instance was just created.
     fn reset(&mut self);
+
+    /// Whether the server can only send packets in response to those it receives, as is the
+    /// case with HTTP. If so, a request should be kept pending at all times for the server to
+    /// be able to send updates.
+    fn needs_polling(&self) -> bool {
+        false
+    }
+}
+
+impl<T: Transport + ?Sized> Transport for Box<T> {
+    fn pack(&mut self, buffer: &mut DequeBuffer<u8>) {
+        (**self).pack(buffer)
+    }
+
+    fn unpack(&mut self, buffer: &mut [u8]) -> Result<UnpackedOffset, Error> {
+        (**self).unpack(buffer)
+    }
+
+    fn reset(&mut self) {
+        (**self).reset()
+    }
+
+    fn needs_polling(&self) -> bool {
+        (**self).needs_polling()
+    }
+}
+
+/// The trait used by the transports that can be wrapped in an [`Obfuscated`] transport.
+pub trait Tagged {
+    /// Returns the tag identifying this transport inside the obfuscated header.
+    ///
+    /// The obfuscated header replaces the one the transport would normally send, so the
+    /// transport must not send its own after this is called, until it is reset.
+    fn init_tag(&mut self) -> [u8; 4];
 }
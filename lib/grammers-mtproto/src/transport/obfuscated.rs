@@ -0,0 +1,284 @@
+// Copyright 2020 - developers of the `grammers` project.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+use super::{Error, Tagged, Transport, UnpackedOffset};
+use grammers_crypto::aes::AesCtr;
+use grammers_crypto::{sha256, DequeBuffer};
+
+/// Length of the random header sent before any other data.
+const HEADER_LEN: usize = 64;
+
+/// Values the first four bytes of the header cannot take, as they would be confused with
+/// other protocols or with the plain transports.
+const FORBIDDEN_STARTS: [[u8; 4]; 7] = [
+    *b"HEAD",
+    *b"POST",
+    *b"GET ",
+    *b"OPTI",
+    [0xdd, 0xdd, 0xdd, 0xdd],
+    [0xee, 0xee, 0xee, 0xee],
+    [0x16, 0x03, 0x01, 0x02],
+];
+
+/// A wrapper over other transports that makes the traffic look random, so that it's harder
+/// to identify. This is an implementation of the [transport obfuscation].
+///
+/// * Overhead: that of the inner transport.
+/// * Minimum envelope length: that of the inner transport.
+/// * Maximum envelope length: that of the inner transport.
+///
+/// Upon connecting, a random 64-byte header is sent, which contains the tag of the inner
+/// transport in encrypted form. The keys used to encrypt all of the data sent and received
+/// afterwards using AES-256-CTR are derived from this header.
+///
+/// Only the transports implementing [`Tagged`] can be obfuscated, which `Full` does not.
+///
+/// [transport obfuscation]: https://core.telegram.org/mtproto/mtproto-transports#transport-obfuscation
+pub struct Obfuscated<T: Transport + Tagged> {
+    inner: T,
+    secret: Option<[u8; 16]>,
+    dc_id: i16,
+    header: Option<[u8; HEADER_LEN]>,
+    encryptor: AesCtr,
+    decryptor: AesCtr,
+    // How many bytes at the start of the next buffer to unpack were already decrypted.
+    decrypted: usize,
+}
+
+impl<T: Transport + Tagged> Obfuscated<T> {
+    pub fn new(inner: T) -> Self {
+        Self::build(inner, None, 0)
+    }
+
+    /// Like [`Obfuscated::new`], but for connecting through an [MTProxy].
+    ///
+    /// The keys are derived from the secret of the proxy as well, and the header tells the
+    /// proxy which datacenter to forward the connection to.
+    ///
+    /// [MTProxy]: https://core.telegram.org/mtproto/mtproto-transports#transport-obfuscation
+    pub fn with_secret(inner: T, secret: [u8; 16], dc_id: i16) -> Self {
+        Self::build(inner, Some(secret), dc_id)
+    }
+
+    fn build(mut inner: T, secret: Option<[u8; 16]>, dc_id: i16) -> Self {
+        let (header, encryptor, decryptor) = generate_header(&mut inner, secret.as_ref(), dc_id);
+        Self {
+            inner,
+            secret,
+            dc_id,
+            header: Some(header),
+            encryptor,
+            decryptor,
+            decrypted: 0,
+        }
+    }
+}
+
+/// Create the cipher for one direction from the key and initialization vector in the header.
+fn cipher_from(key_iv: &[u8], secret: Option<&[u8; 16]>) -> AesCtr {
+    let key: [u8; 32] = match secret {
+        Some(secret) => sha256!(&key_iv[..32], secret),
+        None => key_iv[..32].try_into().unwrap(),
+    };
+    AesCtr::new(&key, key_iv[32..48].try_into().unwrap())
+}
+
+/// Generate a new random header for the given transport, along with the ciphers derived
+/// from it. The returned header has its tail encrypted, ready to be sent.
+fn generate_header<T: Tagged>(
+    inner: &mut T,
+    secret: Option<&[u8; 16]>,
+    dc_id: i16,
+) -> ([u8; HEADER_LEN], AesCtr, AesCtr) {
+    let mut header = [0; HEADER_LEN];
+    loop {
+        getrandom::getrandom(&mut header).expect("failed to generate a secure header");
+        if header[0] != 0xef
+            && !FORBIDDEN_STARTS
+                .iter()
+                .any(|start| header[..4] == start[..])
+            && header[4..8] != [0; 4]
+        {
+            break;
+        }
+    }
+    header[56..60].copy_from_slice(&inner.init_tag());
+    header[60..62].copy_from_slice(&dc_id.to_le_bytes());
+
+    let mut reversed = header;
+    reversed[8..56].reverse();
+
+    let mut encryptor = cipher_from(&header[8..56], secret);
+    let decryptor = cipher_from(&reversed[8..56], secret);
+
+    // The whole header goes through the encryptor, but only its tail is sent encrypted.
+    let mut encrypted = header;
+    encryptor.apply(&mut encrypted);
+    header[56..].copy_from_slice(&encrypted[56..]);
+
+    (header, encryptor, decryptor)
+}
+
+impl<T: Transport + Tagged> Transport for Obfuscated<T> {
+    fn pack(&mut self, buffer: &mut DequeBuffer<u8>) {
+        self.inner.pack(buffer);
+        self.encryptor.apply(buffer.as_mut());
+
+        if let Some(header) = self.header.take() {
+            buffer.extend_front(&header);
+        }
+    }
+
+    fn unpack(&mut self, buffer: &mut [u8]) -> Result<UnpackedOffset, Error> {
+        // Bytes can only be decrypted once, so keep track of those that already were.
+        self.decryptor.apply(&mut buffer[self.decrypted..]);
+        self.decrypted = buffer.len();
+
+        let offset = self.inner.unpack(buffer)?;
+        self.decrypted -= offset.next_offset;
+        Ok(offset)
+    }
+
+    fn reset(&mut self) {
+        log::info!("resetting sending of header in obfuscated transport");
+        self.inner.reset();
+        let (header, encryptor, decryptor) =
+            generate_header(&mut self.inner, self.secret.as_ref(), self.dc_id);
+        self.header = Some(header);
+        self.encryptor = encryptor;
+        self.decryptor = decryptor;
+        self.decrypted = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::{Intermediate, PaddedIntermediate};
+
+    /// Returns the ciphers the server would use, from the header sent by the client, along
+    /// with the decrypted header.
+    fn server_ciphers(
+        header: &[u8],
+        secret: Option<&[u8; 16]>,
+    ) -> (AesCtr, AesCtr, [u8; HEADER_LEN]) {
+        let mut reversed = header.to_vec();
+        reversed[8..56].reverse();
+
+        let mut decryptor = cipher_from(&header[8..56], secret);
+        let encryptor = cipher_from(&reversed[8..56], secret);
+
+        let mut decrypted: [u8; HEADER_LEN] = header.try_into().unwrap();
+        decryptor.apply(&mut decrypted);
+        (encryptor, decryptor, decrypted)
+    }
+
+    fn setup_pack(n: usize) -> (Obfuscated<Intermediate>, DequeBuffer<u8>) {
+        let mut buffer = DequeBuffer::with_capacity(n, 0);
+        buffer.extend((0..n).map(|x| (x & 0xff) as u8));
+        (Obfuscated::new(Intermediate::new()), buffer)
+    }
+
+    #[test]
+    fn pack_header() {
+        let (mut transport, mut buffer) = setup_pack(128);
+        let orig = buffer.clone();
+        transport.pack(&mut buffer);
+        assert_eq!(buffer.len(), HEADER_LEN + 4 + 128);
+        assert!(!FORBIDDEN_STARTS.iter().any(|s| buffer[..4] == s[..]));
+
+        // The tag is only visible after decrypting the header.
+        let (_, mut decryptor, header) = server_ciphers(&buffer[..HEADER_LEN], None);
+        assert_eq!(&header[56..60], &[0xee, 0xee, 0xee, 0xee]);
+
+        // No plaintext intermediate header follows, only the length and payload.
+        let mut rest = buffer[HEADER_LEN..].to_vec();
+        decryptor.apply(&mut rest);
+        assert_eq!(&rest[..4], &128u32.to_le_bytes());
+        assert_eq!(&rest[4..], &orig[..]);
+    }
+
+    #[test]
+    fn pack_header_once() {
+        let (mut transport, mut buffer) = setup_pack(128);
+        transport.pack(&mut buffer);
+        let (_, mut buffer) = setup_pack(128);
+        transport.pack(&mut buffer);
+        assert_eq!(buffer.len(), 4 + 128);
+    }
+
+    #[test]
+    fn unpack_in_pieces() {
+        let (mut transport, mut buffer) = setup_pack(0);
+        transport.pack(&mut buffer);
+        let (mut encryptor, _, _) = server_ciphers(&buffer[..HEADER_LEN], None);
+
+        let mut response = Vec::new();
+        for n in [8u8, 12] {
+            response.extend((n as u32).to_le_bytes());
+            response.extend(0..n);
+        }
+        encryptor.apply(&mut response);
+
+        // Unpack the first packet with only a few bytes at a time, as if read from the network.
+        let mut offset = Err(Error::MissingBytes);
+        for end in 1..=response.len() {
+            offset = transport.unpack(&mut response[..end]);
+            if offset.is_ok() {
+                break;
+            }
+            assert_eq!(offset, Err(Error::MissingBytes));
+        }
+        let offset = offset.unwrap();
+        assert_eq!(
+            &response[offset.data_start..offset.data_end],
+            &[0, 1, 2, 3, 4, 5, 6, 7]
+        );
+
+        // The remaining bytes are given all at once.
+        let response = &mut response[offset.next_offset..];
+        let offset = transport.unpack(response).unwrap();
+        assert_eq!(
+            &response[offset.data_start..offset.data_end],
+            &(0..12).collect::<Vec<u8>>()[..]
+        );
+    }
+
+    #[test]
+    fn reset_sends_new_header() {
+        let (mut transport, mut buffer) = setup_pack(0);
+        transport.pack(&mut buffer);
+        let first = buffer[..HEADER_LEN].to_vec();
+
+        transport.reset();
+        let (_, mut buffer) = setup_pack(0);
+        transport.pack(&mut buffer);
+        assert_eq!(buffer.len(), HEADER_LEN + 4);
+        assert_ne!(&buffer[..HEADER_LEN], &first[..]);
+    }
+
+    #[test]
+    fn pack_with_secret() {
+        let secret = [7; 16];
+        let mut transport = Obfuscated::with_secret(PaddedIntermediate::new(), secret, -4);
+        let mut buffer = DequeBuffer::with_capacity(8, 0);
+        buffer.extend([1; 8]);
+        transport.pack(&mut buffer);
+
+        // Without the secret, the header cannot be decrypted.
+        let (_, _, header) = server_ciphers(&buffer[..HEADER_LEN], None);
+        assert_ne!(&header[56..60], &[0xdd, 0xdd, 0xdd, 0xdd]);
+
+        let (_, mut decryptor, header) = server_ciphers(&buffer[..HEADER_LEN], Some(&secret));
+        assert_eq!(&header[56..60], &[0xdd, 0xdd, 0xdd, 0xdd]);
+        assert_eq!(i16::from_le_bytes([header[60], header[61]]), -4);
+
+        let mut rest = buffer[HEADER_LEN..].to_vec();
+        decryptor.apply(&mut rest);
+        assert_eq!(&rest[4..12], &[1; 8]);
+    }
+}
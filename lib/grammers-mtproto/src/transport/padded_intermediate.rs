@@ -0,0 +1,191 @@
+// Copyright 2020 - developers of the `grammers` project.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+use super::{Error, Tagged, Transport, UnpackedOffset};
+use grammers_crypto::DequeBuffer;
+
+/// A variant of the intermediate transport which adds random padding to every packet,
+/// so that their length is harder to use for identifying the protocol. This is an
+/// implementation of the [padded intermediate transport].
+///
+/// * Overhead: small.
+/// * Minimum envelope length: 4 bytes.
+/// * Maximum envelope length: 19 bytes.
+///
+/// It serializes the input payload as follows:
+///
+/// ```text
+/// +----+----...----+----...----+
+/// | len|  payload  |  padding  |
+/// +----+----...----+----...----+
+///  ^^^^ 4 bytes     ^^^^^^^^^^^ 0 to 15 bytes
+/// ```
+///
+/// It is meant to be used along with obfuscation, and is required by MTProxy servers with
+/// secrets starting with `dd`.
+///
+/// [padded intermediate transport]: https://core.telegram.org/mtproto/mtproto-transports#padded-intermediate
+pub struct PaddedIntermediate {
+    init: bool,
+}
+
+#[allow(clippy::new_without_default)]
+impl PaddedIntermediate {
+    pub fn new() -> Self {
+        Self { init: false }
+    }
+}
+
+impl Transport for PaddedIntermediate {
+    fn pack(&mut self, buffer: &mut DequeBuffer<u8>) {
+        let len = buffer.len();
+        assert_eq!(len % 4, 0);
+
+        let mut padding = [0; 16];
+        getrandom::getrandom(&mut padding).expect("failed to generate a secure padding");
+        let padding = &padding[1..1 + (padding[0] % 16) as usize];
+        buffer.extend(padding);
+
+        buffer.extend_front(&((len + padding.len()) as i32).to_le_bytes());
+
+        if !self.init {
+            buffer.extend_front(&0xdd_dd_dd_dd_u32.to_le_bytes());
+            self.init = true;
+        }
+    }
+
+    fn unpack(&mut self, buffer: &mut [u8]) -> Result<UnpackedOffset, Error> {
+        if buffer.len() < 4 {
+            return Err(Error::MissingBytes);
+        }
+
+        let len = i32::from_le_bytes(buffer[0..4].try_into().unwrap());
+        if (buffer.len() as i32) < 4 + len {
+            return Err(Error::MissingBytes);
+        }
+
+        if len <= 4 {
+            if len >= 4 {
+                let data = i32::from_le_bytes(buffer[4..8].try_into().unwrap());
+                return Err(Error::BadStatus {
+                    status: (-data) as u32,
+                });
+            }
+            return Err(Error::BadLen { got: len });
+        }
+
+        let len = len as usize;
+
+        // The padding is not part of the payload. Plain messages tolerate trailing bytes,
+        // but encrypted ones must have a header followed by a multiple of 16 bytes.
+        let data_len = if len >= 24 && buffer[4..12] != [0; 8] {
+            len - (len - 24) % 16
+        } else {
+            len
+        };
+
+        Ok(UnpackedOffset {
+            data_start: 4,
+            data_end: 4 + data_len,
+            next_offset: 4 + len,
+        })
+    }
+
+    fn reset(&mut self) {
+        log::info!("resetting sending of header in padded intermediate transport");
+        self.init = false;
+    }
+}
+
+impl Tagged for PaddedIntermediate {
+    fn init_tag(&mut self) -> [u8; 4] {
+        self.init = true;
+        0xdd_dd_dd_dd_u32.to_le_bytes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns a new padded intermediate transport, and `n` bytes of input data for it.
+    fn setup_pack(n: usize) -> (PaddedIntermediate, DequeBuffer<u8>) {
+        let mut buffer = DequeBuffer::with_capacity(n, 0);
+        buffer.extend((0..n).map(|x| (x & 0xff) as u8));
+        (PaddedIntermediate::new(), buffer)
+    }
+
+    #[test]
+    fn pack_normal() {
+        let (mut transport, mut buffer) = setup_pack(128);
+        let orig = buffer.clone();
+        transport.pack(&mut buffer);
+        let len = i32::from_le_bytes(buffer[4..8].try_into().unwrap()) as usize;
+        assert_eq!(&buffer[..4], &[0xdd, 0xdd, 0xdd, 0xdd]);
+        assert!((128..128 + 16).contains(&len));
+        assert_eq!(buffer.len(), 8 + len);
+        assert_eq!(&buffer[8..8 + 128], &orig[..]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn pack_non_padded() {
+        let (mut transport, mut buffer) = setup_pack(7);
+        transport.pack(&mut buffer);
+    }
+
+    #[test]
+    fn unpack_small() {
+        let mut transport = PaddedIntermediate::new();
+        let mut buffer = [1];
+        assert_eq!(transport.unpack(&mut buffer), Err(Error::MissingBytes));
+    }
+
+    #[test]
+    fn unpack_strips_padding() {
+        // An encrypted message (non-zero key ID) of 24 + 32 bytes, with 5 bytes of padding.
+        let mut buffer = Vec::new();
+        buffer.extend(61i32.to_le_bytes());
+        buffer.extend([1; 8]);
+        buffer.extend([2; 48]);
+        buffer.extend([3; 5]);
+        buffer.extend([4; 4]); // start of the next packet
+
+        let mut transport = PaddedIntermediate::new();
+        let offset = transport.unpack(&mut buffer).unwrap();
+        assert_eq!(offset.data_start, 4);
+        assert_eq!(offset.data_end, 4 + 56);
+        assert_eq!(offset.next_offset, 4 + 61);
+    }
+
+    #[test]
+    fn unpack_keeps_plain() {
+        // A plain message (zero key ID) keeps everything, as it has its own length.
+        let mut buffer = Vec::new();
+        buffer.extend(30i32.to_le_bytes());
+        buffer.extend([0; 8]);
+        buffer.extend([2; 22]);
+
+        let mut transport = PaddedIntermediate::new();
+        let offset = transport.unpack(&mut buffer).unwrap();
+        assert_eq!(offset.data_end, 4 + 30);
+        assert_eq!(offset.next_offset, 4 + 30);
+    }
+
+    #[test]
+    fn unpack_bad_status() {
+        let mut transport = PaddedIntermediate::new();
+        let mut buffer = Vec::new();
+        buffer.extend(4_i32.to_le_bytes());
+        buffer.extend((-404_i32).to_le_bytes());
+
+        assert_eq!(
+            transport.unpack(&mut buffer),
+            Err(Error::BadStatus { status: 404 })
+        );
+    }
+}
@@ -177,6 +177,12 @@ pub enum InvocationError {
 
     /// The error occured while reading the response.
     Read(ReadError),
+
+    /// The response did not arrive before the deadline given to the request.
+    Timeout,
+
+    /// The request was cancelled by the caller before the response arrived.
+    Cancelled,
 }
 
 impl std::error::Error for InvocationError {}
@@ -187,6 +193,8 @@ impl fmt::Display for InvocationError {
             Self::Rpc(err) => write!(f, "request error: {err}"),
             Self::Dropped => write!(f, "request error: dropped (cancelled)"),
             Self::Read(err) => write!(f, "request error: {err}"),
+            Self::Timeout => write!(f, "request error: timed out"),
+            Self::Cancelled => write!(f, "request error: cancelled"),
         }
     }
 }
@@ -0,0 +1,42 @@
+// Copyright 2020 - developers of the `grammers` project.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+use crate::ReadError;
+
+/// Changes in the state of the connection owned by a [`Sender`](crate::Sender).
+///
+/// These are only reported once a channel is set through [`Sender::set_connection_events`],
+/// and can be used to monitor the health of the connection.
+///
+/// [`Sender::set_connection_events`]: crate::Sender::set_connection_events
+#[derive(Clone, Debug)]
+pub enum ConnectionEvent {
+    /// The connection was established again after being lost.
+    Connected,
+
+    /// The connection was lost because of the given error.
+    ///
+    /// This is followed by either [`ConnectionEvent::Reconnecting`], if the reconnection policy
+    /// decided to try again, or nothing at all, in which case the error is returned to the caller.
+    Disconnected(ReadError),
+
+    /// A new connection is about to be attempted. The value is the attempt number, starting at 1.
+    Reconnecting(usize),
+
+    /// Nothing was received for a while, so a ping was sent to check that the connection is
+    /// still alive. If the server doesn't answer, the connection will eventually be dropped.
+    PingTimeout,
+
+    /// The server created a new session, so updates which occurred in the meantime may have been
+    /// lost and should be fetched again.
+    NewSession,
+
+    /// The server no longer knows about the authorization key in use (a transport error 404).
+    ///
+    /// The connection cannot be used until a new authorization key is generated.
+    AuthKeyInvalid,
+}
@@ -9,33 +9,43 @@
 #![deny(unsafe_code)]
 
 mod errors;
+mod events;
+#[cfg(feature = "proxy")]
+mod mtproxy;
 mod reconnection;
+mod websocket;
 
 pub use crate::reconnection::*;
 pub use errors::{AuthorizationError, InvocationError, ReadError, RpcError};
+pub use events::ConnectionEvent;
 use futures_util::future::{pending, select, Either};
 use grammers_crypto::DequeBuffer;
 use grammers_mtproto::mtp::{
-    self, BadMessage, Deserialization, DeserializationFailure, Mtp, RpcResult, RpcResultError,
+    self, BadMessage, Deserialization, DeserializationFailure, Mtp, NewSession, ResendRequest,
+    RpcResult, RpcResultError,
 };
 use grammers_mtproto::transport::{self, Transport};
 use grammers_mtproto::{authentication, MsgId};
 use grammers_tl_types::{self as tl, Deserializable, RemoteCall};
 use log::{debug, error, info, trace, warn};
+#[cfg(feature = "proxy")]
+pub use mtproxy::{FakeTlsStream, MtProxy, ProxySecret};
 use std::io;
-use std::io::Error;
 use std::ops::ControlFlow;
 use std::pin::pin;
+use std::pin::Pin;
 use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
 use std::time::SystemTime;
 use tl::Serializable;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::tcp::{ReadHalf, WriteHalf};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
 use tokio::net::TcpStream;
-use tokio::sync::mpsc;
 use tokio::sync::oneshot;
 use tokio::sync::oneshot::error::TryRecvError;
+use tokio::sync::{broadcast, mpsc};
 use tokio::time::{sleep_until, Duration, Instant};
+pub use websocket::{websocket_host, WebSocketStream};
 
 #[cfg(feature = "proxy")]
 use {
@@ -64,6 +74,10 @@ const LEADING_BUFFER_SPACE: usize = mtp::MAX_TRANSPORT_HEADER_LEN
     + mtp::PLAIN_PACKET_HEADER_LEN
     + mtp::MESSAGE_CONTAINER_HEADER_LEN;
 
+/// How long should the server hold on to the response of a request when polling for updates,
+/// in milliseconds, if it has no messages to send.
+const HTTP_MAX_WAIT: i32 = 25000;
+
 /// Every how often are pings sent?
 const PING_DELAY: Duration = Duration::from_secs(60);
 
@@ -100,20 +114,58 @@ pub enum NetStream {
     ProxySocks5(Socks5Stream<TcpStream>),
     #[cfg(feature = "proxy")]
     ProxyHttp(TcpStream),
+    #[cfg(feature = "proxy")]
+    MtProxyFakeTls(FakeTlsStream),
+    WebSocket(WebSocketStream),
 }
 
 impl NetStream {
-    fn split(&mut self) -> (ReadHalf, WriteHalf) {
+    fn as_pin_mut(&mut self) -> Pin<&mut (dyn AsyncStream + Send)> {
         match self {
-            Self::Tcp(stream) => stream.split(),
+            Self::Tcp(stream) => Pin::new(stream),
+            #[cfg(feature = "proxy")]
+            Self::ProxySocks5(stream) => Pin::new(stream),
             #[cfg(feature = "proxy")]
-            Self::ProxySocks5(stream) => stream.split(),
+            Self::ProxyHttp(stream) => Pin::new(stream),
             #[cfg(feature = "proxy")]
-            Self::ProxyHttp(stream) => stream.split(),
+            Self::MtProxyFakeTls(stream) => Pin::new(stream),
+            Self::WebSocket(stream) => Pin::new(stream),
         }
     }
 }
 
+trait AsyncStream: AsyncRead + AsyncWrite + Unpin {}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncStream for S {}
+
+impl AsyncRead for NetStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        self.get_mut().as_pin_mut().poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for NetStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.get_mut().as_pin_mut().poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().as_pin_mut().poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().as_pin_mut().poll_shutdown(cx)
+    }
+}
+
 // Manages enqueuing requests, matching them to their response, and IO.
 
 pub struct Sender<T: Transport, M: Mtp> {
@@ -123,22 +175,51 @@ pub struct Sender<T: Transport, M: Mtp> {
     addr: std::net::SocketAddr,
     #[cfg(feature = "proxy")]
     proxy_url: Option<String>,
+    #[cfg(feature = "proxy")]
+    mtproxy: Option<MtProxy>,
+    websocket: bool,
+    websocket_host: Option<String>,
     requests: Vec<Request>,
     request_rx: mpsc::UnboundedReceiver<Request>,
     next_ping: Instant,
-    reconnection_policy: &'static dyn ReconnectionPolicy,
+    reconnection_policy: Arc<dyn ReconnectionPolicy>,
+    events: Option<broadcast::Sender<ConnectionEvent>>,
+    temp_auth_key: Option<TempAuthKey<M>>,
 
     // Transport-level buffers and positions
     read_buffer: Vec<u8>,
     read_tail: usize,
     write_buffer: DequeBuffer<u8>,
     write_head: usize,
+    // Packets sent over a transport which needs polling whose response has not arrived yet
+    unanswered_packets: usize,
+}
+
+/// The state needed to use temporary authorization keys bound to a permanent one, which
+/// provide [Perfect Forward Secrecy].
+///
+/// [Perfect Forward Secrecy]: https://core.telegram.org/api/pfs
+struct TempAuthKey<M> {
+    perm_auth_key: [u8; 256],
+    dc_id: i32,
+    expires_in: Duration,
+    rotate_at: Instant,
+    // Invoked again every time a new temporary key is bound, such as `initConnection`.
+    init_request: Vec<u8>,
+    // Creates the `Mtp` which will use a newly-generated temporary key.
+    finish_mtp: fn([u8; 256], i32, authentication::Finished) -> M,
+    // Whether the current key replaced one whose binding failed, and no response proved that
+    // binding it worked yet. Failing again means the permanent key is no longer valid.
+    rebinding: bool,
 }
 
 struct Request {
     body: Vec<u8>,
     state: RequestState,
     result: oneshot::Sender<Result<Vec<u8>, InvocationError>>,
+    // Whether nobody waits for the result (as is the case with pings), so the request must not
+    // be treated as cancelled when the receiving half of `result` is dropped.
+    detached: bool,
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -169,9 +250,16 @@ impl Enqueuer {
     pub fn enqueue<R: RemoteCall>(
         &self,
         request: &R,
+    ) -> oneshot::Receiver<Result<Vec<u8>, InvocationError>> {
+        self.enqueue_body(request.to_bytes())
+    }
+
+    /// Like [`Enqueuer::enqueue`], but for a request that was already serialized.
+    pub fn enqueue_body(
+        &self,
+        body: Vec<u8>,
     ) -> oneshot::Receiver<Result<Vec<u8>, InvocationError>> {
         // TODO we probably want a bound here (to not enqueue more than N at once)
-        let body = request.to_bytes();
         assert!(body.len() >= 4);
         let req_id = u32::from_le_bytes([body[0], body[1], body[2], body[3]]);
         debug!(
@@ -184,6 +272,7 @@ impl Enqueuer {
             body,
             state: RequestState::NotSerialized,
             result: tx,
+            detached: false,
         }) {
             err.0.result.send(Err(InvocationError::Dropped)).unwrap();
         }
@@ -196,7 +285,7 @@ impl<T: Transport, M: Mtp> Sender<T, M> {
         transport: T,
         mtp: M,
         addr: std::net::SocketAddr,
-        reconnection_policy: &'static dyn ReconnectionPolicy,
+        reconnection_policy: Arc<dyn ReconnectionPolicy>,
     ) -> Result<(Self, Enqueuer), io::Error> {
         let stream = connect_stream(&addr).await?;
         let (tx, rx) = mpsc::unbounded_channel();
@@ -208,15 +297,22 @@ impl<T: Transport, M: Mtp> Sender<T, M> {
                 addr,
                 #[cfg(feature = "proxy")]
                 proxy_url: None,
+                #[cfg(feature = "proxy")]
+                mtproxy: None,
+                websocket: false,
+                websocket_host: None,
                 requests: vec![],
                 request_rx: rx,
                 next_ping: Instant::now() + PING_DELAY,
                 reconnection_policy,
+                events: None,
+                temp_auth_key: None,
 
                 read_buffer: vec![0; MAXIMUM_DATA],
                 read_tail: 0,
                 write_buffer: DequeBuffer::with_capacity(MAXIMUM_DATA, LEADING_BUFFER_SPACE),
                 write_head: 0,
+                unanswered_packets: 0,
             },
             Enqueuer(tx),
         ))
@@ -228,7 +324,7 @@ impl<T: Transport, M: Mtp> Sender<T, M> {
         mtp: M,
         addr: SocketAddr,
         proxy_url: &str,
-        reconnection_policy: &'static dyn ReconnectionPolicy,
+        reconnection_policy: Arc<dyn ReconnectionPolicy>,
     ) -> Result<(Self, Enqueuer), io::Error> {
         info!("connecting...");
 
@@ -241,34 +337,132 @@ impl<T: Transport, M: Mtp> Sender<T, M> {
                 mtp,
                 addr,
                 proxy_url: Some(proxy_url.to_string()),
+                mtproxy: None,
+                websocket: false,
+                websocket_host: None,
+                requests: vec![],
+                request_rx: rx,
+                next_ping: Instant::now() + PING_DELAY,
+                reconnection_policy,
+                events: None,
+                temp_auth_key: None,
+
+                read_buffer: vec![0; MAXIMUM_DATA],
+                read_tail: 0,
+                write_buffer: DequeBuffer::with_capacity(MAXIMUM_DATA, LEADING_BUFFER_SPACE),
+                write_head: 0,
+                unanswered_packets: 0,
+            },
+            Enqueuer(tx),
+        ))
+    }
+
+    #[cfg(feature = "proxy")]
+    async fn connect_via_mtproxy(
+        transport: T,
+        mtp: M,
+        addr: SocketAddr,
+        proxy: &MtProxy,
+        reconnection_policy: Arc<dyn ReconnectionPolicy>,
+    ) -> Result<(Self, Enqueuer), io::Error> {
+        let stream = mtproxy::connect_mtproxy_stream(proxy).await?;
+        let (tx, rx) = mpsc::unbounded_channel();
+        Ok((
+            Self {
+                stream,
+                transport,
+                mtp,
+                addr,
+                proxy_url: None,
+                mtproxy: Some(proxy.clone()),
+                websocket: false,
+                websocket_host: None,
+                requests: vec![],
+                request_rx: rx,
+                next_ping: Instant::now() + PING_DELAY,
+                reconnection_policy,
+                events: None,
+                temp_auth_key: None,
+
+                read_buffer: vec![0; MAXIMUM_DATA],
+                read_tail: 0,
+                write_buffer: DequeBuffer::with_capacity(MAXIMUM_DATA, LEADING_BUFFER_SPACE),
+                write_head: 0,
+                unanswered_packets: 0,
+            },
+            Enqueuer(tx),
+        ))
+    }
+
+    async fn connect_via_websocket(
+        transport: T,
+        mtp: M,
+        addr: std::net::SocketAddr,
+        host: Option<&str>,
+        reconnection_policy: Arc<dyn ReconnectionPolicy>,
+    ) -> Result<(Self, Enqueuer), io::Error> {
+        let stream = websocket::connect_websocket_stream(&addr, host).await?;
+        let (tx, rx) = mpsc::unbounded_channel();
+        Ok((
+            Self {
+                stream,
+                transport,
+                mtp,
+                addr,
+                #[cfg(feature = "proxy")]
+                proxy_url: None,
+                #[cfg(feature = "proxy")]
+                mtproxy: None,
+                websocket: true,
+                websocket_host: host.map(str::to_string),
                 requests: vec![],
                 request_rx: rx,
                 next_ping: Instant::now() + PING_DELAY,
                 reconnection_policy,
+                events: None,
+                temp_auth_key: None,
 
                 read_buffer: vec![0; MAXIMUM_DATA],
                 read_tail: 0,
                 write_buffer: DequeBuffer::with_capacity(MAXIMUM_DATA, LEADING_BUFFER_SPACE),
                 write_head: 0,
+                unanswered_packets: 0,
             },
             Enqueuer(tx),
         ))
     }
 
+    /// Report changes in the state of the connection through the given channel.
+    ///
+    /// Events are only sent while there are receivers subscribed to the channel.
+    pub fn set_connection_events(&mut self, events: broadcast::Sender<ConnectionEvent>) {
+        self.events = Some(events);
+    }
+
+    /// Send a connection event, if anyone is listening.
+    fn emit(&self, event: ConnectionEvent) {
+        if let Some(events) = self.events.as_ref() {
+            // Failing to send only means there are no receivers.
+            drop(events.send(event));
+        }
+    }
+
     pub async fn invoke<R: RemoteCall>(&mut self, request: &R) -> Result<Vec<u8>, InvocationError> {
-        let rx = self.enqueue_body(request.to_bytes());
+        let rx = self.enqueue_body(request.to_bytes(), false);
         self.step_until_receive(rx).await
     }
 
     /// Like `invoke` but raw data.
     async fn send(&mut self, body: Vec<u8>) -> Result<Vec<u8>, InvocationError> {
-        let rx = self.enqueue_body(body);
+        let rx = self.enqueue_body(body, false);
         self.step_until_receive(rx).await
     }
 
+    /// Enqueue the request body, which will be `detached` if its result will not be awaited.
     fn enqueue_body(
         &mut self,
         body: Vec<u8>,
+        detached: bool,
     ) -> oneshot::Receiver<Result<Vec<u8>, InvocationError>> {
         assert!(body.len() >= 4);
         let req_id = u32::from_le_bytes([body[0], body[1], body[2], body[3]]);
@@ -282,6 +476,7 @@ impl<T: Transport, M: Mtp> Sender<T, M> {
             body,
             state: RequestState::NotSerialized,
             result: tx,
+            detached,
         });
         rx
     }
@@ -313,6 +508,24 @@ impl<T: Transport, M: Mtp> Sender<T, M> {
             Write(io::Result<usize>),
         }
 
+        if self
+            .temp_auth_key
+            .as_ref()
+            .is_some_and(|temp| Instant::now() >= temp.rotate_at)
+        {
+            match self.rotate_temp_auth_key().await {
+                Ok(()) => self.enqueue_temp_auth_key_init(),
+                Err(e) => {
+                    if let Some(temp) = self.temp_auth_key.as_mut() {
+                        // Don't retry right away, the current key should still be valid for a while.
+                        temp.rotate_at = Instant::now() + PING_DELAY;
+                    }
+                    return self.on_error(e).await;
+                }
+            }
+        }
+
+        self.drop_cancelled_requests();
         self.try_fill_write();
         let write_len = self.write_buffer.len() - self.write_head;
         trace!(
@@ -320,7 +533,7 @@ impl<T: Transport, M: Mtp> Sender<T, M> {
             write_len
         );
 
-        let (mut reader, mut writer) = self.stream.split();
+        let (mut reader, mut writer) = tokio::io::split(&mut self.stream);
         let sel = {
             let sleep = pin!(async { sleep_until(self.next_ping).await });
             let recv_req = pin!(async { self.request_rx.recv().await });
@@ -364,35 +577,54 @@ impl<T: Transport, M: Mtp> Sender<T, M> {
         }
     }
 
-    #[allow(unused_variables)]
-    async fn try_connect(&mut self) -> Result<(), Error> {
+    /// Open a new connection to the same server, through the same proxy if any.
+    ///
+    /// Takes `&mut self` only so that the returned future is `Send` (the transport needs not be
+    /// `Sync`).
+    async fn connect_net_stream(&mut self) -> Result<NetStream, io::Error> {
+        #[cfg(feature = "proxy")]
+        let res = if self.websocket {
+            websocket::connect_websocket_stream(&self.addr, self.websocket_host.as_deref()).await
+        } else if let Some(proxy) = self.mtproxy.as_ref() {
+            mtproxy::connect_mtproxy_stream(proxy).await
+        } else if let Some(url) = self.proxy_url.as_ref() {
+            connect_proxy_stream(&self.addr, url).await
+        } else {
+            connect_stream(&self.addr).await
+        };
+
+        #[cfg(not(feature = "proxy"))]
+        let res = if self.websocket {
+            websocket::connect_websocket_stream(&self.addr, self.websocket_host.as_deref()).await
+        } else {
+            connect_stream(&self.addr).await
+        };
+
+        res
+    }
+
+    async fn try_connect(&mut self) -> Result<(), ReadError> {
         let mut attempts = 0;
         loop {
-            #[cfg(feature = "proxy")]
-            let res = if self.proxy_url.is_some() {
-                connect_proxy_stream(&self.addr, self.proxy_url.as_ref().unwrap()).await
-            } else {
-                connect_stream(&self.addr).await
-            };
+            self.emit(ConnectionEvent::Reconnecting(attempts + 1));
 
-            #[cfg(not(feature = "proxy"))]
-            let res = connect_stream(&self.addr).await;
-
-            match res {
+            match self.connect_net_stream().await {
                 Ok(result) => {
                     log::info!(
                         "auto-reconnect success after {} failed attempt(s)",
                         attempts
                     );
                     self.stream = result;
+                    self.reconnection_policy.reset();
+                    self.emit(ConnectionEvent::Connected);
                     return Ok(());
                 }
                 Err(e) => {
                     attempts += 1;
                     log::warn!("auto-reconnect failed {} time(s): {}", attempts, e);
-                    tokio::time::sleep(Duration::from_secs(1)).await;
 
-                    match self.reconnection_policy.should_retry(attempts) {
+                    let e = ReadError::from(e);
+                    match self.reconnection_policy.should_retry(attempts, &e) {
                         ControlFlow::Break(_) => {
                             log::error!(
                                 "attempted more than {} times for reconnection and failed",
@@ -409,6 +641,161 @@ impl<T: Transport, M: Mtp> Sender<T, M> {
         }
     }
 
+    /// Generate a new temporary authorization key over a new connection, and switch to it.
+    ///
+    /// Requests which were already sent will be sent again with the new key, as it happens
+    /// when reconnecting.
+    async fn rotate_temp_auth_key(&mut self) -> Result<(), ReadError> {
+        let temp = self
+            .temp_auth_key
+            .as_ref()
+            .expect("temporary auth keys are in use");
+        let (perm_auth_key, dc_id, expires_in, finish_mtp) = (
+            temp.perm_auth_key,
+            temp.dc_id,
+            temp.expires_in,
+            temp.finish_mtp,
+        );
+
+        info!("generating new temporary authorization key...");
+        let mut stream = self.connect_net_stream().await?;
+        self.transport.reset();
+        self.read_tail = 0;
+        self.write_head = 0;
+        self.write_buffer.clear();
+        self.unanswered_packets = 0;
+
+        let mut plain = mtp::Plain::new();
+        let gen_error = |e| ReadError::Io(io::Error::new(io::ErrorKind::InvalidData, e));
+        let (request, data) = authentication::step1().map_err(gen_error)?;
+        let response = self.invoke_plain(&mut stream, &mut plain, request).await?;
+        let (request, data) =
+            authentication::step2_temp(data, &response, dc_id, expires_in.as_secs() as i32)
+                .map_err(gen_error)?;
+        let response = self.invoke_plain(&mut stream, &mut plain, request).await?;
+        let (request, data) = authentication::step3(data, &response).map_err(gen_error)?;
+        let response = self.invoke_plain(&mut stream, &mut plain, request).await?;
+        let key = authentication::create_key(data, &response).map_err(gen_error)?;
+        info!("temporary authorization key generated successfully");
+
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .expect("system time is before epoch")
+            .as_secs() as i32;
+        let expires_at = now + key.time_offset + expires_in.as_secs() as i32;
+
+        self.stream = stream;
+        self.mtp = finish_mtp(perm_auth_key, expires_at, key);
+        self.requests
+            .iter_mut()
+            .for_each(|r| r.state = RequestState::NotSerialized);
+        if let Some(temp) = self.temp_auth_key.as_mut() {
+            // Rotate once most of the lifetime has passed, so the key never expires while in use.
+            temp.rotate_at = Instant::now() + expires_in - expires_in / 4;
+            temp.rebinding = false;
+        }
+        self.emit(ConnectionEvent::Connected);
+        Ok(())
+    }
+
+    /// Enqueue the request which must be invoked after binding a new temporary key.
+    ///
+    /// It goes before any other pending request, because those rely on the connection being
+    /// initialized with the right layer.
+    fn enqueue_temp_auth_key_init(&mut self) {
+        if let Some(body) = self.temp_auth_key.as_ref().map(|t| t.init_request.clone()) {
+            let (tx, _) = oneshot::channel();
+            self.requests.insert(
+                0,
+                Request {
+                    body,
+                    state: RequestState::NotSerialized,
+                    result: tx,
+                    detached: true,
+                },
+            );
+        }
+    }
+
+    /// Send a single plain request over the given stream and wait for its response, which
+    /// is used to generate authorization keys without going through the main connection.
+    async fn invoke_plain(
+        &mut self,
+        stream: &mut NetStream,
+        plain: &mut mtp::Plain,
+        request: Vec<u8>,
+    ) -> Result<Vec<u8>, ReadError> {
+        self.write_buffer.clear();
+        plain.push(&mut self.write_buffer, &request);
+        plain.finalize(&mut self.write_buffer);
+        self.transport.pack(&mut self.write_buffer);
+        stream.write_all(&self.write_buffer[..]).await?;
+        self.write_buffer.clear();
+
+        let mut tail = 0;
+        loop {
+            let n = stream.read(&mut self.read_buffer[tail..]).await?;
+            if n == 0 {
+                return Err(ReadError::Io(io::Error::new(
+                    io::ErrorKind::ConnectionReset,
+                    "read 0 bytes",
+                )));
+            }
+            tail += n;
+
+            match self.transport.unpack(&mut self.read_buffer[..tail]) {
+                Ok(offset) => {
+                    let result =
+                        plain.deserialize(&self.read_buffer[offset.data_start..offset.data_end])?;
+                    return match result.into_iter().next() {
+                        Some(Deserialization::RpcResult(result)) => Ok(result.body),
+                        _ => Err(ReadError::Io(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "plain message did not contain a response",
+                        ))),
+                    };
+                }
+                Err(transport::Error::MissingBytes) => continue,
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+
+    /// Forget about the requests whose result is no longer awaited by anyone.
+    ///
+    /// Requests which were not sent yet are simply removed. If they were already sent, the
+    /// server is asked not to send their result with `rpc_drop_answer`. Requests which are
+    /// only serialized are left alone until they're sent, since they're part of the pending
+    /// write.
+    fn drop_cancelled_requests(&mut self) {
+        for i in (0..self.requests.len()).rev() {
+            let request = &self.requests[i];
+            if request.detached || !request.result.is_closed() {
+                continue;
+            }
+            match request.state {
+                RequestState::NotSerialized => {
+                    debug!("dropping cancelled request before sending it");
+                    self.requests.swap_remove(i);
+                }
+                RequestState::Serialized(_) => {}
+                RequestState::Sent(pair) => {
+                    debug!("dropping answer to cancelled request {:?}", pair.msg_id);
+                    self.requests.swap_remove(i);
+                    drop(
+                        self.enqueue_body(
+                            tl::functions::RpcDropAnswer {
+                                req_msg_id: pair.msg_id.value(),
+                            }
+                            .to_bytes(),
+                            true,
+                        ),
+                    );
+                }
+            }
+        }
+    }
+
     /// Setup the write buffer for the transport, unless a write is already pending.
     fn try_fill_write(&mut self) {
         if !self.write_buffer.is_empty() {
@@ -445,6 +832,16 @@ impl<T: Transport, M: Mtp> Sender<T, M> {
             }
         }
 
+        // Transports like HTTP only let the server send messages in response to a request, so
+        // one must be pending at all times in order to receive updates.
+        if self.write_buffer.is_empty()
+            && self.unanswered_packets == 0
+            && self.transport.needs_polling()
+        {
+            self.mtp
+                .push_http_wait(&mut self.write_buffer, HTTP_MAX_WAIT);
+        }
+
         if let Some(container_msg_id) = self.mtp.finalize(&mut self.write_buffer) {
             for request in self.requests.iter_mut() {
                 match request.state {
@@ -480,7 +877,7 @@ impl<T: Transport, M: Mtp> Sender<T, M> {
         while next_offset != self.read_tail {
             match self
                 .transport
-                .unpack(&self.read_buffer[next_offset..self.read_tail])
+                .unpack(&mut self.read_buffer[next_offset..self.read_tail])
             {
                 Ok(offset) => {
                     debug!("deserializing valid transport packet...");
@@ -490,6 +887,7 @@ impl<T: Transport, M: Mtp> Sender<T, M> {
 
                     self.process_mtp_buffer(result, &mut updates);
                     next_offset += offset.next_offset;
+                    self.unanswered_packets = self.unanswered_packets.saturating_sub(1);
                 }
                 Err(transport::Error::MissingBytes) => break,
                 Err(err) => return Err(err.into()),
@@ -518,6 +916,9 @@ impl<T: Transport, M: Mtp> Sender<T, M> {
 
         self.write_buffer.clear();
         self.write_head = 0;
+        if self.transport.needs_polling() {
+            self.unanswered_packets += 1;
+        }
         for req in self.requests.iter_mut() {
             match req.state {
                 RequestState::NotSerialized | RequestState::Sent(_) => {}
@@ -533,6 +934,7 @@ impl<T: Transport, M: Mtp> Sender<T, M> {
     fn on_ping_timeout(&mut self) {
         let ping_id = generate_random_id();
         debug!("enqueueing keepalive ping {}", ping_id);
+        self.emit(ConnectionEvent::PingTimeout);
         drop(
             self.enqueue_body(
                 tl::functions::PingDelayDisconnect {
@@ -540,6 +942,7 @@ impl<T: Transport, M: Mtp> Sender<T, M> {
                     disconnect_delay: NO_PING_DISCONNECT,
                 }
                 .to_bytes(),
+                true,
             ),
         );
         self.next_ping = Instant::now() + PING_DELAY;
@@ -548,6 +951,46 @@ impl<T: Transport, M: Mtp> Sender<T, M> {
     /// Handle errors that occured while performing I/O.
     async fn on_error(&mut self, error: ReadError) -> Result<Vec<tl::enums::Updates>, ReadError> {
         log::info!("handling error: {error}");
+        let bind_failed = matches!(
+            error,
+            ReadError::Deserialize(mtp::DeserializeError::BindFailed { .. })
+        );
+        let rebind_failed = bind_failed && self.temp_auth_key.as_ref().is_some_and(|t| t.rebinding);
+        let error = if rebind_failed {
+            // A freshly-generated key couldn't be bound either, so it's the permanent key that
+            // the server no longer knows about. Report it as such, so that a new one is made.
+            log::warn!(
+                "binding a new temporary auth key failed too; giving up on the permanent key"
+            );
+            if let Some(temp) = self.temp_auth_key.as_mut() {
+                temp.rebinding = false;
+            }
+            ReadError::Transport(transport::Error::BadStatus { status: 404 })
+        } else {
+            error
+        };
+        let auth_key_invalid = matches!(
+            error,
+            ReadError::Transport(transport::Error::BadStatus { status: 404 })
+        );
+        if (auth_key_invalid || bind_failed) && !rebind_failed && self.temp_auth_key.is_some() {
+            // The server forgets about temporary keys once they expire, but the permanent key
+            // is still fine, so a new temporary key can be used instead.
+            log::info!("temporary auth key is no longer valid; generating a new one");
+            match self.rotate_temp_auth_key().await {
+                Ok(()) => {
+                    if let Some(temp) = self.temp_auth_key.as_mut() {
+                        temp.rebinding = bind_failed;
+                    }
+                    self.enqueue_temp_auth_key_init();
+                    return Ok(Vec::new());
+                }
+                Err(e) => log::warn!("failed to generate a new temporary auth key: {e}"),
+            }
+        } else if auth_key_invalid {
+            self.emit(ConnectionEvent::AuthKeyInvalid);
+        }
+        self.emit(ConnectionEvent::Disconnected(error.clone()));
         self.transport.reset();
         self.mtp.reset();
         log::info!(
@@ -561,14 +1004,11 @@ impl<T: Transport, M: Mtp> Sender<T, M> {
         self.read_buffer.fill(0);
         self.write_head = 0;
         self.write_buffer.clear();
+        self.unanswered_packets = 0;
 
-        let error = match error {
-            ReadError::Io(_)
-                if matches!(
-                    self.reconnection_policy.should_retry(0),
-                    ControlFlow::Continue(_)
-                ) =>
-            {
+        let error = match self.reconnection_policy.should_retry(0, &error) {
+            ControlFlow::Continue(delay) => {
+                tokio::time::sleep(delay).await;
                 match self.try_connect().await {
                     Ok(_) => {
                         // Reconnect success means everything can be retried.
@@ -578,10 +1018,10 @@ impl<T: Transport, M: Mtp> Sender<T, M> {
 
                         return Ok(Vec::new());
                     }
-                    Err(e) => ReadError::from(e),
+                    Err(e) => e,
                 }
             }
-            e => e,
+            ControlFlow::Break(_) => error,
         };
 
         log::warn!(
@@ -597,6 +1037,14 @@ impl<T: Transport, M: Mtp> Sender<T, M> {
         Err(error)
     }
 
+    /// Note that the temporary key in use was bound successfully, because the server answered
+    /// a request sent with it (bindings go first, and failing to bind is an error instead).
+    fn confirm_binding(&mut self) {
+        if let Some(temp) = self.temp_auth_key.as_mut() {
+            temp.rebinding = false;
+        }
+    }
+
     /// Process the result of deserializing an MTP buffer.
     fn process_mtp_buffer(
         &mut self,
@@ -609,6 +1057,10 @@ impl<T: Transport, M: Mtp> Sender<T, M> {
                 Deserialization::RpcResult(result) => self.process_result(result),
                 Deserialization::RpcError(error) => self.process_error(error),
                 Deserialization::BadMessage(bad_msg) => self.process_bad_message(bad_msg),
+                Deserialization::NewSession(new_session) => {
+                    self.process_new_session(updates, new_session)
+                }
+                Deserialization::Resend(resend) => self.process_resend(resend),
                 Deserialization::Failure(failure) => self.process_deserialize_error(failure),
             }
         }
@@ -656,6 +1108,7 @@ impl<T: Transport, M: Mtp> Sender<T, M> {
     }
 
     fn process_result(&mut self, result: RpcResult) {
+        self.confirm_binding();
         if let Some(req) = self.pop_request(result.msg_id) {
             let x = result.body;
             assert!(x.len() >= 4);
@@ -676,6 +1129,7 @@ impl<T: Transport, M: Mtp> Sender<T, M> {
     }
 
     fn process_error(&mut self, error: RpcResultError) {
+        self.confirm_binding();
         if let Some(req) = self.pop_request(error.msg_id) {
             debug!("got rpc error {:?}", error.error);
             let x = req.body.as_slice();
@@ -694,6 +1148,24 @@ impl<T: Transport, M: Mtp> Sender<T, M> {
     }
 
     fn process_bad_message(&mut self, bad_msg: BadMessage) {
+        if bad_msg.resets_session() {
+            // Answers to anything sent in the old session will never arrive, and anything
+            // still in the write buffer belongs to the old session as well.
+            info!(
+                "{}; re-sending all in-flight requests in a new session",
+                bad_msg.description()
+            );
+            for request in self.requests.iter_mut() {
+                match request.state {
+                    RequestState::Serialized(_) | RequestState::Sent(_) => {
+                        request.state = RequestState::NotSerialized;
+                    }
+                    RequestState::NotSerialized => {}
+                }
+            }
+            return;
+        }
+
         for i in (0..self.requests.len()).rev() {
             match self.requests[i].state {
                 RequestState::Serialized(pair)
@@ -741,6 +1213,36 @@ impl<T: Transport, M: Mtp> Sender<T, M> {
         }
     }
 
+    fn process_resend(&mut self, resend: ResendRequest) {
+        for request in self.requests.iter_mut() {
+            match request.state {
+                RequestState::Sent(pair)
+                    if pair.msg_id == resend.msg_id || pair.container_msg_id == resend.msg_id =>
+                {
+                    info!("server asked to re-send request {:?}", pair.msg_id);
+                    request.state = RequestState::NotSerialized;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn process_new_session(
+        &mut self,
+        updates: &mut Vec<tl::enums::Updates>,
+        new_session: NewSession,
+    ) {
+        info!(
+            "server created a new session starting at msg_id {:?}",
+            new_session.first_msg_id
+        );
+        self.emit(ConnectionEvent::NewSession);
+
+        // Updates sent to the previous session may have been lost. `updatesTooLong` is how
+        // Telegram itself tells clients that they must fetch the difference, so reuse it.
+        updates.push(tl::enums::Updates::TooLong);
+    }
+
     fn process_deserialize_error(&mut self, failure: DeserializationFailure) {
         if let Some(req) = self.pop_request(failure.msg_id) {
             debug!("got deserialization failure {:?}", failure.error);
@@ -774,15 +1276,76 @@ impl<T: Transport, M: Mtp> Sender<T, M> {
 }
 
 impl<T: Transport> Sender<T, mtp::Encrypted> {
+    /// The permanent authorization key, even if temporary keys are being used.
     pub fn auth_key(&self) -> [u8; 256] {
-        self.mtp.auth_key()
+        match self.temp_auth_key.as_ref() {
+            Some(temp) => temp.perm_auth_key,
+            None => self.mtp.auth_key(),
+        }
+    }
+
+    /// The future salts of the authorization key, if new ones were received since the last call.
+    ///
+    /// Always `None` while temporary keys are being used, because salts belong to the key they
+    /// were received with, and temporary keys are never reused.
+    pub fn take_new_salts(&mut self) -> Option<Vec<tl::types::FutureSalt>> {
+        if self.temp_auth_key.is_some() {
+            return None;
+        }
+        self.mtp.take_new_salts()
+    }
+
+    /// Use temporary authorization keys bound to the current one, which becomes the permanent
+    /// key, to achieve [Perfect Forward Secrecy].
+    ///
+    /// A new temporary key is generated right away for the datacenter `dc_id`, and rotated
+    /// before `expires_in` elapses (or whenever Telegram forgets about it). Each key is bound to
+    /// the permanent key before any other request is sent with it.
+    ///
+    /// Because the connection is initialized anew with every key, `init_request` (normally
+    /// `initConnection`) is invoked again after rotating the key. It's not sent for the first
+    /// key, so the caller can invoke it and use its result.
+    ///
+    /// [Perfect Forward Secrecy]: https://core.telegram.org/api/pfs
+    pub async fn use_temp_auth_keys<R: RemoteCall>(
+        &mut self,
+        dc_id: i32,
+        expires_in: Duration,
+        init_request: &R,
+    ) -> Result<(), ReadError> {
+        fn finish_mtp(
+            perm_auth_key: [u8; 256],
+            expires_at: i32,
+            key: authentication::Finished,
+        ) -> mtp::Encrypted {
+            mtp::Encrypted::build()
+                .time_offset(key.time_offset)
+                .first_salt(key.first_salt)
+                .bind_to(perm_auth_key, expires_at)
+                .finish(key.auth_key)
+        }
+
+        self.temp_auth_key = Some(TempAuthKey {
+            perm_auth_key: self.auth_key(),
+            dc_id,
+            expires_in,
+            rotate_at: Instant::now(),
+            init_request: init_request.to_bytes(),
+            finish_mtp,
+            rebinding: false,
+        });
+        let result = self.rotate_temp_auth_key().await;
+        if result.is_err() {
+            self.temp_auth_key = None;
+        }
+        result
     }
 }
 
 pub async fn connect<T: Transport>(
     transport: T,
     addr: std::net::SocketAddr,
-    rc_policy: &'static dyn ReconnectionPolicy,
+    rc_policy: Arc<dyn ReconnectionPolicy>,
 ) -> Result<(Sender<T, mtp::Encrypted>, Enqueuer), AuthorizationError> {
     let (sender, enqueuer) = Sender::connect(transport, mtp::Plain::new(), addr, rc_policy).await?;
     generate_auth_key(sender, enqueuer).await
@@ -793,13 +1356,48 @@ pub async fn connect_via_proxy<'a, T: Transport>(
     transport: T,
     addr: std::net::SocketAddr,
     proxy_url: &str,
-    rc_policy: &'static dyn ReconnectionPolicy,
+    rc_policy: Arc<dyn ReconnectionPolicy>,
 ) -> Result<(Sender<T, mtp::Encrypted>, Enqueuer), AuthorizationError> {
     let (sender, enqueuer) =
         Sender::connect_via_proxy(transport, mtp::Plain::new(), addr, proxy_url, rc_policy).await?;
     generate_auth_key(sender, enqueuer).await
 }
 
+/// Like [`connect`], but through an MTProxy server.
+///
+/// The transport must be the one created by [`MtProxy::transport`], so that the proxy knows
+/// which datacenter to forward the connection to.
+#[cfg(feature = "proxy")]
+pub async fn connect_via_mtproxy<T: Transport>(
+    transport: T,
+    addr: std::net::SocketAddr,
+    proxy: &MtProxy,
+    rc_policy: Arc<dyn ReconnectionPolicy>,
+) -> Result<(Sender<T, mtp::Encrypted>, Enqueuer), AuthorizationError> {
+    let (sender, enqueuer) =
+        Sender::connect_via_mtproxy(transport, mtp::Plain::new(), addr, proxy, rc_policy).await?;
+    generate_auth_key(sender, enqueuer).await
+}
+
+/// Like [`connect`], but through the WebSocket endpoint of the server.
+///
+/// Telegram serves this endpoint from a different host than the address of the datacenter,
+/// which can be found with [`websocket_host`]. If `host` is given, the connection is made to
+/// it (on port 80) instead of `addr`. Only plain WebSocket connections are supported.
+///
+/// Telegram only accepts obfuscated transports over WebSocket, such as
+/// [`transport::Obfuscated`] wrapping [`transport::Intermediate`].
+pub async fn connect_via_websocket<T: Transport>(
+    transport: T,
+    addr: std::net::SocketAddr,
+    host: Option<&str>,
+    rc_policy: Arc<dyn ReconnectionPolicy>,
+) -> Result<(Sender<T, mtp::Encrypted>, Enqueuer), AuthorizationError> {
+    let (sender, enqueuer) =
+        Sender::connect_via_websocket(transport, mtp::Plain::new(), addr, host, rc_policy).await?;
+    generate_auth_key(sender, enqueuer).await
+}
+
 async fn connect_stream(addr: &std::net::SocketAddr) -> Result<NetStream, std::io::Error> {
     info!("connecting...");
     Ok(NetStream::Tcp(TcpStream::connect(addr).await?))
@@ -929,10 +1527,17 @@ pub async fn generate_auth_key<T: Transport>(
             read_tail: sender.read_tail,
             write_buffer: sender.write_buffer,
             write_head: sender.write_head,
+            unanswered_packets: sender.unanswered_packets,
             addr: sender.addr,
             #[cfg(feature = "proxy")]
             proxy_url: sender.proxy_url,
+            #[cfg(feature = "proxy")]
+            mtproxy: sender.mtproxy,
+            websocket: sender.websocket,
+            websocket_host: sender.websocket_host,
             reconnection_policy: sender.reconnection_policy,
+            events: sender.events,
+            temp_auth_key: None,
         },
         enqueuer,
     ))
@@ -942,11 +1547,12 @@ pub async fn connect_with_auth<T: Transport>(
     transport: T,
     addr: std::net::SocketAddr,
     auth_key: [u8; 256],
-    rc_policy: &'static dyn ReconnectionPolicy,
+    salts: Vec<tl::types::FutureSalt>,
+    rc_policy: Arc<dyn ReconnectionPolicy>,
 ) -> Result<(Sender<T, mtp::Encrypted>, Enqueuer), io::Error> {
     Sender::connect(
         transport,
-        mtp::Encrypted::build().finish(auth_key),
+        mtp::Encrypted::build().salts(salts).finish(auth_key),
         addr,
         rc_policy,
     )
@@ -958,15 +1564,434 @@ pub async fn connect_via_proxy_with_auth<'a, T: Transport>(
     transport: T,
     addr: std::net::SocketAddr,
     auth_key: [u8; 256],
+    salts: Vec<tl::types::FutureSalt>,
     proxy_url: &str,
-    rc_policy: &'static dyn ReconnectionPolicy,
+    rc_policy: Arc<dyn ReconnectionPolicy>,
 ) -> Result<(Sender<T, mtp::Encrypted>, Enqueuer), io::Error> {
     Sender::connect_via_proxy(
         transport,
-        mtp::Encrypted::build().finish(auth_key),
+        mtp::Encrypted::build().salts(salts).finish(auth_key),
         addr,
         proxy_url,
         rc_policy,
     )
     .await
 }
+
+/// Like [`connect_with_auth`], but through an MTProxy server.
+#[cfg(feature = "proxy")]
+pub async fn connect_via_mtproxy_with_auth<T: Transport>(
+    transport: T,
+    addr: std::net::SocketAddr,
+    auth_key: [u8; 256],
+    salts: Vec<tl::types::FutureSalt>,
+    proxy: &MtProxy,
+    rc_policy: Arc<dyn ReconnectionPolicy>,
+) -> Result<(Sender<T, mtp::Encrypted>, Enqueuer), io::Error> {
+    Sender::connect_via_mtproxy(
+        transport,
+        mtp::Encrypted::build().salts(salts).finish(auth_key),
+        addr,
+        proxy,
+        rc_policy,
+    )
+    .await
+}
+
+/// Like [`connect_with_auth`], but through the WebSocket endpoint of the server.
+pub async fn connect_via_websocket_with_auth<T: Transport>(
+    transport: T,
+    addr: std::net::SocketAddr,
+    host: Option<&str>,
+    auth_key: [u8; 256],
+    salts: Vec<tl::types::FutureSalt>,
+    rc_policy: Arc<dyn ReconnectionPolicy>,
+) -> Result<(Sender<T, mtp::Encrypted>, Enqueuer), io::Error> {
+    Sender::connect_via_websocket(
+        transport,
+        mtp::Encrypted::build().salts(salts).finish(auth_key),
+        addr,
+        host,
+        rc_policy,
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use grammers_tl_types::Identifiable;
+    use tokio::net::TcpListener;
+    use tokio::runtime;
+
+    /// Read a single packet sent using the intermediate transport with plain messages,
+    /// returning the request inside it.
+    async fn read_plain_request(stream: &mut TcpStream) -> Vec<u8> {
+        let len = stream.read_u32_le().await.unwrap() as usize;
+        let mut packet = vec![0; len];
+        stream.read_exact(&mut packet).await.unwrap();
+        packet[20..].to_vec()
+    }
+
+    /// Step the sender until the stand-in server is done, returning what it produced.
+    async fn step_until_done<T>(
+        sender: &mut Sender<transport::Intermediate, mtp::Plain>,
+        server: tokio::task::JoinHandle<T>,
+    ) -> T {
+        while !server.is_finished() {
+            // The sender has nothing left to do once the server is done, so don't wait forever.
+            if let Ok(result) = tokio::time::timeout(Duration::from_millis(10), sender.step()).await
+            {
+                result.unwrap();
+            }
+        }
+        server.await.unwrap()
+    }
+
+    async fn connect_stand_in(
+        listener: &TcpListener,
+    ) -> (Sender<transport::Intermediate, mtp::Plain>, Enqueuer) {
+        Sender::connect(
+            transport::Intermediate::new(),
+            mtp::Plain::new(),
+            listener.local_addr().unwrap(),
+            Arc::new(NoReconnect),
+        )
+        .await
+        .unwrap()
+    }
+
+    #[test]
+    fn cancelled_requests_are_not_sent() {
+        let rt = runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let (mut sender, enqueuer) = connect_stand_in(&listener).await;
+            let (mut stream, _) = listener.accept().await.unwrap();
+
+            let cancelled = enqueuer.enqueue(&tl::functions::Ping { ping_id: 1 });
+            let _kept = enqueuer.enqueue(&tl::functions::Ping { ping_id: 2 });
+            drop(cancelled);
+
+            let server = tokio::spawn(async move {
+                assert_eq!(stream.read_u32_le().await.unwrap(), 0xee_ee_ee_ee);
+                let request = read_plain_request(&mut stream).await;
+                // Keep the stream open so the sender doesn't fail before it's done stepping.
+                (stream, request)
+            });
+            let (_stream, request) = step_until_done(&mut sender, server).await;
+            assert_eq!(request, tl::functions::Ping { ping_id: 2 }.to_bytes());
+        });
+    }
+
+    #[test]
+    fn cancelled_sent_requests_drop_answer() {
+        let rt = runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let (mut sender, enqueuer) = connect_stand_in(&listener).await;
+            let (mut stream, _) = listener.accept().await.unwrap();
+
+            let cancelled = enqueuer.enqueue(&tl::functions::Ping { ping_id: 1 });
+            while !sender
+                .requests
+                .iter()
+                .any(|r| matches!(r.state, RequestState::Sent(_)))
+            {
+                sender.step().await.unwrap();
+            }
+            drop(cancelled);
+
+            let server = tokio::spawn(async move {
+                assert_eq!(stream.read_u32_le().await.unwrap(), 0xee_ee_ee_ee);
+                let first = read_plain_request(&mut stream).await;
+                let second = read_plain_request(&mut stream).await;
+                (stream, first, second)
+            });
+            // Enqueue something else for the sender to wake up and notice the cancellation.
+            let _kept = enqueuer.enqueue(&tl::functions::Ping { ping_id: 2 });
+            let (_stream, first, second) = step_until_done(&mut sender, server).await;
+            assert_eq!(first, tl::functions::Ping { ping_id: 1 }.to_bytes());
+            assert_eq!(
+                &second[..4],
+                tl::functions::RpcDropAnswer::CONSTRUCTOR_ID.to_le_bytes()
+            );
+            assert!(sender
+                .requests
+                .iter()
+                .all(|r| r.detached || r.body == tl::functions::Ping { ping_id: 2 }.to_bytes()));
+        });
+    }
+
+    #[test]
+    fn session_reset_resends_requests() {
+        let rt = runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let (mut sender, enqueuer) = connect_stand_in(&listener).await;
+            let (mut stream, _) = listener.accept().await.unwrap();
+
+            let codes = [16, 17, 32, 33];
+            let server = tokio::spawn(async move {
+                assert_eq!(stream.read_u32_le().await.unwrap(), 0xee_ee_ee_ee);
+                let mut requests = Vec::new();
+                for _ in 0..=codes.len() {
+                    requests.push(read_plain_request(&mut stream).await);
+                }
+                (stream, requests)
+            });
+
+            let _ping = enqueuer.enqueue(&tl::functions::Ping { ping_id: 1 });
+            for code in codes {
+                let msg_id = loop {
+                    if let Some(msg_id) = sender.requests.iter().find_map(|r| match r.state {
+                        RequestState::Sent(pair) => Some(pair.msg_id),
+                        _ => None,
+                    }) {
+                        break msg_id;
+                    }
+                    sender.step().await.unwrap();
+                };
+                sender.process_mtp_buffer(
+                    vec![Deserialization::BadMessage(BadMessage { msg_id, code })],
+                    &mut Vec::new(),
+                );
+                assert!(matches!(
+                    sender.requests[0].state,
+                    RequestState::NotSerialized
+                ));
+            }
+
+            let (_stream, requests) = step_until_done(&mut sender, server).await;
+            for request in requests {
+                assert_eq!(request, tl::functions::Ping { ping_id: 1 }.to_bytes());
+            }
+        });
+    }
+
+    fn stand_in_temp_auth_key(rotate_at: Instant) -> TempAuthKey<mtp::Plain> {
+        TempAuthKey {
+            perm_auth_key: [0; 256],
+            dc_id: 2,
+            expires_in: Duration::from_secs(3600),
+            rotate_at,
+            init_request: tl::functions::Ping { ping_id: 9 }.to_bytes(),
+            finish_mtp: |_, _, _| mtp::Plain::new(),
+            rebinding: false,
+        }
+    }
+
+    #[test]
+    fn temp_auth_key_rotation_generates_new_key() {
+        let rt = runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let (mut sender, _enqueuer) = connect_stand_in(&listener).await;
+            let (_stream, _) = listener.accept().await.unwrap();
+            sender.temp_auth_key = Some(stand_in_temp_auth_key(Instant::now()));
+
+            // The new key is generated over a new connection, starting from the first step.
+            let server = tokio::spawn(async move {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                assert_eq!(stream.read_u32_le().await.unwrap(), 0xee_ee_ee_ee);
+                read_plain_request(&mut stream).await
+            });
+            // Generation fails once the stand-in goes away, which is fine.
+            while !server.is_finished() {
+                drop(tokio::time::timeout(Duration::from_millis(10), sender.step()).await);
+            }
+            let request = server.await.unwrap();
+            assert_eq!(
+                &request[..4],
+                tl::functions::ReqPqMulti::CONSTRUCTOR_ID.to_le_bytes()
+            );
+        });
+    }
+
+    #[test]
+    fn second_binding_failure_invalidates_perm_auth_key() {
+        let rt = runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let (mut sender, _enqueuer) = connect_stand_in(&listener).await;
+            let (_stream, _) = listener.accept().await.unwrap();
+            let (events, mut receiver) = broadcast::channel(4);
+            sender.set_connection_events(events);
+
+            // As if the binding of the previous key had failed, and this key replaced it.
+            let mut temp = stand_in_temp_auth_key(Instant::now() + Duration::from_secs(3600));
+            temp.rebinding = true;
+            sender.temp_auth_key = Some(temp);
+            let pending = sender.enqueue_body(tl::functions::Ping { ping_id: 1 }.to_bytes(), false);
+
+            let result = sender
+                .on_error(ReadError::Deserialize(mtp::DeserializeError::BindFailed {
+                    error: Some("ENCRYPTED_MESSAGE_INVALID".into()),
+                }))
+                .await;
+            assert!(matches!(
+                result,
+                Err(ReadError::Transport(transport::Error::BadStatus {
+                    status: 404
+                }))
+            ));
+            assert!(matches!(
+                pending.await.unwrap(),
+                Err(InvocationError::Read(ReadError::Transport(
+                    transport::Error::BadStatus { status: 404 }
+                )))
+            ));
+            assert!(matches!(
+                receiver.try_recv(),
+                Ok(ConnectionEvent::AuthKeyInvalid)
+            ));
+
+            // No new key was generated, which would need a new connection.
+            assert!(
+                tokio::time::timeout(Duration::from_millis(50), listener.accept())
+                    .await
+                    .is_err()
+            );
+        });
+    }
+
+    #[test]
+    fn temp_auth_key_init_is_sent_first() {
+        let rt = runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let (mut sender, _enqueuer) = connect_stand_in(&listener).await;
+            let (mut stream, _) = listener.accept().await.unwrap();
+            sender.temp_auth_key = Some(stand_in_temp_auth_key(
+                Instant::now() + Duration::from_secs(3600),
+            ));
+
+            // As if the key had just been rotated with a request pending.
+            let _pending =
+                sender.enqueue_body(tl::functions::Ping { ping_id: 1 }.to_bytes(), false);
+            sender.enqueue_temp_auth_key_init();
+
+            let server = tokio::spawn(async move {
+                assert_eq!(stream.read_u32_le().await.unwrap(), 0xee_ee_ee_ee);
+                let first = read_plain_request(&mut stream).await;
+                let second = read_plain_request(&mut stream).await;
+                (stream, first, second)
+            });
+            let (_stream, first, second) = step_until_done(&mut sender, server).await;
+            assert_eq!(first, tl::functions::Ping { ping_id: 9 }.to_bytes());
+            assert_eq!(second, tl::functions::Ping { ping_id: 1 }.to_bytes());
+        });
+    }
+
+    #[test]
+    fn lost_connection_reports_reconnection() {
+        let rt = runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let (mut sender, _enqueuer) = Sender::connect(
+                transport::Intermediate::new(),
+                mtp::Plain::new(),
+                listener.local_addr().unwrap(),
+                Arc::new(FixedReconnect {
+                    attempts: 1,
+                    delay: Duration::ZERO,
+                }),
+            )
+            .await
+            .unwrap();
+            let (events, mut receiver) = broadcast::channel(8);
+            sender.set_connection_events(events);
+
+            // Drop the first connection, and accept the one made to replace it.
+            let server = tokio::spawn(async move {
+                drop(listener.accept().await.unwrap());
+                listener.accept().await.unwrap()
+            });
+            sender.step().await.unwrap();
+            drop(server.await.unwrap());
+
+            assert!(matches!(
+                receiver.try_recv(),
+                Ok(ConnectionEvent::Disconnected(ReadError::Io(_)))
+            ));
+            assert!(matches!(
+                receiver.try_recv(),
+                Ok(ConnectionEvent::Reconnecting(1))
+            ));
+            assert!(matches!(
+                receiver.try_recv(),
+                Ok(ConnectionEvent::Connected)
+            ));
+            assert!(receiver.try_recv().is_err());
+        });
+    }
+
+    #[test]
+    fn ping_timeout_is_reported() {
+        let rt = runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let (mut sender, _enqueuer) = connect_stand_in(&listener).await;
+            let (events, mut receiver) = broadcast::channel(1);
+            sender.set_connection_events(events);
+
+            sender.on_ping_timeout();
+            assert!(matches!(
+                receiver.try_recv(),
+                Ok(ConnectionEvent::PingTimeout)
+            ));
+        });
+    }
+
+    #[test]
+    fn new_session_asks_for_difference() {
+        let rt = runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let (mut sender, _enqueuer) = connect_stand_in(&listener).await;
+            let (events, mut receiver) = broadcast::channel(1);
+            sender.set_connection_events(events);
+
+            let first_msg_id = mtp::Plain::new()
+                .push(&mut DequeBuffer::with_capacity(0, 0), &[])
+                .unwrap();
+            let mut updates = Vec::new();
+            sender.process_mtp_buffer(
+                vec![Deserialization::NewSession(NewSession { first_msg_id })],
+                &mut updates,
+            );
+            assert!(matches!(updates.as_slice(), [tl::enums::Updates::TooLong]));
+            assert!(matches!(
+                receiver.try_recv(),
+                Ok(ConnectionEvent::NewSession)
+            ));
+        });
+    }
+}
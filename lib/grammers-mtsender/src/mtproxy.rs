@@ -0,0 +1,664 @@
+// Copyright 2020 - developers of the `grammers` project.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Support for connecting through [MTProxy] servers.
+//!
+//! [MTProxy]: https://core.telegram.org/proxy
+use base64::Engine;
+use grammers_crypto::{hex, hmac_sha256};
+use grammers_mtproto::transport::{self, Obfuscated, Transport};
+use std::io::{self, ErrorKind};
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::TcpStream;
+
+/// Maximum length of the payload of a TLS record.
+const MAX_RECORD_LEN: usize = 16384;
+
+/// Length of the `ClientHello` record sent during the fake TLS handshake, header excluded.
+const CLIENT_HELLO_LEN: usize = 512;
+
+/// Offset of the random value in both the `ClientHello` and `ServerHello`, which is used to
+/// carry the digest proving knowledge of the secret.
+const DIGEST_POS: usize = 11;
+
+const RECORD_HANDSHAKE: u8 = 0x16;
+const RECORD_CHANGE_CIPHER_SPEC: u8 = 0x14;
+const RECORD_APPLICATION_DATA: u8 = 0x17;
+
+/// The `ChangeCipherSpec` record sent before any data once the fake handshake is done.
+const CHANGE_CIPHER_SPEC: [u8; 6] = [RECORD_CHANGE_CIPHER_SPEC, 0x03, 0x03, 0x00, 0x01, 0x01];
+
+/// The secret of an MTProxy server, which also determines how to connect to it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ProxySecret {
+    /// A plain secret, to be used with the obfuscated intermediate transport.
+    Simple([u8; 16]),
+    /// A secret starting with `dd`, which requires the padded intermediate transport.
+    Padded([u8; 16]),
+    /// A secret starting with `ee`, which requires the traffic to look like TLS to the domain.
+    FakeTls { secret: [u8; 16], domain: String },
+}
+
+/// The connection details of an MTProxy server.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MtProxy {
+    pub host: String,
+    pub port: u16,
+    pub secret: ProxySecret,
+}
+
+fn invalid_input(message: String) -> io::Error {
+    io::Error::new(ErrorKind::InvalidInput, message)
+}
+
+impl ProxySecret {
+    /// Parse a secret in either its hexadecimal or base64 representation.
+    pub fn parse(secret: &str) -> io::Result<Self> {
+        let bytes = hex::opt_from_hex(secret)
+            .or_else(|| {
+                base64::engine::general_purpose::URL_SAFE_NO_PAD
+                    .decode(
+                        secret
+                            .trim_end_matches('=')
+                            .replace('+', "-")
+                            .replace('/', "_"),
+                    )
+                    .ok()
+            })
+            .ok_or_else(|| invalid_input(format!("proxy secret is not valid: {}", secret)))?;
+
+        let key = |bytes: &[u8]| -> io::Result<[u8; 16]> {
+            bytes
+                .get(..16)
+                .and_then(|key| key.try_into().ok())
+                .ok_or_else(|| invalid_input(format!("proxy secret is too short: {}", secret)))
+        };
+
+        match bytes.first() {
+            Some(0xdd) if bytes.len() == 17 => Ok(Self::Padded(key(&bytes[1..])?)),
+            Some(0xee) if bytes.len() > 17 => Ok(Self::FakeTls {
+                secret: key(&bytes[1..])?,
+                domain: String::from_utf8(bytes[17..].to_vec()).map_err(|_| {
+                    invalid_input(format!("proxy secret has an invalid domain: {}", secret))
+                })?,
+            }),
+            _ if bytes.len() == 16 => Ok(Self::Simple(key(&bytes)?)),
+            _ => Err(invalid_input(format!(
+                "proxy secret has an unknown format: {}",
+                secret
+            ))),
+        }
+    }
+}
+
+impl MtProxy {
+    /// Parse a proxy link, either as `tg://proxy?server=…&port=…&secret=…` or as
+    /// `https://t.me/proxy?server=…&port=…&secret=…`.
+    pub fn from_link(link: &str) -> io::Result<Self> {
+        let url = url::Url::parse(link).map_err(|err| invalid_input(err.to_string()))?;
+        let is_proxy_link = match url.scheme() {
+            "tg" => url.host_str() == Some("proxy"),
+            "http" | "https" => {
+                matches!(url.host_str(), Some("t.me" | "telegram.me")) && url.path() == "/proxy"
+            }
+            _ => false,
+        };
+        if !is_proxy_link {
+            return Err(invalid_input(format!("not a proxy link: {}", link)));
+        }
+
+        let param = |name: &str| {
+            url.query_pairs()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.into_owned())
+                .ok_or_else(|| {
+                    invalid_input(format!("proxy link is missing the {}: {}", name, link))
+                })
+        };
+
+        Ok(Self {
+            host: param("server")?,
+            port: param("port")?
+                .parse()
+                .map_err(|_| invalid_input(format!("proxy link has an invalid port: {}", link)))?,
+            secret: ProxySecret::parse(&param("secret")?)?,
+        })
+    }
+
+    /// Create the obfuscated transport to use with this proxy, which will forward the
+    /// connection to the given datacenter.
+    pub fn transport(&self, dc_id: i16) -> Box<dyn Transport + Send> {
+        match &self.secret {
+            ProxySecret::Simple(secret) => Box::new(Obfuscated::with_secret(
+                transport::Intermediate::new(),
+                *secret,
+                dc_id,
+            )),
+            ProxySecret::Padded(secret) | ProxySecret::FakeTls { secret, .. } => Box::new(
+                Obfuscated::with_secret(transport::PaddedIntermediate::new(), *secret, dc_id),
+            ),
+        }
+    }
+}
+
+/// Build the `ClientHello` record for the given domain, with an empty random value.
+fn client_hello(domain: &str) -> io::Result<Vec<u8>> {
+    fn random<const N: usize>() -> [u8; N] {
+        let mut buffer = [0; N];
+        getrandom::getrandom(&mut buffer).expect("failed to generate a secure value");
+        buffer
+    }
+
+    fn with_len(buffer: &mut Vec<u8>, len_size: usize, body: impl FnOnce(&mut Vec<u8>)) {
+        let start = buffer.len();
+        buffer.extend(&[0; 4][..len_size]);
+        body(buffer);
+        let len = (buffer.len() - start - len_size) as u32;
+        buffer[start..start + len_size].copy_from_slice(&len.to_be_bytes()[4 - len_size..]);
+    }
+
+    fn extension(buffer: &mut Vec<u8>, kind: u16, body: impl FnOnce(&mut Vec<u8>)) {
+        buffer.extend(kind.to_be_bytes());
+        with_len(buffer, 2, body);
+    }
+
+    let mut hello = vec![RECORD_HANDSHAKE, 0x03, 0x01];
+    with_len(&mut hello, 2, |hello| {
+        hello.push(0x01); // ClientHello
+        with_len(hello, 3, |hello| {
+            hello.extend([0x03, 0x03]);
+            hello.extend([0; 32]); // random, later replaced by the digest
+            hello.push(32);
+            hello.extend(random::<32>()); // session ID
+            with_len(hello, 2, |hello| {
+                for suite in [
+                    0x1301u16, 0x1302, 0x1303, 0xc02b, 0xc02f, 0xc02c, 0xc030, 0xcca9, 0xcca8,
+                    0xc013, 0xc014, 0x009c, 0x009d, 0x002f, 0x0035,
+                ] {
+                    hello.extend(suite.to_be_bytes());
+                }
+            });
+            hello.extend([0x01, 0x00]); // no compression
+            with_len(hello, 2, |hello| {
+                extension(hello, 0x0000, |hello| {
+                    // server_name
+                    with_len(hello, 2, |hello| {
+                        hello.push(0x00);
+                        with_len(hello, 2, |hello| hello.extend(domain.as_bytes()));
+                    })
+                });
+                extension(hello, 0x000a, |hello| {
+                    // supported_groups: x25519, secp256r1
+                    hello.extend([0x00, 0x04, 0x00, 0x1d, 0x00, 0x17]);
+                });
+                extension(hello, 0x000b, |hello| hello.extend([0x01, 0x00])); // ec_point_formats
+                extension(hello, 0x000d, |hello| {
+                    // signature_algorithms
+                    with_len(hello, 2, |hello| {
+                        for scheme in [0x0403u16, 0x0804, 0x0401, 0x0503, 0x0805, 0x0501] {
+                            hello.extend(scheme.to_be_bytes());
+                        }
+                    })
+                });
+                extension(hello, 0x002b, |hello| hello.extend([0x02, 0x03, 0x04])); // TLS 1.3
+                extension(hello, 0x0033, |hello| {
+                    // key_share
+                    with_len(hello, 2, |hello| {
+                        hello.extend([0x00, 0x1d, 0x00, 0x20]);
+                        hello.extend(random::<32>());
+                    })
+                });
+
+                // Pad the record to the exact length the proxies expect.
+                let padding = (CLIENT_HELLO_LEN + 5).saturating_sub(hello.len() + 4);
+                extension(hello, 0x0015, |hello| {
+                    hello.resize(hello.len() + padding, 0)
+                });
+            });
+        });
+    });
+
+    if hello.len() != CLIENT_HELLO_LEN + 5 {
+        return Err(invalid_input(format!(
+            "proxy domain is too long: {}",
+            domain
+        )));
+    }
+    Ok(hello)
+}
+
+/// Read a whole TLS record of the given type, header included.
+async fn read_record(stream: &mut TcpStream, kind: u8) -> io::Result<Vec<u8>> {
+    let mut record = vec![0; 5];
+    stream.read_exact(&mut record).await?;
+    if record[0] != kind || record[1..3] != [0x03, 0x03] {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "proxy sent an unexpected TLS record",
+        ));
+    }
+    let len = u16::from_be_bytes([record[3], record[4]]) as usize;
+    record.resize(5 + len, 0);
+    stream.read_exact(&mut record[5..]).await?;
+    Ok(record)
+}
+
+/// Perform the fake TLS handshake with an MTProxy server whose secret starts with `ee`.
+pub(crate) async fn connect_fake_tls(
+    mut stream: TcpStream,
+    secret: &ProxySecret,
+) -> io::Result<FakeTlsStream> {
+    let ProxySecret::FakeTls { secret, domain } = secret else {
+        panic!("fake TLS can only be used with ee secrets");
+    };
+
+    let mut hello = client_hello(domain)?;
+    let mut digest = hmac_sha256(secret, &hello);
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system time is before epoch")
+        .as_secs() as u32;
+    digest[28..]
+        .iter_mut()
+        .zip(timestamp.to_le_bytes())
+        .for_each(|(d, t)| *d ^= t);
+    hello[DIGEST_POS..DIGEST_POS + 32].copy_from_slice(&digest);
+    stream.write_all(&hello).await?;
+
+    // ServerHello, ChangeCipherSpec and some application data, signed with the secret.
+    let mut response = read_record(&mut stream, RECORD_HANDSHAKE).await?;
+    response.extend(read_record(&mut stream, RECORD_CHANGE_CIPHER_SPEC).await?);
+    response.extend(read_record(&mut stream, RECORD_APPLICATION_DATA).await?);
+
+    let server_digest = response
+        .get(DIGEST_POS..DIGEST_POS + 32)
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "proxy sent a short TLS record"))?
+        .to_vec();
+    response[DIGEST_POS..DIGEST_POS + 32].fill(0);
+    let mut signed = digest.to_vec();
+    signed.extend(response);
+    if hmac_sha256(secret, &signed)[..] != server_digest[..] {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "proxy failed to prove it knows the secret",
+        ));
+    }
+
+    Ok(FakeTlsStream::new(stream))
+}
+
+/// A stream that wraps all of the data in TLS records, after the handshake is done.
+pub struct FakeTlsStream {
+    stream: TcpStream,
+    // Header of the record being read, how much of it was read, and how much of its payload
+    // is left to read.
+    read_header: [u8; 5],
+    read_header_len: usize,
+    read_remaining: usize,
+    read_buffer: Vec<u8>,
+    // Record being written, how much of it was written, and the length of its payload.
+    write_record: Vec<u8>,
+    write_pos: usize,
+    write_payload_len: usize,
+    sent_change_cipher_spec: bool,
+}
+
+impl FakeTlsStream {
+    fn new(stream: TcpStream) -> Self {
+        Self {
+            stream,
+            read_header: [0; 5],
+            read_header_len: 0,
+            read_remaining: 0,
+            read_buffer: vec![0; MAX_RECORD_LEN],
+            write_record: Vec::new(),
+            write_pos: 0,
+            write_payload_len: 0,
+            sent_change_cipher_spec: false,
+        }
+    }
+
+    /// Write as much of the pending record as possible.
+    fn poll_write_record(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.write_pos < self.write_record.len() {
+            let n = ready!(
+                Pin::new(&mut self.stream).poll_write(cx, &self.write_record[self.write_pos..])
+            )?;
+            if n == 0 {
+                return Poll::Ready(Err(ErrorKind::WriteZero.into()));
+            }
+            self.write_pos += n;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncRead for FakeTlsStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+        loop {
+            if this.read_remaining != 0 {
+                let len = this.read_remaining.min(buf.remaining());
+                let mut payload = ReadBuf::new(&mut this.read_buffer[..len]);
+                ready!(Pin::new(&mut this.stream).poll_read(cx, &mut payload))?;
+                let n = payload.filled().len();
+                if n == 0 {
+                    return Poll::Ready(Err(ErrorKind::UnexpectedEof.into()));
+                }
+                this.read_remaining -= n;
+                if this.read_header[0] == RECORD_APPLICATION_DATA {
+                    buf.put_slice(&this.read_buffer[..n]);
+                    return Poll::Ready(Ok(()));
+                }
+                continue;
+            }
+
+            let mut header = ReadBuf::new(&mut this.read_header[this.read_header_len..]);
+            ready!(Pin::new(&mut this.stream).poll_read(cx, &mut header))?;
+            let n = header.filled().len();
+            if n == 0 {
+                // The connection was closed, which is reported the same way.
+                return Poll::Ready(Ok(()));
+            }
+            this.read_header_len += n;
+            if this.read_header_len != this.read_header.len() {
+                continue;
+            }
+
+            this.read_header_len = 0;
+            if !matches!(
+                this.read_header[0],
+                RECORD_APPLICATION_DATA | RECORD_CHANGE_CIPHER_SPEC
+            ) {
+                return Poll::Ready(Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    "proxy sent an unexpected TLS record",
+                )));
+            }
+            this.read_remaining =
+                u16::from_be_bytes([this.read_header[3], this.read_header[4]]) as usize;
+        }
+    }
+}
+
+impl AsyncWrite for FakeTlsStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        // A record that was not fully written is for the same data being written again.
+        if this.write_record.is_empty() {
+            if !this.sent_change_cipher_spec {
+                this.write_record.extend(CHANGE_CIPHER_SPEC);
+                this.sent_change_cipher_spec = true;
+            }
+            let payload = &buf[..buf.len().min(MAX_RECORD_LEN)];
+            this.write_record
+                .extend([RECORD_APPLICATION_DATA, 0x03, 0x03]);
+            this.write_record
+                .extend((payload.len() as u16).to_be_bytes());
+            this.write_record.extend(payload);
+            this.write_pos = 0;
+            this.write_payload_len = payload.len();
+        }
+
+        ready!(this.poll_write_record(cx))?;
+        this.write_record.clear();
+        Poll::Ready(Ok(this.write_payload_len))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_record(cx))?;
+        Pin::new(&mut this.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_record(cx))?;
+        Pin::new(&mut this.stream).poll_shutdown(cx)
+    }
+}
+
+/// Connect to the MTProxy server, performing the fake TLS handshake if needed.
+pub(crate) async fn connect_mtproxy_stream(proxy: &MtProxy) -> io::Result<crate::NetStream> {
+    log::info!("connecting to mtproxy {}:{}...", proxy.host, proxy.port);
+    let stream = TcpStream::connect((proxy.host.as_str(), proxy.port)).await?;
+    Ok(match proxy.secret {
+        ProxySecret::FakeTls { .. } => {
+            crate::NetStream::MtProxyFakeTls(connect_fake_tls(stream, &proxy.secret).await?)
+        }
+        ProxySecret::Simple(_) | ProxySecret::Padded(_) => crate::NetStream::Tcp(stream),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{connect_via_mtproxy, AuthorizationError, InvocationError, NoReconnect, ReadError};
+    use grammers_crypto::aes::AesCtr;
+    use grammers_crypto::sha256;
+    use std::sync::Arc;
+    use tokio::net::TcpListener;
+    use tokio::runtime;
+
+    const SECRET: [u8; 16] = [
+        0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc, 0xde, 0xf0, 0x0f, 0xed, 0xcb, 0xa9, 0x87, 0x65, 0x43,
+        0x21,
+    ];
+
+    fn cipher_from(key_iv: &[u8]) -> AesCtr {
+        let key = sha256!(&key_iv[..32], &SECRET);
+        AesCtr::new(&key, key_iv[32..48].try_into().unwrap())
+    }
+
+    /// Perform the server side of the fake TLS handshake, checking the client's digest.
+    async fn accept_fake_tls(mut stream: TcpStream, domain: &str) -> FakeTlsStream {
+        let mut hello = vec![0; 5];
+        stream.read_exact(&mut hello).await.unwrap();
+        assert_eq!(&hello[..3], &[RECORD_HANDSHAKE, 0x03, 0x01]);
+        assert_eq!(
+            u16::from_be_bytes([hello[3], hello[4]]) as usize,
+            CLIENT_HELLO_LEN
+        );
+        hello.resize(5 + CLIENT_HELLO_LEN, 0);
+        stream.read_exact(&mut hello[5..]).await.unwrap();
+        assert!(hello
+            .windows(domain.len())
+            .any(|window| window == domain.as_bytes()));
+
+        let client_digest = hello[DIGEST_POS..DIGEST_POS + 32].to_vec();
+        hello[DIGEST_POS..DIGEST_POS + 32].fill(0);
+        let expected = hmac_sha256(&SECRET, &hello);
+        assert_eq!(&client_digest[..28], &expected[..28]);
+
+        let mut response = vec![RECORD_HANDSHAKE, 0x03, 0x03, 0x00, 0x40];
+        response.extend([0x02; 0x40]);
+        response.extend(CHANGE_CIPHER_SPEC);
+        response.extend([RECORD_APPLICATION_DATA, 0x03, 0x03, 0x00, 0x10]);
+        response.extend([0x17; 0x10]);
+        response[DIGEST_POS..DIGEST_POS + 32].fill(0);
+        let mut signed = client_digest;
+        signed.extend(&response);
+        response[DIGEST_POS..DIGEST_POS + 32].copy_from_slice(&hmac_sha256(&SECRET, &signed));
+        stream.write_all(&response).await.unwrap();
+
+        FakeTlsStream::new(stream)
+    }
+
+    /// Check the obfuscated header and first packet sent through the proxy, and reply to
+    /// it the way a server without the authorization key would.
+    async fn serve_obfuscated<S: AsyncRead + AsyncWrite + Unpin>(mut stream: S, tag: [u8; 4]) {
+        let mut header = [0; 64];
+        stream.read_exact(&mut header).await.unwrap();
+        let mut reversed = header;
+        reversed[8..56].reverse();
+        let mut decryptor = cipher_from(&header[8..56]);
+        let mut encryptor = cipher_from(&reversed[8..56]);
+
+        decryptor.apply(&mut header);
+        assert_eq!(&header[56..60], &tag);
+        assert_eq!(i16::from_le_bytes([header[60], header[61]]), 2);
+
+        let mut len = [0; 4];
+        stream.read_exact(&mut len).await.unwrap();
+        decryptor.apply(&mut len);
+        let mut packet = vec![0; u32::from_le_bytes(len) as usize];
+        stream.read_exact(&mut packet).await.unwrap();
+        decryptor.apply(&mut packet);
+        assert_eq!(&packet[..8], &[0; 8]);
+        assert_eq!(&packet[20..24], &0xbe7e8ef1_u32.to_le_bytes());
+
+        let mut response = Vec::new();
+        response.extend(4_i32.to_le_bytes());
+        response.extend((-404_i32).to_le_bytes());
+        encryptor.apply(&mut response);
+        stream.write_all(&response).await.unwrap();
+        stream.flush().await.unwrap();
+    }
+
+    fn connect_through_stand_in(secret: ProxySecret) {
+        let rt = runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+
+        rt.block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let proxy = MtProxy {
+                host: "127.0.0.1".to_string(),
+                port: listener.local_addr().unwrap().port(),
+                secret,
+            };
+
+            let server_secret = proxy.secret.clone();
+            let server = tokio::spawn(async move {
+                let (stream, _) = listener.accept().await.unwrap();
+                match server_secret {
+                    ProxySecret::Simple(_) => serve_obfuscated(stream, [0xee; 4]).await,
+                    ProxySecret::Padded(_) => serve_obfuscated(stream, [0xdd; 4]).await,
+                    ProxySecret::FakeTls { domain, .. } => {
+                        let stream = accept_fake_tls(stream, &domain).await;
+                        serve_obfuscated(stream, [0xdd; 4]).await
+                    }
+                }
+            });
+
+            let result = connect_via_mtproxy(
+                proxy.transport(2),
+                "127.0.0.1:443".parse().unwrap(),
+                &proxy,
+                Arc::new(NoReconnect),
+            )
+            .await;
+
+            server.await.unwrap();
+            assert!(matches!(
+                result,
+                Err(AuthorizationError::Invoke(InvocationError::Read(
+                    ReadError::Transport(transport::Error::BadStatus { status: 404 })
+                )))
+            ));
+        });
+    }
+
+    #[test]
+    fn parse_tg_link() {
+        let proxy = MtProxy::from_link(
+            "tg://proxy?server=proxy.example.com&port=443&secret=123456789abcdef00fedcba987654321",
+        )
+        .unwrap();
+        assert_eq!(
+            proxy,
+            MtProxy {
+                host: "proxy.example.com".to_string(),
+                port: 443,
+                secret: ProxySecret::Simple(SECRET),
+            }
+        );
+    }
+
+    #[test]
+    fn parse_web_link() {
+        let proxy = MtProxy::from_link(
+            "https://t.me/proxy?server=1.2.3.4&port=8888&secret=dd123456789abcdef00fedcba987654321",
+        )
+        .unwrap();
+        assert_eq!(proxy.host, "1.2.3.4");
+        assert_eq!(proxy.port, 8888);
+        assert_eq!(proxy.secret, ProxySecret::Padded(SECRET));
+    }
+
+    #[test]
+    fn parse_fake_tls_secret() {
+        let hex = "ee123456789abcdef00fedcba9876543216578616d706c652e636f6d";
+        let expected = ProxySecret::FakeTls {
+            secret: SECRET,
+            domain: "example.com".to_string(),
+        };
+        assert_eq!(ProxySecret::parse(hex).unwrap(), expected);
+
+        let base64 =
+            base64::engine::general_purpose::STANDARD.encode(hex::opt_from_hex(hex).unwrap());
+        assert_eq!(ProxySecret::parse(&base64).unwrap(), expected);
+    }
+
+    #[test]
+    fn parse_invalid_links() {
+        for link in [
+            "https://example.com/proxy?server=a&port=1&secret=123456789abcdef00fedcba987654321",
+            "tg://socks?server=a&port=1",
+            "tg://proxy?server=a&secret=123456789abcdef00fedcba987654321",
+            "tg://proxy?server=a&port=x&secret=123456789abcdef00fedcba987654321",
+            "tg://proxy?server=a&port=1&secret=1234",
+        ] {
+            assert_eq!(
+                MtProxy::from_link(link).unwrap_err().kind(),
+                ErrorKind::InvalidInput
+            );
+        }
+    }
+
+    #[test]
+    fn client_hello_len() {
+        let hello = client_hello("example.com").unwrap();
+        assert_eq!(hello.len(), 5 + CLIENT_HELLO_LEN);
+        assert!(client_hello(&"a".repeat(400)).is_err());
+    }
+
+    #[test]
+    fn connect_with_simple_secret() {
+        connect_through_stand_in(ProxySecret::Simple(SECRET));
+    }
+
+    #[test]
+    fn connect_with_padded_secret() {
+        connect_through_stand_in(ProxySecret::Padded(SECRET));
+    }
+
+    #[test]
+    fn connect_with_fake_tls_secret() {
+        connect_through_stand_in(ProxySecret::FakeTls {
+            secret: SECRET,
+            domain: "example.com".to_string(),
+        });
+    }
+}
@@ -5,6 +5,8 @@
 // <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
+use crate::ReadError;
+use grammers_mtproto::transport;
 use std::ops::ControlFlow;
 use std::time::Duration;
 
@@ -14,29 +16,79 @@ use std::time::Duration;
 /// custom implementations for handling connection failures.
 ///
 /// the default implementation is **NoReconnect** which does not handle anything! there is also a `FixedReconnect`
-/// which sets a fixed attempt count and a duration
+/// which sets a fixed attempt count and a duration, and an `ExponentialBackoff` which waits longer after every
+/// failed attempt.
 ///
 /// note that this will return a `ControlFlow<(), Duration>` which tells the handler either `Break` the Connection Attempt *or*
 /// `Continue` After the Given `Duration`
+///
+/// the policy is shared behind an `Arc`, so it may keep its own state (for example, a counter of how many times
+/// the connection was lost) through interior mutability, and clear it in [`ReconnectionPolicy::reset`].
 pub trait ReconnectionPolicy: Send + Sync {
     ///this function will indicate that the handler should attempt for a new *reconnection* or not.
     ///
-    /// it accepts a `attempts` which is the amount of reconnection tries that has been made already
-    fn should_retry(&self, attempts: usize) -> ControlFlow<(), Duration>;
+    /// it accepts a `attempts` which is the amount of reconnection tries that has been made already,
+    /// and the `error` which caused the connection to be lost (when `attempts` is zero) or the last
+    /// reconnection attempt to fail.
+    ///
+    /// the error can be used to decide differently depending on the kind of failure. for example,
+    /// [`ReadError::Io`] usually means the network went down, a transport error with status 429
+    /// means too many connections were made in a short time, and a status of 404 means the server
+    /// no longer knows the authorization key, so reconnecting won't help.
+    fn should_retry(&self, attempts: usize, error: &ReadError) -> ControlFlow<(), Duration>;
+
+    /// called after a reconnection succeeds, so that stateful policies can reset their counters.
+    ///
+    /// the default implementation does nothing.
+    fn reset(&self) {}
+}
+
+/// Whether the error is worth reconnecting for: either the connection was lost, or the server
+/// asked to slow down with a transport error 429.
+fn is_transient(error: &ReadError, retry_flood: bool) -> bool {
+    match error {
+        ReadError::Io(_) => true,
+        ReadError::Transport(transport::Error::BadStatus { status: 429 }) => retry_flood,
+        _ => false,
+    }
 }
 
 /// the default implementation of the **ReconnectionPolicy**.
 pub struct NoReconnect;
 
 /// simple *Fixed* sized implementation for the **ReconnectionPolicy** trait.
+///
+/// only errors caused by the connection being lost are retried. the first reconnection is
+/// attempted right away, and the following ones after `delay`.
 pub struct FixedReconnect {
     pub attempts: usize,
     pub delay: Duration,
 }
 
+/// an implementation of the **ReconnectionPolicy** trait which waits exponentially longer after
+/// every failed attempt, up to a maximum.
+///
+/// the delay before an attempt is `initial * multiplier ^ attempts`, capped at `max`, minus a
+/// random amount of up to `jitter` times the delay (so `jitter` should be between `0.0` and
+/// `1.0`). this avoids having many clients reconnecting at the same time.
+///
+/// both the connection being lost and the server asking to slow down (transport error 429) are
+/// retried, the latter waiting at least `initial` before reconnecting.
+pub struct ExponentialBackoff {
+    pub initial: Duration,
+    pub max: Duration,
+    pub multiplier: f64,
+    pub jitter: f64,
+    pub max_attempts: usize,
+}
+
 impl ReconnectionPolicy for FixedReconnect {
-    fn should_retry(&self, attempts: usize) -> ControlFlow<(), Duration> {
-        if attempts <= self.attempts {
+    fn should_retry(&self, attempts: usize, error: &ReadError) -> ControlFlow<(), Duration> {
+        if !is_transient(error, false) {
+            ControlFlow::Break(())
+        } else if attempts == 0 {
+            ControlFlow::Continue(Duration::ZERO)
+        } else if attempts <= self.attempts {
             ControlFlow::Continue(self.delay)
         } else {
             ControlFlow::Break(())
@@ -45,7 +97,127 @@ impl ReconnectionPolicy for FixedReconnect {
 }
 
 impl ReconnectionPolicy for NoReconnect {
-    fn should_retry(&self, _: usize) -> ControlFlow<(), Duration> {
+    fn should_retry(&self, _: usize, _: &ReadError) -> ControlFlow<(), Duration> {
         ControlFlow::Break(())
     }
 }
+
+impl ExponentialBackoff {
+    /// The delay before the given attempt, before applying any jitter.
+    fn delay(&self, attempts: usize) -> Duration {
+        let factor = self.multiplier.powi(attempts.min(i32::MAX as usize) as i32);
+        let delay = self.initial.as_secs_f64() * factor;
+        if delay.is_finite() && delay < self.max.as_secs_f64() {
+            Duration::from_secs_f64(delay)
+        } else {
+            self.max
+        }
+    }
+}
+
+impl Default for ExponentialBackoff {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_millis(500),
+            max: Duration::from_secs(60),
+            multiplier: 2.0,
+            jitter: 0.2,
+            max_attempts: 10,
+        }
+    }
+}
+
+impl ReconnectionPolicy for ExponentialBackoff {
+    fn should_retry(&self, attempts: usize, error: &ReadError) -> ControlFlow<(), Duration> {
+        if !is_transient(error, true) || attempts > self.max_attempts {
+            return ControlFlow::Break(());
+        }
+
+        let delay = self.delay(attempts);
+        let mut random = [0; 4];
+        getrandom::getrandom(&mut random).expect("failed to generate a secure jitter");
+        let random = u32::from_le_bytes(random) as f64 / u32::MAX as f64;
+        let delay = delay.mul_f64(1.0 - self.jitter.clamp(0.0, 1.0) * random);
+
+        if attempts == 0 && matches!(error, ReadError::Transport(_)) {
+            ControlFlow::Continue(delay.max(self.initial))
+        } else {
+            ControlFlow::Continue(delay)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+
+    fn io_error() -> ReadError {
+        ReadError::Io(io::Error::new(io::ErrorKind::ConnectionReset, "reset"))
+    }
+
+    fn status_error(status: u32) -> ReadError {
+        ReadError::Transport(transport::Error::BadStatus { status })
+    }
+
+    #[test]
+    fn exponential_backoff_grows_until_max() {
+        let policy = ExponentialBackoff {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(10),
+            multiplier: 2.0,
+            jitter: 0.0,
+            max_attempts: 10,
+        };
+        let delays = (0..6)
+            .map(|attempts| policy.should_retry(attempts, &io_error()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            delays,
+            [1, 2, 4, 8, 10, 10].map(|secs| ControlFlow::Continue(Duration::from_secs(secs)))
+        );
+        assert_eq!(policy.should_retry(11, &io_error()), ControlFlow::Break(()));
+    }
+
+    #[test]
+    fn exponential_backoff_jitter_shortens_delay() {
+        let policy = ExponentialBackoff {
+            jitter: 0.5,
+            ..Default::default()
+        };
+        for _ in 0..100 {
+            let ControlFlow::Continue(delay) = policy.should_retry(3, &io_error()) else {
+                panic!("policy should retry");
+            };
+            assert!(delay <= Duration::from_secs(4));
+            assert!(delay >= Duration::from_secs(2));
+        }
+    }
+
+    #[test]
+    fn policies_depend_on_error() {
+        let policy = ExponentialBackoff::default();
+        assert!(matches!(
+            policy.should_retry(0, &status_error(429)),
+            ControlFlow::Continue(_)
+        ));
+        assert_eq!(
+            policy.should_retry(0, &status_error(404)),
+            ControlFlow::Break(())
+        );
+
+        let policy = FixedReconnect {
+            attempts: 3,
+            delay: Duration::from_secs(1),
+        };
+        assert_eq!(
+            policy.should_retry(0, &io_error()),
+            ControlFlow::Continue(Duration::ZERO)
+        );
+        assert_eq!(
+            policy.should_retry(0, &status_error(429)),
+            ControlFlow::Break(())
+        );
+        assert_eq!(policy.should_retry(4, &io_error()), ControlFlow::Break(()));
+    }
+}
@@ -0,0 +1,616 @@
+// Copyright 2020 - developers of the `grammers` project.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A minimal [WebSocket] client, used to reach the `/apiws` endpoints of Telegram.
+//!
+//! Everything written to the stream is sent as binary messages, and the payload of the binary
+//! messages received is read back as a continuous stream. Telegram requires the data sent this
+//! way to use the obfuscated framing.
+//!
+//! [WebSocket]: https://datatracker.ietf.org/doc/html/rfc6455
+use base64::Engine;
+use grammers_crypto::sha1;
+use std::io::{self, ErrorKind};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::TcpStream;
+
+/// Path of the WebSocket endpoint of Telegram servers.
+pub const API_PATH: &str = "/apiws";
+
+/// Port of the plain WebSocket endpoint of Telegram servers.
+pub const PORT: u16 = 80;
+
+/// Appended to the key sent by the client to produce the one the server must reply with.
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// The longest header a frame can have: two bytes, eight for the length and four for the mask.
+const MAX_HEADER_LEN: usize = 2 + 8 + 4;
+
+/// How much of the payload of a frame is read at most at once.
+const READ_BUFFER_LEN: usize = 16384;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xa;
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message.to_string())
+}
+
+/// Fill the buffer with secure random bytes.
+fn random<const N: usize>() -> [u8; N] {
+    let mut buffer = [0; N];
+    getrandom::getrandom(&mut buffer).expect("failed to generate a secure value");
+    buffer
+}
+
+/// The value the server must reply with in `Sec-WebSocket-Accept` for the given key.
+fn accept_key(key: &str) -> String {
+    base64::engine::general_purpose::STANDARD.encode(sha1!(key.as_bytes(), ACCEPT_GUID.as_bytes()))
+}
+
+/// Build a masked frame with the given opcode and payload, as clients must send them.
+fn client_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(MAX_HEADER_LEN + payload.len());
+    frame.push(0x80 | opcode);
+    match payload.len() {
+        len @ 0..=125 => frame.push(0x80 | len as u8),
+        len @ 126..=0xffff => {
+            frame.push(0x80 | 126);
+            frame.extend((len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(0x80 | 127);
+            frame.extend((len as u64).to_be_bytes());
+        }
+    }
+
+    let mask = random::<4>();
+    frame.extend(mask);
+    frame.extend(payload.iter().zip(mask.iter().cycle()).map(|(b, m)| b ^ m));
+    frame
+}
+
+/// Returns the host serving the WebSocket endpoint of the given production datacenter.
+///
+/// Telegram does not serve WebSocket connections from the addresses of its datacenters, but
+/// from these hosts instead.
+pub fn websocket_host(dc_id: i32) -> Option<&'static str> {
+    Some(match dc_id {
+        1 => "pluto.web.telegram.org",
+        2 => "venus.web.telegram.org",
+        3 => "aurora.web.telegram.org",
+        4 => "vesta.web.telegram.org",
+        5 => "flora.web.telegram.org",
+        _ => return None,
+    })
+}
+
+/// Connect to the WebSocket endpoint at the given host, or at the given address if there is no
+/// host.
+pub(crate) async fn connect_websocket_stream(
+    addr: &SocketAddr,
+    host: Option<&str>,
+) -> io::Result<crate::NetStream> {
+    let (stream, host) = match host {
+        Some(host) => {
+            log::info!("connecting to websocket at ws://{}{}...", host, API_PATH);
+            (TcpStream::connect((host, PORT)).await?, host.to_string())
+        }
+        None => {
+            log::info!("connecting to websocket at ws://{}{}...", addr, API_PATH);
+            (TcpStream::connect(addr).await?, addr.to_string())
+        }
+    };
+    Ok(crate::NetStream::WebSocket(
+        WebSocketStream::handshake(stream, &host, API_PATH).await?,
+    ))
+}
+
+/// A stream that sends and receives the data as the payload of binary WebSocket messages.
+pub struct WebSocketStream {
+    stream: TcpStream,
+    // Header of the frame being read, how much of it was read, and how much it needs.
+    read_header: [u8; MAX_HEADER_LEN],
+    read_header_len: usize,
+    read_header_needed: usize,
+    // Opcode, mask and payload left to read of the frame being read, and the position in the
+    // mask to apply to the next byte.
+    read_opcode: u8,
+    read_mask: Option<[u8; 4]>,
+    read_remaining: usize,
+    read_mask_pos: usize,
+    read_buffer: Vec<u8>,
+    // Payload of the control frame being read, which is only handled once complete.
+    control_payload: Vec<u8>,
+    // Frame being written, how much of it was written, and the length of its payload if it's
+    // a data frame.
+    write_frame: Vec<u8>,
+    write_pos: usize,
+    write_payload_len: Option<usize>,
+    // Control frames to send as soon as no data frame is being written.
+    control_out: Vec<u8>,
+    closed: bool,
+}
+
+impl WebSocketStream {
+    /// Perform the opening handshake over the stream, for the given host and path.
+    pub async fn handshake(mut stream: TcpStream, host: &str, path: &str) -> io::Result<Self> {
+        let key = base64::engine::general_purpose::STANDARD.encode(random::<16>());
+        let request = format!(
+            "GET {path} HTTP/1.1\r\n\
+             Host: {host}\r\n\
+             Upgrade: websocket\r\n\
+             Connection: Upgrade\r\n\
+             Sec-WebSocket-Key: {key}\r\n\
+             Sec-WebSocket-Version: 13\r\n\
+             Sec-WebSocket-Protocol: binary\r\n\r\n"
+        );
+        stream.write_all(request.as_bytes()).await?;
+
+        // The server can't send any frame before it receives data, so reading byte by byte
+        // won't consume more than the response.
+        let mut response = Vec::new();
+        while !response.ends_with(b"\r\n\r\n") {
+            if response.len() > 8192 {
+                return Err(invalid_data("websocket handshake response is too long"));
+            }
+            response.push(stream.read_u8().await?);
+        }
+
+        let response = String::from_utf8(response)
+            .map_err(|_| invalid_data("websocket handshake response is not valid"))?;
+        let mut lines = response.split("\r\n");
+        if lines.next().and_then(|status| status.split(' ').nth(1)) != Some("101") {
+            return Err(invalid_data("websocket handshake was rejected"));
+        }
+        let accept = lines.find_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            name.trim()
+                .eq_ignore_ascii_case("Sec-WebSocket-Accept")
+                .then(|| value.trim())
+        });
+        if accept != Some(&accept_key(&key)) {
+            return Err(invalid_data("websocket handshake has a wrong accept key"));
+        }
+
+        Ok(Self {
+            stream,
+            read_header: [0; MAX_HEADER_LEN],
+            read_header_len: 0,
+            read_header_needed: 2,
+            read_opcode: 0,
+            read_mask: None,
+            read_remaining: 0,
+            read_mask_pos: 0,
+            read_buffer: vec![0; READ_BUFFER_LEN],
+            control_payload: Vec::new(),
+            write_frame: Vec::new(),
+            write_pos: 0,
+            write_payload_len: None,
+            control_out: Vec::new(),
+            closed: false,
+        })
+    }
+
+    /// Parse the frame header read so far, returning whether it's complete.
+    fn parse_header(&mut self) -> io::Result<bool> {
+        let header = &self.read_header[..self.read_header_len];
+        let masked = header[1] & 0x80 != 0;
+        let len_size = match header[1] & 0x7f {
+            126 => 2,
+            127 => 8,
+            _ => 0,
+        };
+        self.read_header_needed = 2 + len_size + if masked { 4 } else { 0 };
+        if self.read_header_len < self.read_header_needed {
+            return Ok(false);
+        }
+
+        self.read_opcode = header[0] & 0x0f;
+        self.read_remaining = match len_size {
+            0 => (header[1] & 0x7f) as usize,
+            2 => u16::from_be_bytes([header[2], header[3]]) as usize,
+            _ => u64::from_be_bytes(header[2..10].try_into().unwrap())
+                .try_into()
+                .map_err(|_| invalid_data("websocket frame is too long"))?,
+        };
+        self.read_mask = masked.then(|| header[2 + len_size..][..4].try_into().unwrap());
+        self.read_mask_pos = 0;
+        self.read_header_len = 0;
+        self.read_header_needed = 2;
+
+        if self.read_opcode >= OPCODE_CLOSE && self.read_remaining > 125 {
+            return Err(invalid_data("websocket control frame is too long"));
+        }
+        Ok(true)
+    }
+
+    /// Remove the mask from data read from the payload of the current frame.
+    fn unmask(&mut self, data: &mut [u8]) {
+        if let Some(mask) = self.read_mask {
+            for byte in data.iter_mut() {
+                *byte ^= mask[self.read_mask_pos % 4];
+                self.read_mask_pos += 1;
+            }
+        }
+    }
+
+    /// Handle a control frame once its whole payload was read.
+    fn on_control_frame(&mut self) {
+        match self.read_opcode {
+            OPCODE_CLOSE => {
+                log::info!("websocket closed by the server");
+                self.closed = true;
+            }
+            OPCODE_PING => {
+                let payload = std::mem::take(&mut self.control_payload);
+                self.control_out.extend(client_frame(OPCODE_PONG, &payload));
+            }
+            _ => {}
+        }
+        self.control_payload.clear();
+    }
+
+    /// Write as much of the pending data frame as possible.
+    fn poll_write_frame(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.write_pos < self.write_frame.len() {
+            let n = ready!(
+                Pin::new(&mut self.stream).poll_write(cx, &self.write_frame[self.write_pos..])
+            )?;
+            if n == 0 {
+                return Poll::Ready(Err(ErrorKind::WriteZero.into()));
+            }
+            self.write_pos += n;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    /// Write the pending control frames, if any, without waiting for them to be written, unless
+    /// a data frame is being written.
+    fn try_write_control_frames(&mut self, cx: &mut Context<'_>) -> io::Result<()> {
+        if self.write_payload_len.is_some() {
+            return Ok(());
+        }
+        if self.write_frame.is_empty() {
+            self.write_frame = std::mem::take(&mut self.control_out);
+            self.write_pos = 0;
+        }
+        if let Poll::Ready(result) = self.poll_write_frame(cx) {
+            result?;
+            self.write_frame.clear();
+            self.write_pos = 0;
+        }
+        Ok(())
+    }
+}
+
+impl AsyncRead for WebSocketStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+        loop {
+            if this.closed {
+                return Poll::Ready(Ok(()));
+            }
+
+            // Pongs are sent on a best-effort basis, as data might not be written for a while.
+            this.try_write_control_frames(cx)?;
+
+            if this.read_remaining != 0 {
+                let len = if this.read_opcode >= OPCODE_CLOSE {
+                    this.read_remaining
+                } else {
+                    this.read_remaining.min(buf.remaining())
+                };
+                let mut payload = ReadBuf::new(&mut this.read_buffer[..len.min(READ_BUFFER_LEN)]);
+                ready!(Pin::new(&mut this.stream).poll_read(cx, &mut payload))?;
+                let n = payload.filled().len();
+                if n == 0 {
+                    return Poll::Ready(Err(ErrorKind::UnexpectedEof.into()));
+                }
+                let mut data = std::mem::take(&mut this.read_buffer);
+                this.unmask(&mut data[..n]);
+                this.read_remaining -= n;
+
+                if this.read_opcode >= OPCODE_CLOSE {
+                    this.control_payload.extend(&data[..n]);
+                    this.read_buffer = data;
+                    if this.read_remaining == 0 {
+                        this.on_control_frame();
+                    }
+                    continue;
+                }
+
+                buf.put_slice(&data[..n]);
+                this.read_buffer = data;
+                return Poll::Ready(Ok(()));
+            }
+
+            let mut header =
+                ReadBuf::new(&mut this.read_header[this.read_header_len..this.read_header_needed]);
+            ready!(Pin::new(&mut this.stream).poll_read(cx, &mut header))?;
+            let n = header.filled().len();
+            if n == 0 {
+                // The connection was closed, which is reported the same way.
+                return Poll::Ready(Ok(()));
+            }
+            this.read_header_len += n;
+            if this.read_header_len < this.read_header_needed || !this.parse_header()? {
+                continue;
+            }
+
+            match this.read_opcode {
+                OPCODE_BINARY | OPCODE_CONTINUATION => {}
+                OPCODE_CLOSE | OPCODE_PING | OPCODE_PONG => {
+                    if this.read_remaining == 0 {
+                        this.on_control_frame();
+                    }
+                }
+                _ => return Poll::Ready(Err(invalid_data("unexpected websocket frame"))),
+            }
+        }
+    }
+}
+
+impl AsyncWrite for WebSocketStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        loop {
+            // A frame that was not fully written is for the same data being written again.
+            if this.write_frame.is_empty() {
+                if this.control_out.is_empty() {
+                    this.write_frame = client_frame(OPCODE_BINARY, buf);
+                    this.write_payload_len = Some(buf.len());
+                } else {
+                    this.write_frame = std::mem::take(&mut this.control_out);
+                }
+                this.write_pos = 0;
+            }
+
+            ready!(this.poll_write_frame(cx))?;
+            this.write_frame.clear();
+            this.write_pos = 0;
+            if let Some(n) = this.write_payload_len.take() {
+                return Poll::Ready(Ok(n));
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_frame(cx))?;
+        Pin::new(&mut this.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_frame(cx))?;
+        Pin::new(&mut this.stream).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        connect_via_websocket, AuthorizationError, InvocationError, NoReconnect, ReadError,
+    };
+    use grammers_crypto::aes::AesCtr;
+    use grammers_mtproto::transport;
+    use std::sync::Arc;
+    use tokio::net::TcpListener;
+    use tokio::runtime;
+
+    /// Perform the server side of the handshake, checking the request of the client.
+    async fn accept(listener: TcpListener) -> TcpStream {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut request = Vec::new();
+        while !request.ends_with(b"\r\n\r\n") {
+            request.push(stream.read_u8().await.unwrap());
+        }
+        let request = String::from_utf8(request).unwrap();
+        assert!(request.starts_with("GET /apiws HTTP/1.1\r\n"));
+        assert!(request.contains("\r\nSec-WebSocket-Protocol: binary\r\n"));
+        let key = request
+            .split("\r\n")
+            .find_map(|line| line.strip_prefix("Sec-WebSocket-Key: "))
+            .unwrap();
+
+        let response = format!(
+            "HTTP/1.1 101 Switching Protocols\r\n\
+             Upgrade: websocket\r\n\
+             Connection: Upgrade\r\n\
+             Sec-WebSocket-Accept: {}\r\n\
+             Sec-WebSocket-Protocol: binary\r\n\r\n",
+            accept_key(key)
+        );
+        stream.write_all(response.as_bytes()).await.unwrap();
+        stream
+    }
+
+    /// Read a frame sent by the client, which must be masked, returning its opcode and payload.
+    async fn read_frame(stream: &mut TcpStream) -> (u8, Vec<u8>) {
+        let mut header = [0; 2];
+        stream.read_exact(&mut header).await.unwrap();
+        assert_eq!(header[0] & 0x80, 0x80);
+        assert_eq!(header[1] & 0x80, 0x80);
+        let len = match header[1] & 0x7f {
+            126 => stream.read_u16().await.unwrap() as usize,
+            127 => stream.read_u64().await.unwrap() as usize,
+            len => len as usize,
+        };
+        let mut mask = [0; 4];
+        stream.read_exact(&mut mask).await.unwrap();
+        let mut payload = vec![0; len];
+        stream.read_exact(&mut payload).await.unwrap();
+        payload
+            .iter_mut()
+            .zip(mask.iter().cycle())
+            .for_each(|(b, m)| *b ^= m);
+        (header[0] & 0x0f, payload)
+    }
+
+    /// Write an unmasked frame, as servers send them.
+    async fn write_frame(stream: &mut TcpStream, fin: bool, opcode: u8, payload: &[u8]) {
+        let mut frame = vec![if fin { 0x80 } else { 0 } | opcode];
+        if payload.len() < 126 {
+            frame.push(payload.len() as u8);
+        } else {
+            frame.push(126);
+            frame.extend((payload.len() as u16).to_be_bytes());
+        }
+        frame.extend(payload);
+        stream.write_all(&frame).await.unwrap();
+    }
+
+    fn run(future: impl std::future::Future<Output = ()>) {
+        runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    #[test]
+    fn known_datacenters_have_a_host() {
+        assert_eq!(websocket_host(2), Some("venus.web.telegram.org"));
+        assert!((1..=5).all(|dc_id| websocket_host(dc_id).is_some()));
+        assert_eq!(websocket_host(6), None);
+    }
+
+    #[test]
+    fn handshake_rejected() {
+        run(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let server = tokio::spawn(async move {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                stream
+                    .write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n")
+                    .await
+                    .unwrap();
+            });
+
+            let stream = TcpStream::connect(addr).await.unwrap();
+            let result = WebSocketStream::handshake(stream, &addr.to_string(), API_PATH).await;
+            server.await.unwrap();
+            assert_eq!(result.err().unwrap().kind(), ErrorKind::InvalidData);
+        });
+    }
+
+    #[test]
+    fn echo_with_ping() {
+        run(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let server = tokio::spawn(async move {
+                let mut stream = accept(listener).await;
+                let (opcode, payload) = read_frame(&mut stream).await;
+                assert_eq!(opcode, OPCODE_BINARY);
+
+                // Interleave a ping between the fragments of the echoed message.
+                let (first, second) = payload.split_at(5);
+                write_frame(&mut stream, false, OPCODE_BINARY, first).await;
+                write_frame(&mut stream, true, OPCODE_PING, b"ping").await;
+                write_frame(&mut stream, true, OPCODE_CONTINUATION, second).await;
+
+                assert_eq!(
+                    read_frame(&mut stream).await,
+                    (OPCODE_PONG, b"ping".to_vec())
+                );
+                let (opcode, payload) = read_frame(&mut stream).await;
+                assert_eq!(opcode, OPCODE_BINARY);
+                assert_eq!(payload.len(), 300);
+            });
+
+            let stream = TcpStream::connect(addr).await.unwrap();
+            let mut stream = WebSocketStream::handshake(stream, &addr.to_string(), API_PATH)
+                .await
+                .unwrap();
+            stream.write_all(b"hello world!").await.unwrap();
+            let mut echo = [0; 12];
+            stream.read_exact(&mut echo).await.unwrap();
+            assert_eq!(&echo, b"hello world!");
+
+            // A longer payload uses the extended length.
+            stream.write_all(&[7; 300]).await.unwrap();
+            server.await.unwrap();
+        });
+    }
+
+    #[test]
+    fn connect_through_stand_in() {
+        run(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let server = tokio::spawn(async move {
+                let mut stream = accept(listener).await;
+                let (_, mut data) = read_frame(&mut stream).await;
+
+                // Undo the obfuscation, which uses the random header for the keys.
+                let header = data[..64].to_vec();
+                let mut reversed = header.clone();
+                reversed[8..56].reverse();
+                let mut decryptor = AesCtr::new(
+                    header[8..40].try_into().unwrap(),
+                    header[40..56].try_into().unwrap(),
+                );
+                let mut encryptor = AesCtr::new(
+                    reversed[8..40].try_into().unwrap(),
+                    reversed[40..56].try_into().unwrap(),
+                );
+                decryptor.apply(&mut data);
+                assert_eq!(&data[56..60], &[0xee; 4]);
+
+                // A plain req_pq_multi follows, after the intermediate length.
+                let packet = &data[64 + 4..];
+                assert_eq!(&packet[..8], &[0; 8]);
+                assert_eq!(&packet[20..24], &0xbe7e8ef1_u32.to_le_bytes());
+
+                let mut response = Vec::new();
+                response.extend(4_i32.to_le_bytes());
+                response.extend((-404_i32).to_le_bytes());
+                encryptor.apply(&mut response);
+                write_frame(&mut stream, true, OPCODE_BINARY, &response).await;
+            });
+
+            let result = connect_via_websocket(
+                transport::Obfuscated::new(transport::Intermediate::new()),
+                addr,
+                None,
+                Arc::new(NoReconnect),
+            )
+            .await;
+
+            server.await.unwrap();
+            assert!(matches!(
+                result,
+                Err(AuthorizationError::Invoke(InvocationError::Read(
+                    ReadError::Transport(transport::Error::BadStatus { status: 404 })
+                )))
+            ));
+        });
+    }
+}
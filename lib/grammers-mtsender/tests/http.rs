@@ -0,0 +1,76 @@
+// Copyright 2020 - developers of the `grammers` project.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use grammers_mtproto::transport;
+use grammers_mtsender::{connect, AuthorizationError, InvocationError, NoReconnect, ReadError};
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::runtime;
+
+/// Read a whole HTTP request, returning its headers and body.
+async fn read_request(stream: &mut tokio::net::TcpStream) -> (String, Vec<u8>) {
+    let mut request = Vec::new();
+    while !request.ends_with(b"\r\n\r\n") {
+        request.push(stream.read_u8().await.unwrap());
+    }
+    let headers = String::from_utf8(request).unwrap();
+    let len = headers
+        .split("\r\n")
+        .find_map(|line| line.strip_prefix("Content-Length: "))
+        .unwrap()
+        .parse()
+        .unwrap();
+
+    let mut body = vec![0; len];
+    stream.read_exact(&mut body).await.unwrap();
+    (headers, body)
+}
+
+#[test]
+fn test_http_transport_posts_to_api() {
+    let rt = runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    rt.block_on(async {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let (headers, body) = read_request(&mut stream).await;
+            assert!(headers.starts_with("POST /api HTTP/1.1\r\n"));
+            assert!(headers.contains(&format!("\r\nHost: {}\r\n", addr)));
+
+            // A plain req_pq_multi: no auth key ID, message ID, length and the request itself.
+            assert_eq!(&body[..8], &[0; 8]);
+            assert_eq!(&body[20..24], &0xbe7e8ef1_u32.to_le_bytes());
+
+            stream
+                .write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n")
+                .await
+                .unwrap();
+        });
+
+        let result = connect(
+            transport::Http::new(&addr.to_string()),
+            addr,
+            Arc::new(NoReconnect),
+        )
+        .await;
+
+        server.await.unwrap();
+        assert!(matches!(
+            result,
+            Err(AuthorizationError::Invoke(InvocationError::Read(
+                ReadError::Transport(transport::Error::BadStatus { status: 404 })
+            )))
+        ));
+    });
+}
@@ -15,6 +15,7 @@ use grammers_mtproto::transport;
 use grammers_mtsender::{connect, NoReconnect};
 use grammers_tl_types::{enums, functions, Deserializable, RemoteCall, LAYER};
 use std::str::FromStr;
+use std::sync::Arc;
 
 use simple_logger::SimpleLogger;
 use tokio::runtime;
@@ -34,7 +35,7 @@ fn test_invoke_encrypted_method() {
         let (mut sender, enqueuer) = connect(
             transport::Full::new(),
             std::net::SocketAddr::from_str(TELEGRAM_TEST_DC_2).unwrap(),
-            &NoReconnect,
+            Arc::new(NoReconnect),
         )
         .await
         .unwrap();
@@ -12,7 +12,7 @@ use std::fs::File;
 use std::io::{BufWriter, Write};
 use std::path::Path;
 
-const CURRENT_VERSION: i32 = 2;
+const CURRENT_VERSION: i32 = 5;
 
 fn main() -> std::io::Result<()> {
     let mut file = BufWriter::new(File::create(
@@ -26,7 +26,17 @@ fn main() -> std::io::Result<()> {
         user id:long dc:int bot:Bool = User;
         channelState channel_id:long pts:int = ChannelState;
         updateState pts:int qts:int date:int seq:int channels:Vector<ChannelState> = UpdateState;
-        session flags:# dcs:Vector<DataCenter> user:flags.0?User state:flags.1?UpdateState = Session;
+        peer flags:# id:long ty:int access_hash:flags.0?long = Peer;
+        username name:string peer_id:long = Username;
+        dcOption flags:# id:int ipv4:flags.0?int ipv6:flags.1?int128 port:int media_only:flags.2?true cdn:flags.3?true static:flags.4?true = DcOption;
+        futureSalt valid_since:int valid_until:int salt:long = FutureSalt;
+        dcSalts id:int salts:Vector<FutureSalt> = DcSalts;
+        session flags:# dcs:Vector<DataCenter> user:flags.0?User state:flags.1?UpdateState peers:Vector<Peer> usernames:Vector<Username> dc_options:Vector<DcOption> salts:Vector<DcSalts> = Session;
+
+        // Older versions, which are still loaded and migrated to the current one.
+        sessionV4#28e39e87 flags:# dcs:Vector<DataCenter> user:flags.0?User state:flags.1?UpdateState peers:Vector<Peer> usernames:Vector<Username> dc_options:Vector<DcOption> = Session;
+        sessionV3#c18183d2 flags:# dcs:Vector<DataCenter> user:flags.0?User state:flags.1?UpdateState peers:Vector<Peer> usernames:Vector<Username> = Session;
+        sessionV2#a73eb8ce flags:# dcs:Vector<DataCenter> user:flags.0?User state:flags.1?UpdateState = Session;
         "#,
     )
     .map(Result::unwrap)
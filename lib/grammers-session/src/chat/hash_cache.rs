@@ -6,16 +6,22 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 use super::{PackedChat, PackedType};
+use crate::SessionStorage;
 use grammers_tl_types as tl;
 use std::collections::HashMap;
+use std::sync::Arc;
 
 /// In-memory chat cache, mapping peers to their respective access hashes.
+///
+/// If a [`SessionStorage`] is provided, newly-learnt access hashes are also written to it, and
+/// peers missing from memory are looked up there.
 pub struct ChatHashCache {
     // As far as I've observed, user, chat and channel IDs cannot collide,
     // but it will be an interesting moment if they ever do.
     hash_map: HashMap<i64, (i64, PackedType)>,
     self_id: Option<i64>,
     self_bot: bool,
+    storage: Option<Arc<dyn SessionStorage>>,
 }
 
 impl ChatHashCache {
@@ -24,6 +30,15 @@ impl ChatHashCache {
             hash_map: HashMap::new(),
             self_id: self_user.map(|user| user.0),
             self_bot: self_user.map(|user| user.1).unwrap_or(false),
+            storage: None,
+        }
+    }
+
+    /// Like [`ChatHashCache::new`], but backed by the given session storage.
+    pub fn with_storage(self_user: Option<(i64, bool)>, storage: Arc<dyn SessionStorage>) -> Self {
+        Self {
+            storage: Some(storage),
+            ..Self::new(self_user)
         }
     }
 
@@ -46,16 +61,50 @@ impl ChatHashCache {
     }
 
     pub fn get(&self, id: i64) -> Option<PackedChat> {
-        self.hash_map.get(&id).map(|&(hash, ty)| PackedChat {
-            ty,
-            id,
-            access_hash: Some(hash),
-        })
+        match self.hash_map.get(&id) {
+            Some(&(hash, ty)) => Some(PackedChat {
+                ty,
+                id,
+                access_hash: Some(hash),
+            }),
+            None => self
+                .storage
+                .as_ref()
+                .and_then(|storage| storage.get_chat(id))
+                .filter(|chat| chat.access_hash.is_some()),
+        }
     }
 
     #[inline]
     fn has(&self, id: i64) -> bool {
-        self.hash_map.contains_key(&id)
+        self.get(id).is_some()
+    }
+
+    /// Inserts the access hash into memory, remembering it in `changed` if it was not known before.
+    fn insert(&mut self, changed: &mut Vec<PackedChat>, id: i64, hash: i64, ty: PackedType) {
+        if self.hash_map.insert(id, (hash, ty)) != Some((hash, ty)) {
+            changed.push(PackedChat {
+                ty,
+                id,
+                access_hash: Some(hash),
+            });
+        }
+    }
+
+    /// Writes the given chats and usernames to the storage, if any.
+    fn store(&self, chats: &[PackedChat], usernames: &[(String, i64)]) {
+        if let Some(storage) = self.storage.as_ref() {
+            if !chats.is_empty() {
+                storage.cache_chats(chats);
+            }
+            if !usernames.is_empty() {
+                let usernames = usernames
+                    .iter()
+                    .map(|(username, id)| (username.as_str(), *id))
+                    .collect::<Vec<_>>();
+                storage.cache_usernames(&usernames);
+            }
+        }
     }
 
     fn has_peer(&self, peer: &tl::enums::Peer) -> bool {
@@ -130,6 +179,8 @@ impl ChatHashCache {
         use tl::enums::{Chat as C, User as U};
 
         let mut success = true;
+        let mut changed = Vec::new();
+        let mut usernames = Vec::new();
 
         users.iter().for_each(|user| match user {
             U::Empty(_) => {}
@@ -140,9 +191,10 @@ impl ChatHashCache {
                     } else {
                         PackedType::User
                     };
-                    self.hash_map.insert(u.id, (hash, ty));
+                    self.insert(&mut changed, u.id, hash, ty);
+                    collect_usernames(&mut usernames, u.id, &u.username, &u.usernames);
                 }
-                _ => success &= self.hash_map.contains_key(&u.id),
+                _ => success &= self.has(u.id),
             },
         });
 
@@ -157,9 +209,10 @@ impl ChatHashCache {
                     } else {
                         PackedType::Broadcast
                     };
-                    self.hash_map.insert(c.id, (hash, ty));
+                    self.insert(&mut changed, c.id, hash, ty);
+                    collect_usernames(&mut usernames, c.id, &c.username, &c.usernames);
                 }
-                _ => success &= self.hash_map.contains_key(&c.id),
+                _ => success &= self.has(c.id),
             },
             C::ChannelForbidden(c) => {
                 let ty = if c.megagroup {
@@ -167,10 +220,11 @@ impl ChatHashCache {
                 } else {
                     PackedType::Broadcast
                 };
-                self.hash_map.insert(c.id, (c.access_hash, ty));
+                self.insert(&mut changed, c.id, c.access_hash, ty);
             }
         });
 
+        self.store(&changed, &usernames);
         success
     }
 
@@ -586,3 +640,17 @@ impl ChatHashCache {
         }
     }
 }
+
+/// Collects all the active usernames of a peer, so they can be stored along its access hash.
+fn collect_usernames(
+    out: &mut Vec<(String, i64)>,
+    id: i64,
+    username: &Option<String>,
+    usernames: &Option<Vec<tl::enums::Username>>,
+) {
+    out.extend(username.iter().map(|username| (username.clone(), id)));
+    out.extend(usernames.iter().flatten().filter_map(|username| {
+        let tl::enums::Username::Username(username) = username;
+        username.active.then(|| (username.username.clone(), id))
+    }));
+}
@@ -0,0 +1,228 @@
+// Copyright 2020 - developers of the `grammers` project.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Encrypted container for the serialized session.
+//!
+//! The container starts with a header, followed by the encrypted session:
+//!
+//! ```text
+//! magic (4) | version (1) | rounds (4, little-endian) | salt (32) | ciphertext
+//! ```
+//!
+//! The AES-256-IGE key and initialization vector are derived from the passphrase and the salt
+//! with PBKDF2-HMAC-SHA512. The plaintext is prefixed with its own SHA-256 hash and length, so
+//! that a wrong passphrase can be told apart from corrupted data.
+use crate::{Error, FileFormat, Session};
+use grammers_crypto::{decrypt_ige, encrypt_ige, pbkdf2_sha512, sha256};
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+const MAGIC: [u8; 4] = *b"GRSE";
+const VERSION: u8 = 1;
+const ROUNDS: u32 = 100_000;
+// Files claiming more rounds are rejected, so that they can't make loading take forever.
+const MAX_ROUNDS: u32 = 100 * ROUNDS;
+const HEADER_LEN: usize = MAGIC.len() + 1 + 4 + 32;
+
+fn derive_key(passphrase: &str, salt: &[u8], rounds: u32) -> ([u8; 32], [u8; 32]) {
+    let dk = pbkdf2_sha512(passphrase.as_bytes(), salt, rounds);
+    let mut key = [0; 32];
+    let mut iv = [0; 32];
+    key.copy_from_slice(&dk[..32]);
+    iv.copy_from_slice(&dk[32..]);
+    (key, iv)
+}
+
+fn encrypt(data: &[u8], passphrase: &str, rounds: u32) -> Vec<u8> {
+    let mut salt = [0; 32];
+    getrandom::getrandom(&mut salt).expect("failed to generate a secure salt");
+    let (key, iv) = derive_key(passphrase, &salt, rounds);
+
+    let mut plaintext = Vec::with_capacity(32 + 4 + data.len());
+    plaintext.extend(sha256!(data));
+    plaintext.extend((data.len() as u32).to_le_bytes());
+    plaintext.extend(data);
+
+    let mut result = Vec::with_capacity(HEADER_LEN + plaintext.len() + 16);
+    result.extend(MAGIC);
+    result.push(VERSION);
+    result.extend(rounds.to_le_bytes());
+    result.extend(salt);
+    result.extend(encrypt_ige(&plaintext, &key, &iv));
+    result
+}
+
+fn decrypt(data: &[u8], passphrase: &str) -> Result<Vec<u8>, Error> {
+    if data.len() < HEADER_LEN || data[..MAGIC.len()] != MAGIC {
+        return Err(Error::MalformedData);
+    }
+    if data[MAGIC.len()] != VERSION {
+        return Err(Error::UnsupportedVersion);
+    }
+
+    let rounds = u32::from_le_bytes(data[5..9].try_into().unwrap());
+    if !(ROUNDS..=MAX_ROUNDS).contains(&rounds) {
+        return Err(Error::MalformedData);
+    }
+    let salt = &data[9..HEADER_LEN];
+    let ciphertext = &data[HEADER_LEN..];
+    if ciphertext.len() < 48 || !ciphertext.len().is_multiple_of(16) {
+        return Err(Error::MalformedData);
+    }
+
+    let (key, iv) = derive_key(passphrase, salt, rounds);
+    let plaintext = decrypt_ige(ciphertext, &key, &iv);
+
+    let len = u32::from_le_bytes(plaintext[32..36].try_into().unwrap()) as usize;
+    let session = plaintext
+        .get(36..36 + len)
+        .filter(|session| sha256!(session) == plaintext[..32])
+        .ok_or(Error::WrongPassphrase)?;
+
+    Ok(session.to_vec())
+}
+
+impl Session {
+    /// Like [`Session::save`], but encrypts the session with the given passphrase.
+    ///
+    /// The passphrase is needed to [`Session::load_encrypted`] the session back.
+    #[must_use]
+    pub fn save_encrypted(&self, passphrase: &str) -> Vec<u8> {
+        encrypt(&self.save(), passphrase, ROUNDS)
+    }
+
+    /// Like [`Session::load`], but for sessions saved with [`Session::save_encrypted`].
+    ///
+    /// [`Error::WrongPassphrase`] is returned if the passphrase does not match the one used
+    /// to encrypt the session.
+    pub fn load_encrypted(data: &[u8], passphrase: &str) -> Result<Self, Error> {
+        Self::load(&decrypt(data, passphrase)?)
+    }
+
+    /// Saves the session to a file, encrypted with the given passphrase.
+    ///
+    /// The file and passphrase are remembered, so that [`SessionStorage::flush`] saves the
+    /// session back to it, encrypted again.
+    ///
+    /// [`SessionStorage::flush`]: crate::SessionStorage::flush
+    pub fn save_to_file_encrypted<P: AsRef<Path>>(
+        &self,
+        path: P,
+        passphrase: &str,
+    ) -> io::Result<()> {
+        self.save_and_remember_file(
+            path.as_ref(),
+            false,
+            FileFormat::Encrypted {
+                passphrase: passphrase.to_string(),
+            },
+        )
+    }
+
+    /// Load a previous session instance from a file encrypted with the given passphrase.
+    ///
+    /// The file and passphrase are remembered, so that [`SessionStorage::flush`] saves the
+    /// session back to it, encrypted again.
+    ///
+    /// [`SessionStorage::flush`]: crate::SessionStorage::flush
+    pub fn load_file_encrypted<P: AsRef<Path>>(path: P, passphrase: &str) -> io::Result<Self> {
+        let mut data = Vec::new();
+        File::open(path.as_ref())?.read_to_end(&mut data)?;
+
+        let session = Self::load_encrypted(&data, passphrase)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        session.remember_file(
+            path.as_ref(),
+            false,
+            FileFormat::Encrypted {
+                passphrase: passphrase.to_string(),
+            },
+        );
+        Ok(session)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encrypted_roundtrip() {
+        let data = encrypt(b"session data", "hunter2", ROUNDS);
+        assert!(!data.windows(12).any(|w| w == b"session data"));
+        assert_eq!(decrypt(&data, "hunter2").unwrap(), b"session data");
+    }
+
+    #[test]
+    fn encrypted_wrong_passphrase() {
+        let data = encrypt(b"session data", "hunter2", ROUNDS);
+        assert!(matches!(
+            decrypt(&data, "hunter3"),
+            Err(Error::WrongPassphrase)
+        ));
+    }
+
+    #[test]
+    fn encrypted_bad_header() {
+        let mut data = encrypt(b"session data", "hunter2", ROUNDS);
+        assert!(matches!(
+            decrypt(&data[..HEADER_LEN], "hunter2"),
+            Err(Error::MalformedData)
+        ));
+
+        data[MAGIC.len()] = VERSION + 1;
+        assert!(matches!(
+            decrypt(&data, "hunter2"),
+            Err(Error::UnsupportedVersion)
+        ));
+
+        data[MAGIC.len()] = VERSION;
+        for rounds in [ROUNDS - 1, MAX_ROUNDS + 1] {
+            data[5..9].copy_from_slice(&rounds.to_le_bytes());
+            assert!(matches!(
+                decrypt(&data, "hunter2"),
+                Err(Error::MalformedData)
+            ));
+        }
+
+        data[0] = 0;
+        assert!(matches!(
+            decrypt(&data, "hunter2"),
+            Err(Error::MalformedData)
+        ));
+    }
+
+    #[test]
+    fn encrypted_file_is_flushed_encrypted() {
+        use crate::SessionStorage;
+
+        let path =
+            std::env::temp_dir().join(format!("grammers-encrypted-{}.session", std::process::id()));
+        Session::new()
+            .save_to_file_encrypted(&path, "hunter2")
+            .unwrap();
+
+        let session = Session::load_file_encrypted(&path, "hunter2").unwrap();
+        session.set_user(123, 2, false);
+        session.flush().unwrap();
+        assert!(Session::load_file(&path).is_err());
+
+        let session = Session::load_file_encrypted(&path, "hunter2").unwrap();
+        assert_eq!(session.get_user().map(|user| user.id), Some(123));
+
+        // Saving a plain session encrypted in place makes later flushes encrypted too.
+        std::fs::write(&path, Session::new().save()).unwrap();
+        let session = Session::load_file(&path).unwrap();
+        session.save_to_file_encrypted(&path, "hunter2").unwrap();
+        session.flush().unwrap();
+        assert!(Session::load_file_encrypted(&path, "hunter2").is_ok());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
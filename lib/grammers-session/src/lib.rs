@@ -9,29 +9,91 @@
 #![deny(unsafe_code)]
 
 mod chat;
+mod encrypted;
+// Not all of the accessors generated for the older session versions are used.
+#[allow(dead_code)]
 mod generated;
 mod message_box;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+mod storage;
+mod string;
 
 pub use chat::{ChatHashCache, PackedChat, PackedType};
+pub use generated::types::DcOption;
+pub use generated::types::FutureSalt;
 pub use generated::types::UpdateState;
 pub use generated::types::User;
 pub use generated::LAYER as VERSION;
 use generated::{enums, types};
 use grammers_tl_types::deserialize::Error as DeserializeError;
+use log::warn;
 pub use message_box::{channel_id, PrematureEndReason};
 pub use message_box::{Gap, MessageBox};
+#[cfg(feature = "sqlite")]
+pub use sqlite::SqliteSession;
+use std::collections::HashMap;
 use std::fmt;
-use std::fs::{File, OpenOptions};
-use std::io::{self, Read, Seek, Write};
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
 use std::net::{SocketAddr, SocketAddrV4, SocketAddrV6};
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Mutex;
+pub use storage::{MemorySession, SessionStorage};
 
 // Needed for auto-generated definitions.
 use grammers_tl_types::{deserialize, Deserializable, Identifiable, Serializable};
 
+const TEMP_SUFFIX: &str = ".tmp";
+const BACKUP_SUFFIX: &str = ".bak";
+const CORRUPT_SUFFIX: &str = ".corrupt";
+
+/// Used to give every temporary file written by this process a different name.
+static TEMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns the path to a file next to `path`, with the suffix appended to its name.
+fn sibling_path(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// Returns a path next to `path` that no other write (from this or another process) will use.
+fn temp_path(path: &Path) -> PathBuf {
+    let n = TEMP_COUNTER.fetch_add(1, Ordering::Relaxed);
+    sibling_path(path, &format!(".{}-{n}{TEMP_SUFFIX}", process::id()))
+}
+
+/// The default [`SessionStorage`], which keeps all the data in memory and can be saved to and
+/// loaded from a single file.
 pub struct Session {
+    // The `peers` and `usernames` are kept in `chats` instead, and only filled in when saving.
     session: Mutex<types::Session>,
+    chats: Mutex<ChatCache>,
+    // The file used by `flush`. The lock is held while writing, so that an older snapshot
+    // cannot replace a newer one.
+    file: Mutex<Option<SessionFile>>,
+}
+
+/// The file a [`Session`] was last loaded from or saved to, and how to save it again.
+struct SessionFile {
+    path: PathBuf,
+    // Whether the previous file should be kept as a backup.
+    backup: bool,
+    format: FileFormat,
+}
+
+enum FileFormat {
+    Plain,
+    Encrypted { passphrase: String },
+}
+
+#[derive(Default)]
+struct ChatCache {
+    chats: HashMap<i64, PackedChat>,
+    usernames: HashMap<String, i64>,
 }
 
 #[allow(clippy::new_without_default)]
@@ -42,50 +104,257 @@ impl Session {
                 dcs: Vec::new(),
                 user: None,
                 state: None,
+                peers: Vec::new(),
+                usernames: Vec::new(),
+                dc_options: Vec::new(),
+                salts: Vec::new(),
             }),
+            chats: Mutex::new(ChatCache::default()),
+            file: Mutex::new(None),
         }
     }
 
     /// Load a previous session instance from a file,
     /// creating one if it doesn't exist
+    ///
+    /// If the file is corrupt and a backup made by [`Session::save_to_file_with_backup`]
+    /// exists, the backup is loaded instead. The corrupt file is moved aside, with the
+    /// `.corrupt` extension appended to its name, so that saving again won't replace the
+    /// backup with it.
     pub fn load_file_or_create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
         let path = path.as_ref();
         if !path.exists() {
-            File::create(path)?;
             let session = Session::new();
             session.save_to_file(path)?;
             Ok(session)
         } else {
-            Self::load_file(path)
+            match Self::load_file(path) {
+                Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                    let backup = sibling_path(path, BACKUP_SUFFIX);
+                    if backup.exists() {
+                        warn!("session file is corrupt ({}); loading backup instead", e);
+                        let session = Self::load_file(backup)?;
+                        fs::rename(path, sibling_path(path, CORRUPT_SUFFIX))?;
+                        session.remember_file(path, true, FileFormat::Plain);
+                        Ok(session)
+                    } else {
+                        Err(e)
+                    }
+                }
+                result => result,
+            }
         }
     }
 
     /// Load a previous session instance from a file.
+    ///
+    /// The file is remembered, so that [`SessionStorage::flush`] saves the session back to it.
     pub fn load_file<P: AsRef<Path>>(path: P) -> io::Result<Self> {
         let mut data = Vec::new();
         File::open(path.as_ref())?.read_to_end(&mut data)?;
 
-        Self::load(&data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        let session =
+            Self::load(&data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        session.remember_file(path.as_ref(), false, FileFormat::Plain);
+        Ok(session)
+    }
+
+    fn remember_file(&self, path: &Path, backup: bool, format: FileFormat) {
+        *self.file.lock().unwrap() = Some(SessionFile {
+            path: path.to_path_buf(),
+            backup,
+            format,
+        });
     }
 
     pub fn load(data: &[u8]) -> Result<Self, Error> {
+        let mut session = match enums::Session::from_bytes(data).map_err(|e| match e {
+            DeserializeError::UnexpectedEof => Error::MalformedData,
+            DeserializeError::UnexpectedConstructor { .. } => Error::UnsupportedVersion,
+        })? {
+            enums::Session::Session(session) => session,
+            enums::Session::V4(session) => types::Session {
+                dcs: session.dcs,
+                user: session.user,
+                state: session.state,
+                peers: session.peers,
+                usernames: session.usernames,
+                dc_options: session.dc_options,
+                salts: Vec::new(),
+            },
+            enums::Session::V3(session) => types::Session {
+                dcs: session.dcs,
+                user: session.user,
+                state: session.state,
+                peers: session.peers,
+                usernames: session.usernames,
+                dc_options: Vec::new(),
+                salts: Vec::new(),
+            },
+            enums::Session::V2(session) => types::Session {
+                dcs: session.dcs,
+                user: session.user,
+                state: session.state,
+                peers: Vec::new(),
+                usernames: Vec::new(),
+                dc_options: Vec::new(),
+                salts: Vec::new(),
+            },
+        };
+
+        let chats = ChatCache {
+            chats: std::mem::take(&mut session.peers)
+                .into_iter()
+                .filter_map(|enums::Peer::Peer(peer)| {
+                    Some((
+                        peer.id,
+                        PackedChat {
+                            ty: PackedType::from_bits(u8::try_from(peer.ty).ok()?)?,
+                            id: peer.id,
+                            access_hash: peer.access_hash,
+                        },
+                    ))
+                })
+                .collect(),
+            usernames: std::mem::take(&mut session.usernames)
+                .into_iter()
+                .map(|enums::Username::Username(username)| (username.name, username.peer_id))
+                .collect(),
+        };
+
         Ok(Self {
-            session: Mutex::new(
-                enums::Session::from_bytes(data)
-                    .map_err(|e| match e {
-                        DeserializeError::UnexpectedEof => Error::MalformedData,
-                        DeserializeError::UnexpectedConstructor { .. } => Error::UnsupportedVersion,
-                    })?
-                    .into(),
-            ),
+            session: Mutex::new(session),
+            chats: Mutex::new(chats),
+            file: Mutex::new(None),
         })
     }
 
-    pub fn signed_in(&self) -> bool {
+    pub fn get_dcs(&self) -> Vec<types::DataCenter> {
+        self.session
+            .lock()
+            .unwrap()
+            .dcs
+            .iter()
+            .map(|enums::DataCenter::Center(dc)| dc.clone())
+            .collect()
+    }
+
+    #[must_use]
+    pub fn save(&self) -> Vec<u8> {
+        let mut session = self.session.lock().unwrap().clone();
+        let chats = self.chats.lock().unwrap();
+        session.peers = chats
+            .chats
+            .values()
+            .map(|chat| {
+                types::Peer {
+                    id: chat.id,
+                    ty: chat.ty as i32,
+                    access_hash: chat.access_hash,
+                }
+                .into()
+            })
+            .collect();
+        session.usernames = chats
+            .usernames
+            .iter()
+            .map(|(name, &peer_id)| {
+                types::Username {
+                    name: name.clone(),
+                    peer_id,
+                }
+                .into()
+            })
+            .collect();
+        enums::Session::Session(session).to_bytes()
+    }
+
+    /// Saves the session to a file.
+    ///
+    /// The session is first written to a temporary file next to it, which then replaces the
+    /// original file, so that a crash mid-write cannot leave a truncated session behind.
+    ///
+    /// The file is remembered, so that [`SessionStorage::flush`] saves the session back to it.
+    pub fn save_to_file<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        self.save_and_remember_file(path.as_ref(), false, FileFormat::Plain)
+    }
+
+    /// Like [`Session::save_to_file`], but the previous file is kept as a backup, with the
+    /// `.bak` extension appended to its name.
+    ///
+    /// [`Session::load_file_or_create`] will fall back to this backup if the file is corrupt.
+    pub fn save_to_file_with_backup<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        self.save_and_remember_file(path.as_ref(), true, FileFormat::Plain)
+    }
+
+    fn save_and_remember_file(
+        &self,
+        path: &Path,
+        backup: bool,
+        format: FileFormat,
+    ) -> io::Result<()> {
+        let mut file = self.file.lock().unwrap();
+        Self::write_file(path, &self.save_as(&format), backup)?;
+        *file = Some(SessionFile {
+            path: path.to_path_buf(),
+            backup,
+            format,
+        });
+        Ok(())
+    }
+
+    fn save_as(&self, format: &FileFormat) -> Vec<u8> {
+        match format {
+            FileFormat::Plain => self.save(),
+            FileFormat::Encrypted { passphrase } => self.save_encrypted(passphrase),
+        }
+    }
+
+    fn write_file(path: &Path, data: &[u8], backup: bool) -> io::Result<()> {
+        let tmp = temp_path(path);
+        let result = Self::replace_file(path, &tmp, data, backup);
+        if result.is_err() {
+            let _ = fs::remove_file(&tmp);
+        }
+        result
+    }
+
+    fn replace_file(path: &Path, tmp: &Path, data: &[u8], backup: bool) -> io::Result<()> {
+        let mut file = File::create(tmp)?;
+        file.write_all(data)?;
+        file.sync_all()?;
+        drop(file);
+
+        if backup && path.exists() {
+            let backup = sibling_path(path, BACKUP_SUFFIX);
+            fs::copy(path, &backup)?;
+            File::open(&backup)?.sync_all()?;
+        }
+
+        fs::rename(tmp, path)?;
+
+        // The rename itself is only durable once the directory is synced. Not all platforms
+        // can open directories, so this is done on a best-effort basis.
+        if let Some(dir) = path.parent() {
+            let dir = if dir.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                dir
+            };
+            if let Ok(dir) = File::open(dir) {
+                let _ = dir.sync_all();
+            }
+        }
+        Ok(())
+    }
+}
+
+impl SessionStorage for Session {
+    fn signed_in(&self) -> bool {
         self.session.lock().unwrap().user.is_some()
     }
 
-    pub fn dc_auth_key(&self, dc_id: i32) -> Option<[u8; 256]> {
+    fn dc_auth_key(&self, dc_id: i32) -> Option<[u8; 256]> {
         self.session
             .lock()
             .unwrap()
@@ -107,7 +376,7 @@ impl Session {
             .next()
     }
 
-    pub fn insert_dc(&self, id: i32, addr: SocketAddr, auth: [u8; 256]) {
+    fn insert_dc(&self, id: i32, addr: SocketAddr, auth: [u8; 256]) {
         let mut session = self.session.lock().unwrap();
         if let Some(pos) = session
             .dcs
@@ -116,6 +385,10 @@ impl Session {
         {
             session.dcs.remove(pos);
         }
+        // Salts belong to the authorization key, so they can't be used with a new one.
+        session
+            .salts
+            .retain(|enums::DcSalts::Salts(salts)| salts.id != id);
 
         let (ip_v4, ip_v6): (Option<&SocketAddrV4>, Option<&SocketAddrV6>) = match &addr {
             SocketAddr::V4(ip_v4) => (Some(ip_v4), None),
@@ -134,12 +407,15 @@ impl Session {
         );
     }
 
-    pub fn set_user(&self, id: i64, dc: i32, bot: bool) {
+    fn set_user(&self, id: i64, dc: i32, bot: bool) {
         self.session.lock().unwrap().user = Some(User { id, dc, bot }.into())
     }
 
-    /// Returns the stored user
-    pub fn get_user(&self) -> Option<User> {
+    fn remove_user(&self) {
+        self.session.lock().unwrap().user = None
+    }
+
+    fn get_user(&self) -> Option<User> {
         self.session
             .lock()
             .unwrap()
@@ -148,38 +424,96 @@ impl Session {
             .map(|enums::User::User(user)| user.clone())
     }
 
-    pub fn get_state(&self) -> Option<UpdateState> {
+    fn get_state(&self) -> Option<UpdateState> {
         let session = self.session.lock().unwrap();
         let enums::UpdateState::State(state) = session.state.clone()?;
         Some(state)
     }
 
-    pub fn set_state(&self, state: UpdateState) {
+    fn set_state(&self, state: UpdateState) {
         self.session.lock().unwrap().state = Some(state.into())
     }
 
-    pub fn get_dcs(&self) -> Vec<types::DataCenter> {
+    fn get_dc_options(&self, dc_id: i32) -> Vec<DcOption> {
         self.session
             .lock()
             .unwrap()
-            .dcs
+            .dc_options
             .iter()
-            .map(|enums::DataCenter::Center(dc)| dc.clone())
+            .map(|enums::DcOption::Option(option)| option)
+            .filter(|option| option.id == dc_id)
+            .cloned()
             .collect()
     }
 
-    #[must_use]
-    pub fn save(&self) -> Vec<u8> {
-        enums::Session::Session(self.session.lock().unwrap().clone()).to_bytes()
+    fn set_dc_options(&self, options: &[DcOption]) {
+        self.session.lock().unwrap().dc_options = options.iter().cloned().map(Into::into).collect();
     }
 
-    /// Saves the session to a file.
-    pub fn save_to_file<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
-        let mut file = OpenOptions::new().write(true).open(path.as_ref())?;
-        file.seek(io::SeekFrom::Start(0))?;
-        file.set_len(0)?;
-        file.write_all(&self.save())?;
-        file.sync_data()
+    fn get_dc_salts(&self, dc_id: i32) -> Vec<FutureSalt> {
+        self.session
+            .lock()
+            .unwrap()
+            .salts
+            .iter()
+            .map(|enums::DcSalts::Salts(salts)| salts)
+            .find(|salts| salts.id == dc_id)
+            .map(|salts| {
+                salts
+                    .salts
+                    .iter()
+                    .map(|enums::FutureSalt::Salt(salt)| salt.clone())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    fn set_dc_salts(&self, dc_id: i32, salts: &[FutureSalt]) {
+        let mut session = self.session.lock().unwrap();
+        session
+            .salts
+            .retain(|enums::DcSalts::Salts(salts)| salts.id != dc_id);
+        session.salts.push(
+            types::DcSalts {
+                id: dc_id,
+                salts: salts.iter().cloned().map(Into::into).collect(),
+            }
+            .into(),
+        );
+    }
+
+    fn get_chat(&self, id: i64) -> Option<PackedChat> {
+        self.chats.lock().unwrap().chats.get(&id).copied()
+    }
+
+    fn cache_chats(&self, chats: &[PackedChat]) {
+        let mut cache = self.chats.lock().unwrap();
+        cache
+            .chats
+            .extend(chats.iter().map(|&chat| (chat.id, chat)));
+    }
+
+    fn get_chat_by_username(&self, username: &str) -> Option<PackedChat> {
+        let cache = self.chats.lock().unwrap();
+        let id = cache.usernames.get(&username.to_lowercase())?;
+        cache.chats.get(id).copied()
+    }
+
+    fn cache_usernames(&self, usernames: &[(&str, i64)]) {
+        let mut cache = self.chats.lock().unwrap();
+        cache.usernames.extend(
+            usernames
+                .iter()
+                .map(|&(username, id)| (username.to_lowercase(), id)),
+        );
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        let file = self.file.lock().unwrap();
+        match &*file {
+            Some(file) => Self::write_file(&file.path, &self.save_as(&file.format), file.backup),
+            None => Ok(()),
+        }
     }
 }
 
@@ -187,6 +521,7 @@ impl Session {
 pub enum Error {
     MalformedData,
     UnsupportedVersion,
+    WrongPassphrase,
 }
 
 impl fmt::Display for Error {
@@ -194,8 +529,220 @@ impl fmt::Display for Error {
         match self {
             Error::MalformedData => write!(f, "malformed data"),
             Error::UnsupportedVersion => write!(f, "unsupported version"),
+            Error::WrongPassphrase => write!(f, "wrong passphrase"),
         }
     }
 }
 
 impl std::error::Error for Error {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn session_persists_chats() {
+        let chat = PackedChat {
+            ty: PackedType::Bot,
+            id: 123,
+            access_hash: Some(456),
+        };
+
+        let session = Session::new();
+        session.cache_chats(&[chat]);
+        session.cache_usernames(&[("GrammersBot", 123)]);
+
+        let session = Session::load(&session.save()).unwrap();
+        assert_eq!(session.get_chat(123), Some(chat));
+        assert_eq!(session.get_chat_by_username("grammersbot"), Some(chat));
+    }
+
+    #[test]
+    fn session_persists_dc_options() {
+        let option = DcOption {
+            id: 4,
+            ipv4: Some(i32::from_le_bytes([149, 154, 167, 92])),
+            ipv6: None,
+            port: 443,
+            media_only: false,
+            cdn: false,
+            r#static: false,
+        };
+
+        let session = Session::new();
+        session.set_dc_options(&[option.clone()]);
+
+        let session = Session::load(&session.save()).unwrap();
+        assert_eq!(session.get_dc_options(4), vec![option]);
+        assert!(session.get_dc_options(2).is_empty());
+    }
+
+    #[test]
+    fn session_persists_dc_salts() {
+        let salt = FutureSalt {
+            valid_since: 1_700_000_000,
+            valid_until: 1_700_001_800,
+            salt: 0x1234_5678_9abc_def0,
+        };
+        let addr = SocketAddr::from(([149, 154, 167, 92], 443));
+
+        let session = Session::new();
+        session.insert_dc(4, addr, [1; 256]);
+        session.set_dc_salts(4, std::slice::from_ref(&salt));
+
+        let session = Session::load(&session.save()).unwrap();
+        assert_eq!(session.get_dc_salts(4), vec![salt]);
+        assert!(session.get_dc_salts(2).is_empty());
+
+        // A new authorization key means the old salts can't be used.
+        session.insert_dc(4, addr, [2; 256]);
+        assert!(session.get_dc_salts(4).is_empty());
+    }
+
+    #[test]
+    fn session_file_falls_back_to_backup() {
+        let path =
+            std::env::temp_dir().join(format!("grammers-backup-{}.session", std::process::id()));
+        let backup = sibling_path(&path, BACKUP_SUFFIX);
+
+        let session = Session::load_file_or_create(&path).unwrap();
+        session.set_user(123, 2, false);
+        session.save_to_file_with_backup(&path).unwrap();
+        session.set_user(456, 2, false);
+        session.save_to_file_with_backup(&path).unwrap();
+        assert!(!has_temp_files(&path));
+
+        fs::write(&path, [1, 2, 3]).unwrap();
+        let session = Session::load_file_or_create(&path).unwrap();
+        assert_eq!(session.get_user().map(|user| user.id), Some(123));
+
+        // Saving again must not replace the backup with the corrupt file.
+        let corrupt = sibling_path(&path, CORRUPT_SUFFIX);
+        assert_eq!(fs::read(&corrupt).unwrap(), [1, 2, 3]);
+        session.flush().unwrap();
+        let backup_session = Session::load_file(&backup).unwrap();
+        assert_eq!(backup_session.get_user().map(|user| user.id), Some(123));
+        let session = Session::load_file(&path).unwrap();
+        assert_eq!(session.get_user().map(|user| user.id), Some(123));
+
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&backup).unwrap();
+        fs::remove_file(&corrupt).unwrap();
+    }
+
+    fn has_temp_files(path: &Path) -> bool {
+        let name = path.file_name().unwrap().to_string_lossy().into_owned();
+        fs::read_dir(path.parent().unwrap()).unwrap().any(|entry| {
+            let entry = entry.unwrap().file_name();
+            let entry = entry.to_string_lossy();
+            entry.starts_with(&name) && entry.ends_with(TEMP_SUFFIX)
+        })
+    }
+
+    #[test]
+    fn concurrent_saves_to_the_same_file_succeed() {
+        let path =
+            std::env::temp_dir().join(format!("grammers-concurrent-{}.session", process::id()));
+
+        let threads = (0..8)
+            .map(|i| {
+                let path = path.clone();
+                std::thread::spawn(move || {
+                    let session = Session::new();
+                    session.set_user(i, 2, false);
+                    for _ in 0..16 {
+                        session.save_to_file(&path).unwrap();
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        assert!(Session::load_file(&path).unwrap().get_user().is_some());
+        assert!(!has_temp_files(&path));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn session_flushes_to_loaded_file() {
+        let path =
+            std::env::temp_dir().join(format!("grammers-flush-{}.session", std::process::id()));
+
+        Session::new().flush().unwrap();
+        let session = Session::load_file_or_create(&path).unwrap();
+        session.set_user(123, 2, false);
+        session.flush().unwrap();
+
+        let session = Session::load_file(&path).unwrap();
+        assert_eq!(session.get_user().map(|user| user.id), Some(123));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn session_migrates_from_v2() {
+        let data = enums::Session::V2(types::SessionV2 {
+            dcs: vec![types::DataCenter {
+                id: 2,
+                ipv4: Some(i32::from_le_bytes([127, 0, 0, 1])),
+                ipv6: None,
+                port: 443,
+                auth: Some(vec![7; 256]),
+            }
+            .into()],
+            user: Some(
+                User {
+                    id: 123,
+                    dc: 2,
+                    bot: false,
+                }
+                .into(),
+            ),
+            state: None,
+        })
+        .to_bytes();
+
+        let session = Session::load(&data).unwrap();
+        assert_eq!(session.dc_auth_key(2), Some([7; 256]));
+        assert_eq!(session.get_user().map(|user| user.id), Some(123));
+        assert_eq!(session.get_chat(123), None);
+
+        let session = Session::load(&session.save()).unwrap();
+        assert_eq!(session.dc_auth_key(2), Some([7; 256]));
+    }
+
+    #[test]
+    fn session_migrates_from_v4() {
+        let option = DcOption {
+            id: 2,
+            ipv4: Some(i32::from_le_bytes([127, 0, 0, 1])),
+            ipv6: None,
+            port: 443,
+            media_only: false,
+            cdn: false,
+            r#static: false,
+        };
+        let data = enums::Session::V4(types::SessionV4 {
+            dcs: vec![types::DataCenter {
+                id: 2,
+                ipv4: Some(i32::from_le_bytes([127, 0, 0, 1])),
+                ipv6: None,
+                port: 443,
+                auth: Some(vec![7; 256]),
+            }
+            .into()],
+            user: None,
+            state: None,
+            peers: Vec::new(),
+            usernames: Vec::new(),
+            dc_options: vec![option.clone().into()],
+        })
+        .to_bytes();
+
+        let session = Session::load(&data).unwrap();
+        assert_eq!(session.dc_auth_key(2), Some([7; 256]));
+        assert_eq!(session.get_dc_options(2), vec![option]);
+        assert!(session.get_dc_salts(2).is_empty());
+    }
+}
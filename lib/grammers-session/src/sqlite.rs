@@ -0,0 +1,547 @@
+// Copyright 2020 - developers of the `grammers` project.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+use crate::generated::{enums, types};
+use crate::{DcOption, FutureSalt, PackedChat, SessionStorage, UpdateState, User};
+use log::error;
+use rusqlite::{params, Connection, OptionalExtension};
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Mutex;
+
+const SCHEMA_VERSION: i64 = 3;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS dc (
+        id INTEGER PRIMARY KEY,
+        ipv4 INTEGER,
+        ipv6 BLOB,
+        port INTEGER NOT NULL,
+        auth BLOB
+    );
+    CREATE TABLE IF NOT EXISTS user (
+        rowid INTEGER PRIMARY KEY CHECK (rowid = 0),
+        id INTEGER NOT NULL,
+        dc INTEGER NOT NULL,
+        bot INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS update_state (
+        rowid INTEGER PRIMARY KEY CHECK (rowid = 0),
+        pts INTEGER NOT NULL,
+        qts INTEGER NOT NULL,
+        date INTEGER NOT NULL,
+        seq INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS channel_state (
+        channel_id INTEGER PRIMARY KEY,
+        pts INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS chat (
+        id INTEGER PRIMARY KEY,
+        packed BLOB NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS username (
+        username TEXT PRIMARY KEY,
+        chat_id INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS dc_option (
+        dc_id INTEGER NOT NULL,
+        ipv4 INTEGER,
+        ipv6 BLOB,
+        port INTEGER NOT NULL,
+        media_only INTEGER NOT NULL,
+        cdn INTEGER NOT NULL,
+        static INTEGER NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS dc_option_dc_id ON dc_option (dc_id);
+    CREATE TABLE IF NOT EXISTS salt (
+        dc_id INTEGER NOT NULL,
+        valid_since INTEGER NOT NULL,
+        valid_until INTEGER NOT NULL,
+        salt INTEGER NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS salt_dc_id ON salt (dc_id);
+";
+
+/// Session storage backed by a SQLite database.
+///
+/// Unlike [`Session`], there is no need to save this storage manually: every change is written
+/// to the database as soon as it happens, within a transaction, and only the rows that changed
+/// are touched. The database can also hold a large amount of chats, which can be looked up by
+/// their identifier or username without loading all of them into memory.
+///
+/// Because the storage methods cannot fail, database errors are logged and otherwise treated as
+/// if the data was not present.
+///
+/// [`Session`]: crate::Session
+pub struct SqliteSession {
+    conn: Mutex<Connection>,
+}
+
+impl SqliteSession {
+    /// Open the session database at the given path, creating it if it doesn't exist.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::init(Connection::open(path).map_err(io::Error::other)?)
+    }
+
+    /// Open a session database which only lives in memory.
+    pub fn open_in_memory() -> io::Result<Self> {
+        Self::init(Connection::open_in_memory().map_err(io::Error::other)?)
+    }
+
+    fn init(conn: Connection) -> io::Result<Self> {
+        // WAL mode keeps the database consistent even if the process dies mid-write,
+        // and lets other processes read the session while it is in use.
+        conn.pragma_update(None, "journal_mode", "WAL")
+            .map_err(io::Error::other)?;
+        conn.pragma_update(None, "synchronous", "FULL")
+            .map_err(io::Error::other)?;
+
+        let version: i64 = conn
+            .pragma_query_value(None, "user_version", |row| row.get(0))
+            .map_err(io::Error::other)?;
+        if version > SCHEMA_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                crate::Error::UnsupportedVersion,
+            ));
+        }
+
+        conn.execute_batch(SCHEMA).map_err(io::Error::other)?;
+        conn.pragma_update(None, "user_version", SCHEMA_VERSION)
+            .map_err(io::Error::other)?;
+
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    fn read<T>(
+        &self,
+        what: &str,
+        f: impl FnOnce(&Connection) -> rusqlite::Result<Option<T>>,
+    ) -> Option<T> {
+        match f(&self.conn.lock().unwrap()) {
+            Ok(value) => value,
+            Err(e) => {
+                error!("failed to read {} from the session database: {}", what, e);
+                None
+            }
+        }
+    }
+
+    fn write(&self, what: &str, f: impl FnOnce(&rusqlite::Transaction) -> rusqlite::Result<()>) {
+        let mut conn = self.conn.lock().unwrap();
+        let result = conn.transaction().and_then(|tx| {
+            f(&tx)?;
+            tx.commit()
+        });
+        if let Err(e) = result {
+            error!("failed to write {} to the session database: {}", what, e);
+        }
+    }
+
+    /// Returns all the datacenters stored in the session.
+    pub fn get_dcs(&self) -> Vec<types::DataCenter> {
+        self.read("datacenters", |conn| {
+            let mut stmt = conn.prepare("SELECT id, ipv4, ipv6, port, auth FROM dc")?;
+            let dcs = stmt
+                .query_map([], |row| {
+                    Ok(types::DataCenter {
+                        id: row.get(0)?,
+                        ipv4: row.get(1)?,
+                        ipv6: row
+                            .get::<_, Option<Vec<u8>>>(2)?
+                            .and_then(|ip| ip.try_into().ok()),
+                        port: row.get(3)?,
+                        auth: row.get(4)?,
+                    })
+                })?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            Ok(Some(dcs))
+        })
+        .unwrap_or_default()
+    }
+}
+
+impl SessionStorage for SqliteSession {
+    fn dc_auth_key(&self, dc_id: i32) -> Option<[u8; 256]> {
+        self.read("auth key", |conn| {
+            conn.query_row("SELECT auth FROM dc WHERE id = ?1", [dc_id], |row| {
+                row.get::<_, Option<Vec<u8>>>(0)
+            })
+            .optional()
+            .map(|auth| auth.flatten().and_then(|auth| auth.try_into().ok()))
+        })
+    }
+
+    fn insert_dc(&self, id: i32, addr: SocketAddr, auth: [u8; 256]) {
+        let (ipv4, ipv6) = match addr {
+            SocketAddr::V4(addr) => (Some(i32::from_le_bytes(addr.ip().octets())), None),
+            SocketAddr::V6(addr) => (None, Some(addr.ip().octets())),
+        };
+        self.write("datacenter", |tx| {
+            tx.execute(
+                "INSERT OR REPLACE INTO dc (id, ipv4, ipv6, port, auth) VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![id, ipv4, ipv6, addr.port(), &auth[..]],
+            )?;
+            // Salts belong to the authorization key, so they can't be used with a new one.
+            tx.execute("DELETE FROM salt WHERE dc_id = ?1", [id])
+                .map(drop)
+        });
+    }
+
+    fn get_user(&self) -> Option<User> {
+        self.read("user", |conn| {
+            conn.query_row("SELECT id, dc, bot FROM user", [], |row| {
+                Ok(User {
+                    id: row.get(0)?,
+                    dc: row.get(1)?,
+                    bot: row.get(2)?,
+                })
+            })
+            .optional()
+        })
+    }
+
+    fn set_user(&self, id: i64, dc: i32, bot: bool) {
+        self.write("user", |tx| {
+            tx.execute(
+                "INSERT OR REPLACE INTO user (rowid, id, dc, bot) VALUES (0, ?1, ?2, ?3)",
+                params![id, dc, bot],
+            )
+            .map(drop)
+        });
+    }
+
+    fn remove_user(&self) {
+        self.write("user", |tx| tx.execute("DELETE FROM user", []).map(drop));
+    }
+
+    fn get_state(&self) -> Option<UpdateState> {
+        self.read("update state", |conn| {
+            let state = conn
+                .query_row("SELECT pts, qts, date, seq FROM update_state", [], |row| {
+                    Ok(UpdateState {
+                        pts: row.get(0)?,
+                        qts: row.get(1)?,
+                        date: row.get(2)?,
+                        seq: row.get(3)?,
+                        channels: Vec::new(),
+                    })
+                })
+                .optional()?;
+
+            let Some(mut state) = state else {
+                return Ok(None);
+            };
+
+            let mut stmt = conn.prepare("SELECT channel_id, pts FROM channel_state")?;
+            state.channels = stmt
+                .query_map([], |row| {
+                    Ok(types::ChannelState {
+                        channel_id: row.get(0)?,
+                        pts: row.get(1)?,
+                    }
+                    .into())
+                })?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+
+            Ok(Some(state))
+        })
+    }
+
+    fn set_state(&self, state: UpdateState) {
+        self.write("update state", |tx| {
+            tx.execute(
+                "INSERT OR REPLACE INTO update_state (rowid, pts, qts, date, seq) VALUES (0, ?1, ?2, ?3, ?4)",
+                params![state.pts, state.qts, state.date, state.seq],
+            )?;
+
+            // Only touch the channels whose state actually changed.
+            let mut upsert = tx.prepare_cached(
+                "INSERT INTO channel_state (channel_id, pts) VALUES (?1, ?2)
+                 ON CONFLICT (channel_id) DO UPDATE SET pts = excluded.pts WHERE pts != excluded.pts",
+            )?;
+            for enums::ChannelState::State(channel) in &state.channels {
+                upsert.execute(params![channel.channel_id, channel.pts])?;
+            }
+
+            let mut stale = Vec::new();
+            let mut stmt = tx.prepare_cached("SELECT channel_id FROM channel_state")?;
+            for id in stmt.query_map([], |row| row.get::<_, i64>(0))? {
+                let id = id?;
+                if !state
+                    .channels
+                    .iter()
+                    .any(|enums::ChannelState::State(channel)| channel.channel_id == id)
+                {
+                    stale.push(id);
+                }
+            }
+
+            let mut delete =
+                tx.prepare_cached("DELETE FROM channel_state WHERE channel_id = ?1")?;
+            for id in stale {
+                delete.execute([id])?;
+            }
+
+            Ok(())
+        });
+    }
+
+    fn get_dc_options(&self, dc_id: i32) -> Vec<DcOption> {
+        self.read("datacenter options", |conn| {
+            let mut stmt = conn.prepare_cached(
+                "SELECT ipv4, ipv6, port, media_only, cdn, static FROM dc_option WHERE dc_id = ?1",
+            )?;
+            let options = stmt
+                .query_map([dc_id], |row| {
+                    Ok(DcOption {
+                        id: dc_id,
+                        ipv4: row.get(0)?,
+                        ipv6: row
+                            .get::<_, Option<Vec<u8>>>(1)?
+                            .and_then(|ip| ip.try_into().ok()),
+                        port: row.get(2)?,
+                        media_only: row.get(3)?,
+                        cdn: row.get(4)?,
+                        r#static: row.get(5)?,
+                    })
+                })?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            Ok(Some(options))
+        })
+        .unwrap_or_default()
+    }
+
+    fn set_dc_options(&self, options: &[DcOption]) {
+        self.write("datacenter options", |tx| {
+            tx.execute("DELETE FROM dc_option", [])?;
+            let mut stmt = tx.prepare_cached(
+                "INSERT INTO dc_option (dc_id, ipv4, ipv6, port, media_only, cdn, static)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            )?;
+            for option in options {
+                stmt.execute(params![
+                    option.id,
+                    option.ipv4,
+                    option.ipv6,
+                    option.port,
+                    option.media_only,
+                    option.cdn,
+                    option.r#static,
+                ])?;
+            }
+            Ok(())
+        });
+    }
+
+    fn get_dc_salts(&self, dc_id: i32) -> Vec<FutureSalt> {
+        self.read("server salts", |conn| {
+            let mut stmt = conn.prepare_cached(
+                "SELECT valid_since, valid_until, salt FROM salt WHERE dc_id = ?1",
+            )?;
+            let salts = stmt
+                .query_map([dc_id], |row| {
+                    Ok(FutureSalt {
+                        valid_since: row.get(0)?,
+                        valid_until: row.get(1)?,
+                        salt: row.get(2)?,
+                    })
+                })?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            Ok(Some(salts))
+        })
+        .unwrap_or_default()
+    }
+
+    fn set_dc_salts(&self, dc_id: i32, salts: &[FutureSalt]) {
+        self.write("server salts", |tx| {
+            tx.execute("DELETE FROM salt WHERE dc_id = ?1", [dc_id])?;
+            let mut stmt = tx.prepare_cached(
+                "INSERT INTO salt (dc_id, valid_since, valid_until, salt) VALUES (?1, ?2, ?3, ?4)",
+            )?;
+            for salt in salts {
+                stmt.execute(params![
+                    dc_id,
+                    salt.valid_since,
+                    salt.valid_until,
+                    salt.salt
+                ])?;
+            }
+            Ok(())
+        });
+    }
+
+    fn get_chat(&self, id: i64) -> Option<PackedChat> {
+        self.read("chat", |conn| {
+            conn.query_row("SELECT packed FROM chat WHERE id = ?1", [id], |row| {
+                row.get::<_, Vec<u8>>(0)
+            })
+            .optional()
+            .map(|packed| packed.and_then(|packed| PackedChat::from_bytes(&packed).ok()))
+        })
+    }
+
+    fn cache_chats(&self, chats: &[PackedChat]) {
+        if chats.is_empty() {
+            return;
+        }
+        self.write("chats", |tx| {
+            let mut stmt =
+                tx.prepare_cached("INSERT OR REPLACE INTO chat (id, packed) VALUES (?1, ?2)")?;
+            for chat in chats {
+                stmt.execute(params![chat.id, &chat.to_bytes()[..]])?;
+            }
+            Ok(())
+        });
+    }
+
+    fn get_chat_by_username(&self, username: &str) -> Option<PackedChat> {
+        self.read("chat", |conn| {
+            conn.query_row(
+                "SELECT chat.packed FROM username JOIN chat ON chat.id = username.chat_id
+                 WHERE username.username = ?1",
+                [username.to_lowercase()],
+                |row| row.get::<_, Vec<u8>>(0),
+            )
+            .optional()
+            .map(|packed| packed.and_then(|packed| PackedChat::from_bytes(&packed).ok()))
+        })
+    }
+
+    fn cache_usernames(&self, usernames: &[(&str, i64)]) {
+        if usernames.is_empty() {
+            return;
+        }
+        self.write("usernames", |tx| {
+            let mut stmt = tx.prepare_cached(
+                "INSERT OR REPLACE INTO username (username, chat_id) VALUES (?1, ?2)",
+            )?;
+            for &(username, id) in usernames {
+                stmt.execute(params![username.to_lowercase(), id])?;
+            }
+            Ok(())
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PackedType;
+
+    fn state(channels: &[(i64, i32)]) -> UpdateState {
+        UpdateState {
+            pts: 1,
+            qts: 2,
+            date: 3,
+            seq: 4,
+            channels: channels
+                .iter()
+                .map(|&(channel_id, pts)| types::ChannelState { channel_id, pts }.into())
+                .collect(),
+        }
+    }
+
+    fn channels(state: UpdateState) -> Vec<(i64, i32)> {
+        let mut channels = state
+            .channels
+            .into_iter()
+            .map(|enums::ChannelState::State(c)| (c.channel_id, c.pts))
+            .collect::<Vec<_>>();
+        channels.sort();
+        channels
+    }
+
+    #[test]
+    fn sqlite_session_roundtrip() {
+        let session = SqliteSession::open_in_memory().unwrap();
+        assert!(!session.signed_in());
+        assert!(session.get_state().is_none());
+
+        session.insert_dc(2, ([127, 0, 0, 1], 443).into(), [7; 256]);
+        session.insert_dc(2, ([127, 0, 0, 2], 80).into(), [8; 256]);
+        session.set_user(123, 2, true);
+        assert_eq!(session.dc_auth_key(2), Some([8; 256]));
+        assert_eq!(session.dc_auth_key(4), None);
+        assert_eq!(session.get_dcs().len(), 1);
+        assert_eq!(session.get_dcs()[0].port, 80);
+        assert_eq!(
+            session.get_user().map(|u| (u.id, u.dc, u.bot)),
+            Some((123, 2, true))
+        );
+        session.remove_user();
+        assert!(!session.signed_in());
+        session.set_user(123, 2, true);
+
+        let option = DcOption {
+            id: 2,
+            ipv4: None,
+            ipv6: Some([1; 16]),
+            port: 443,
+            media_only: true,
+            cdn: false,
+            r#static: true,
+        };
+        session.set_dc_options(&[option.clone()]);
+        assert_eq!(session.get_dc_options(2), vec![option]);
+        assert!(session.get_dc_options(4).is_empty());
+
+        let salt = FutureSalt {
+            valid_since: 1_700_000_000,
+            valid_until: 1_700_001_800,
+            salt: -42,
+        };
+        session.set_dc_salts(2, std::slice::from_ref(&salt));
+        session.set_dc_salts(2, std::slice::from_ref(&salt));
+        assert_eq!(session.get_dc_salts(2), vec![salt]);
+        assert!(session.get_dc_salts(4).is_empty());
+        session.insert_dc(2, ([127, 0, 0, 2], 80).into(), [8; 256]);
+        assert!(session.get_dc_salts(2).is_empty());
+
+        session.set_state(state(&[(10, 100), (20, 200)]));
+        session.set_state(state(&[(20, 201), (30, 300)]));
+        assert_eq!(
+            channels(session.get_state().unwrap()),
+            vec![(20, 201), (30, 300)]
+        );
+
+        let chat = PackedChat {
+            ty: PackedType::Broadcast,
+            id: 456,
+            access_hash: Some(789),
+        };
+        session.cache_chats(&[chat]);
+        session.cache_usernames(&[("Grammers", 456)]);
+        assert_eq!(session.get_chat(456), Some(chat));
+        assert_eq!(session.get_chat(457), None);
+        assert_eq!(session.get_chat_by_username("GRAMMERS"), Some(chat));
+        assert_eq!(session.get_chat_by_username("telegram"), None);
+    }
+
+    #[test]
+    fn sqlite_session_persists() {
+        let path =
+            std::env::temp_dir().join(format!("grammers-sqlite-session-{}.db", std::process::id()));
+        {
+            let session = SqliteSession::open(&path).unwrap();
+            session.insert_dc(4, ([127, 0, 0, 1], 443).into(), [1; 256]);
+            session.set_state(state(&[(10, 100)]));
+        }
+        {
+            let session = SqliteSession::open(&path).unwrap();
+            assert_eq!(session.dc_auth_key(4), Some([1; 256]));
+            assert_eq!(channels(session.get_state().unwrap()), vec![(10, 100)]);
+        }
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+    }
+}
@@ -0,0 +1,248 @@
+// Copyright 2020 - developers of the `grammers` project.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+use crate::{DcOption, FutureSalt, PackedChat, UpdateState, User};
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+
+/// The data a client needs to persist in order to resume its work after a restart.
+///
+/// Implementations are free to store this data however they see fit (a single file, a database,
+/// a remote service...), as long as they can be shared between threads. Every method takes
+/// `&self`, so implementations are expected to use interior mutability.
+///
+/// [`Session`] is the default implementation, which keeps everything in memory and can be saved
+/// to and loaded from a single file. [`MemorySession`] never persists anything, and is mostly
+/// useful for tests.
+///
+/// [`Session`]: crate::Session
+pub trait SessionStorage: Send + Sync {
+    /// Returns the authorization key used to connect to the given datacenter, if any.
+    fn dc_auth_key(&self, dc_id: i32) -> Option<[u8; 256]>;
+
+    /// Stores the address and authorization key used for a datacenter, replacing any previous
+    /// data for the same datacenter (including its salts).
+    fn insert_dc(&self, id: i32, addr: SocketAddr, auth: [u8; 256]);
+
+    /// Returns the stored user.
+    fn get_user(&self) -> Option<User>;
+
+    /// Stores the logged-in user, along with the datacenter where it lives.
+    fn set_user(&self, id: i64, dc: i32, bot: bool);
+
+    /// Removes the stored user, meaning the session is no longer signed in.
+    fn remove_user(&self);
+
+    /// Returns the stored update state.
+    fn get_state(&self) -> Option<UpdateState>;
+
+    /// Stores the update state, replacing any previous state.
+    fn set_state(&self, state: UpdateState);
+
+    /// Returns the addresses known for the given datacenter.
+    fn get_dc_options(&self, dc_id: i32) -> Vec<DcOption>;
+
+    /// Stores the addresses of all the datacenters, as returned by Telegram's configuration,
+    /// replacing any previous addresses.
+    fn set_dc_options(&self, options: &[DcOption]);
+
+    /// Returns the server salts known for the authorization key of the given datacenter.
+    ///
+    /// Some of the salts may have expired already.
+    fn get_dc_salts(&self, dc_id: i32) -> Vec<FutureSalt>;
+
+    /// Stores the server salts for the authorization key of the given datacenter, replacing any
+    /// previous salts for the same datacenter.
+    fn set_dc_salts(&self, dc_id: i32, salts: &[FutureSalt]);
+
+    /// Returns the packed chat with its access hash for the given peer identifier, if known.
+    fn get_chat(&self, id: i64) -> Option<PackedChat>;
+
+    /// Stores the access hashes of the given chats, replacing any previous value.
+    fn cache_chats(&self, chats: &[PackedChat]);
+
+    /// Returns the packed chat owning the given username, if known.
+    ///
+    /// Usernames are case-insensitive, so the lookup must be too.
+    fn get_chat_by_username(&self, username: &str) -> Option<PackedChat>;
+
+    /// Stores which peer identifier owns each username, replacing any previous owner.
+    fn cache_usernames(&self, usernames: &[(&str, i64)]);
+
+    /// Returns `true` if a user is stored, meaning the session was used to sign in.
+    fn signed_in(&self) -> bool {
+        self.get_user().is_some()
+    }
+
+    /// Persists any change which has not been written yet.
+    ///
+    /// Storages which persist every change as it happens (or never persist anything) need not
+    /// do anything, which is the default.
+    fn flush(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct MemoryData {
+    dcs: HashMap<i32, (SocketAddr, [u8; 256])>,
+    user: Option<User>,
+    state: Option<UpdateState>,
+    dc_options: Vec<DcOption>,
+    salts: HashMap<i32, Vec<FutureSalt>>,
+    chats: HashMap<i64, PackedChat>,
+    usernames: HashMap<String, i64>,
+}
+
+/// Session storage which only lives in memory and is lost once dropped.
+#[derive(Default)]
+pub struct MemorySession {
+    data: Mutex<MemoryData>,
+}
+
+impl MemorySession {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl SessionStorage for MemorySession {
+    fn dc_auth_key(&self, dc_id: i32) -> Option<[u8; 256]> {
+        self.data
+            .lock()
+            .unwrap()
+            .dcs
+            .get(&dc_id)
+            .map(|&(_, auth)| auth)
+    }
+
+    fn insert_dc(&self, id: i32, addr: SocketAddr, auth: [u8; 256]) {
+        let mut data = self.data.lock().unwrap();
+        data.dcs.insert(id, (addr, auth));
+        data.salts.remove(&id);
+    }
+
+    fn get_user(&self) -> Option<User> {
+        self.data.lock().unwrap().user.clone()
+    }
+
+    fn set_user(&self, id: i64, dc: i32, bot: bool) {
+        self.data.lock().unwrap().user = Some(User { id, dc, bot });
+    }
+
+    fn remove_user(&self) {
+        self.data.lock().unwrap().user = None;
+    }
+
+    fn get_state(&self) -> Option<UpdateState> {
+        self.data.lock().unwrap().state.clone()
+    }
+
+    fn set_state(&self, state: UpdateState) {
+        self.data.lock().unwrap().state = Some(state);
+    }
+
+    fn get_dc_options(&self, dc_id: i32) -> Vec<DcOption> {
+        let data = self.data.lock().unwrap();
+        data.dc_options
+            .iter()
+            .filter(|option| option.id == dc_id)
+            .cloned()
+            .collect()
+    }
+
+    fn set_dc_options(&self, options: &[DcOption]) {
+        self.data.lock().unwrap().dc_options = options.to_vec();
+    }
+
+    fn get_dc_salts(&self, dc_id: i32) -> Vec<FutureSalt> {
+        let data = self.data.lock().unwrap();
+        data.salts.get(&dc_id).cloned().unwrap_or_default()
+    }
+
+    fn set_dc_salts(&self, dc_id: i32, salts: &[FutureSalt]) {
+        self.data
+            .lock()
+            .unwrap()
+            .salts
+            .insert(dc_id, salts.to_vec());
+    }
+
+    fn get_chat(&self, id: i64) -> Option<PackedChat> {
+        self.data.lock().unwrap().chats.get(&id).copied()
+    }
+
+    fn cache_chats(&self, chats: &[PackedChat]) {
+        let mut data = self.data.lock().unwrap();
+        data.chats.extend(chats.iter().map(|&chat| (chat.id, chat)));
+    }
+
+    fn get_chat_by_username(&self, username: &str) -> Option<PackedChat> {
+        let data = self.data.lock().unwrap();
+        let id = data.usernames.get(&username.to_lowercase())?;
+        data.chats.get(id).copied()
+    }
+
+    fn cache_usernames(&self, usernames: &[(&str, i64)]) {
+        let mut data = self.data.lock().unwrap();
+        data.usernames.extend(
+            usernames
+                .iter()
+                .map(|&(username, id)| (username.to_lowercase(), id)),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PackedType;
+
+    #[test]
+    fn memory_session_roundtrip() {
+        let session = MemorySession::new();
+        assert!(!session.signed_in());
+        assert_eq!(session.dc_auth_key(2), None);
+
+        session.insert_dc(2, ([127, 0, 0, 1], 443).into(), [7; 256]);
+        session.set_user(123, 2, false);
+        assert!(session.signed_in());
+        assert_eq!(session.dc_auth_key(2), Some([7; 256]));
+        assert_eq!(
+            session.get_user().map(|u| (u.id, u.dc, u.bot)),
+            Some((123, 2, false))
+        );
+        session.remove_user();
+        assert!(!session.signed_in());
+        session.set_user(123, 2, false);
+
+        let chat = PackedChat {
+            ty: PackedType::Megagroup,
+            id: 456,
+            access_hash: Some(789),
+        };
+        session.cache_chats(&[chat]);
+        assert_eq!(session.get_chat(456), Some(chat));
+        assert_eq!(session.get_chat(457), None);
+
+        session.cache_usernames(&[("Grammers", 456)]);
+        assert_eq!(session.get_chat_by_username("grammers"), Some(chat));
+        assert_eq!(session.get_chat_by_username("telegram"), None);
+
+        let salt = FutureSalt {
+            valid_since: 1_700_000_000,
+            valid_until: 1_700_001_800,
+            salt: 42,
+        };
+        session.set_dc_salts(2, std::slice::from_ref(&salt));
+        assert_eq!(session.get_dc_salts(2), vec![salt]);
+        session.insert_dc(2, ([127, 0, 0, 1], 443).into(), [8; 256]);
+        assert!(session.get_dc_salts(2).is_empty());
+    }
+}
@@ -0,0 +1,302 @@
+// Copyright 2020 - developers of the `grammers` project.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Conversion from and to the string sessions used by other Telegram libraries.
+use crate::generated::{enums, types};
+use crate::{Error, Session, SessionStorage};
+use base64::alphabet::URL_SAFE;
+use base64::engine::{DecodePaddingMode, GeneralPurpose, GeneralPurposeConfig};
+use base64::Engine;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+/// URL-safe base64 which can decode strings with or without padding, and encodes without it.
+const BASE64: GeneralPurpose = GeneralPurpose::new(
+    &URL_SAFE,
+    GeneralPurposeConfig::new()
+        .with_encode_padding(false)
+        .with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+
+/// Like [`BASE64`], but encodes with padding, which Telethon requires.
+const BASE64_PADDED: GeneralPurpose = GeneralPurpose::new(
+    &URL_SAFE,
+    GeneralPurposeConfig::new()
+        .with_encode_padding(true)
+        .with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+
+/// Version prefix used by Telethon's `StringSession`.
+const TELETHON_VERSION: char = '1';
+
+/// Production addresses of the datacenters, where the index represents the datacenter ID.
+///
+/// Pyrogram only stores the datacenter ID, so the address needs to be known beforehand.
+const DC_ADDRESSES: [(Ipv4Addr, u16); 6] = [
+    (Ipv4Addr::new(0, 0, 0, 0), 0),
+    (Ipv4Addr::new(149, 154, 175, 53), 443),
+    (Ipv4Addr::new(149, 154, 167, 51), 443),
+    (Ipv4Addr::new(149, 154, 175, 100), 443),
+    (Ipv4Addr::new(149, 154, 167, 92), 443),
+    (Ipv4Addr::new(91, 108, 56, 190), 443),
+];
+
+/// Takes the next `N` bytes from `buf`, advancing it past them.
+fn take<const N: usize>(buf: &mut &[u8]) -> Result<[u8; N], Error> {
+    if buf.len() < N {
+        return Err(Error::MalformedData);
+    }
+    let (head, tail) = buf.split_at(N);
+    *buf = tail;
+    Ok(head.try_into().unwrap())
+}
+
+impl Session {
+    /// Returns the identifier, address and authorization key of the home datacenter.
+    ///
+    /// The home datacenter is that of the logged-in user, or the first datacenter with an
+    /// authorization key if the user is unknown.
+    fn home_dc(&self) -> Option<(i32, SocketAddr, [u8; 256])> {
+        let user_dc = self.get_user().map(|user| user.dc);
+        self.get_dcs()
+            .into_iter()
+            .filter(|dc| user_dc.is_none_or(|id| id == dc.id))
+            .find_map(|dc| {
+                let ip = match (dc.ipv4, dc.ipv6) {
+                    (_, Some(ipv6)) => IpAddr::V6(Ipv6Addr::from(ipv6)),
+                    (Some(ipv4), None) => IpAddr::V4(Ipv4Addr::from(ipv4.to_le_bytes())),
+                    (None, None) => return None,
+                };
+                let auth = dc.auth?.try_into().ok()?;
+                Some((dc.id, SocketAddr::new(ip, dc.port as u16), auth))
+            })
+    }
+
+    /// Load a session from a Telethon `StringSession`.
+    ///
+    /// Telethon does not store the logged-in user, so the returned session only contains the
+    /// datacenter and its authorization key. [`SessionStorage::set_user`] should be called once
+    /// the user is known, or the client will connect to the default datacenter.
+    pub fn from_telethon_string(string: &str) -> Result<Self, Error> {
+        let mut chars = string.chars();
+        if chars.next() != Some(TELETHON_VERSION) {
+            return Err(Error::UnsupportedVersion);
+        }
+        let data = BASE64
+            .decode(chars.as_str())
+            .map_err(|_| Error::MalformedData)?;
+
+        let mut buf = &data[..];
+        let [dc_id] = take::<1>(&mut buf)?;
+        let ip = match buf.len() {
+            262 => IpAddr::V4(Ipv4Addr::from(take::<4>(&mut buf)?)),
+            274 => IpAddr::V6(Ipv6Addr::from(take::<16>(&mut buf)?)),
+            _ => return Err(Error::MalformedData),
+        };
+        let port = u16::from_be_bytes(take(&mut buf)?);
+        let auth = take::<256>(&mut buf)?;
+
+        let session = Session::new();
+        session.insert_dc(dc_id as i32, SocketAddr::new(ip, port), auth);
+        Ok(session)
+    }
+
+    /// Export the home datacenter and its authorization key as a Telethon `StringSession`.
+    ///
+    /// Returns `None` if the session has no authorization key.
+    pub fn to_telethon_string(&self) -> Option<String> {
+        let (dc_id, addr, auth) = self.home_dc()?;
+        let mut data = Vec::with_capacity(275);
+        data.push(dc_id as u8);
+        match addr.ip() {
+            IpAddr::V4(ip) => data.extend(ip.octets()),
+            IpAddr::V6(ip) => data.extend(ip.octets()),
+        }
+        data.extend(addr.port().to_be_bytes());
+        data.extend(auth);
+
+        let mut string = String::from(TELETHON_VERSION);
+        BASE64_PADDED.encode_string(data, &mut string);
+        Some(string)
+    }
+
+    /// Load a session from a Pyrogram session string.
+    ///
+    /// All of the formats used by Pyrogram are supported. Sessions for the test servers are not.
+    pub fn from_pyrogram_string(string: &str) -> Result<Self, Error> {
+        let data = BASE64.decode(string).map_err(|_| Error::MalformedData)?;
+
+        let mut buf = &data[..];
+        let (dc_id, test_mode, auth, user_id, bot) = match data.len() {
+            // Before user IDs were 64 bits: `>B?256sI?`.
+            263 => (
+                take::<1>(&mut buf)?[0],
+                take::<1>(&mut buf)?[0],
+                take::<256>(&mut buf)?,
+                u32::from_be_bytes(take(&mut buf)?) as i64,
+                take::<1>(&mut buf)?[0],
+            ),
+            // Before the API ID was included: `>B?256sQ?`.
+            267 => (
+                take::<1>(&mut buf)?[0],
+                take::<1>(&mut buf)?[0],
+                take::<256>(&mut buf)?,
+                i64::from_be_bytes(take(&mut buf)?),
+                take::<1>(&mut buf)?[0],
+            ),
+            // Current format: `>BI?256sQ?`.
+            271 => {
+                let dc_id = take::<1>(&mut buf)?[0];
+                let _api_id = take::<4>(&mut buf)?;
+                (
+                    dc_id,
+                    take::<1>(&mut buf)?[0],
+                    take::<256>(&mut buf)?,
+                    i64::from_be_bytes(take(&mut buf)?),
+                    take::<1>(&mut buf)?[0],
+                )
+            }
+            _ => return Err(Error::MalformedData),
+        };
+
+        if test_mode != 0 {
+            return Err(Error::UnsupportedVersion);
+        }
+        let &(ip, port) = DC_ADDRESSES
+            .get(dc_id as usize)
+            .filter(|_| dc_id != 0)
+            .ok_or(Error::MalformedData)?;
+
+        let session = Session::new();
+        session.insert_dc(dc_id as i32, SocketAddr::new(IpAddr::V4(ip), port), auth);
+        session.set_user(user_id, dc_id as i32, bot != 0);
+        Ok(session)
+    }
+
+    /// Export the session as a Pyrogram session string, in the format used by current versions.
+    ///
+    /// Pyrogram needs to know the API ID the session was created with.
+    ///
+    /// Returns `None` if the session has no logged-in user or authorization key.
+    pub fn to_pyrogram_string(&self, api_id: i32) -> Option<String> {
+        let user = self.get_user()?;
+        let (dc_id, _, auth) = self.home_dc()?;
+        let mut data = Vec::with_capacity(271);
+        data.push(dc_id as u8);
+        data.extend(api_id.to_be_bytes());
+        data.push(0); // test mode
+        data.extend(auth);
+        data.extend(user.id.to_be_bytes());
+        data.push(user.bot as u8);
+        Some(BASE64.encode(data))
+    }
+
+    /// Load a session from a string produced by [`Session::to_string_session`].
+    pub fn from_string_session(string: &str) -> Result<Self, Error> {
+        Self::load(&BASE64.decode(string).map_err(|_| Error::MalformedData)?)
+    }
+
+    /// Export the session as a compact base64 string.
+    ///
+    /// Only the datacenters with an authorization key and the logged-in user are included.
+    /// The update state, the cached chats, the datacenter options and the server salts are left
+    /// out to keep the string short.
+    pub fn to_string_session(&self) -> String {
+        let session = self.session.lock().unwrap();
+        let compact = types::Session {
+            dcs: session
+                .dcs
+                .iter()
+                .filter(|enums::DataCenter::Center(dc)| dc.auth.is_some())
+                .cloned()
+                .collect(),
+            user: session.user.clone(),
+            state: None,
+            peers: Vec::new(),
+            usernames: Vec::new(),
+            dc_options: Vec::new(),
+            salts: Vec::new(),
+        };
+        drop(session);
+
+        BASE64.encode(grammers_tl_types::Serializable::to_bytes(
+            &enums::Session::Session(compact),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn auth_key() -> [u8; 256] {
+        let mut auth = [0; 256];
+        auth.iter_mut().enumerate().for_each(|(i, x)| *x = i as u8);
+        auth
+    }
+
+    #[test]
+    fn telethon_string() {
+        // `StringSession.save` for DC 2 at 149.154.167.51:443.
+        let mut data = vec![2, 149, 154, 167, 51, 1, 187];
+        data.extend(auth_key());
+        let string = format!("1{}", BASE64_PADDED.encode(&data));
+
+        let session = Session::from_telethon_string(&string).unwrap();
+        assert_eq!(session.dc_auth_key(2), Some(auth_key()));
+        assert_eq!(session.get_dcs()[0].port, 443);
+        assert!(!session.signed_in());
+        assert_eq!(session.to_telethon_string().unwrap(), string);
+
+        assert!(matches!(
+            Session::from_telethon_string(&string[1..]),
+            Err(Error::UnsupportedVersion)
+        ));
+        assert!(matches!(
+            Session::from_telethon_string(&string[..100]),
+            Err(Error::MalformedData)
+        ));
+    }
+
+    #[test]
+    fn pyrogram_string() {
+        let session = Session::new();
+        session.insert_dc(4, ([149, 154, 167, 92], 443).into(), auth_key());
+        session.set_user(1234567890123, 4, true);
+
+        let string = session.to_pyrogram_string(12345).unwrap();
+        let session = Session::from_pyrogram_string(&string).unwrap();
+        assert_eq!(session.dc_auth_key(4), Some(auth_key()));
+        assert_eq!(
+            session.get_user().map(|u| (u.id, u.dc, u.bot)),
+            Some((1234567890123, 4, true))
+        );
+
+        // Oldest format, with 32-bit user IDs and no API ID.
+        let mut data = vec![1, 0];
+        data.extend(auth_key());
+        data.extend(777u32.to_be_bytes());
+        data.push(0);
+        let session = Session::from_pyrogram_string(&BASE64.encode(data)).unwrap();
+        assert_eq!(session.dc_auth_key(1), Some(auth_key()));
+        assert_eq!(
+            session.get_user().map(|u| (u.id, u.dc, u.bot)),
+            Some((777, 1, false))
+        );
+    }
+
+    #[test]
+    fn grammers_string() {
+        let session = Session::new();
+        session.insert_dc(2, ([149, 154, 167, 51], 443).into(), auth_key());
+        session.set_user(123, 2, false);
+
+        let session = Session::from_string_session(&session.to_string_session()).unwrap();
+        assert_eq!(session.dc_auth_key(2), Some(auth_key()));
+        assert_eq!(session.get_user().map(|u| u.id), Some(123));
+    }
+}